@@ -1,10 +1,74 @@
 //! The MPQ Builder.
 //! Allows progressively creating the MPQ as the file is read.
+//! It also allows writing a new archive from a set of files.
 use crate::{MPQParserError, MPQResult};
+use crate::{
+    COMPRESSION_ZLIB, MPQ_FILE_COMPRESS, MPQ_FILE_ENCRYPTED, MPQ_FILE_EXISTS, MPQ_FILE_SINGLE_UNIT,
+};
 
-use super::parser::peek_hex;
-use super::{MPQBlockTableEntry, MPQFileHeader, MPQHashTableEntry, MPQHashType, MPQUserData, MPQ};
+use super::parser::{
+    peek_hex, AttributeFlags, MPQ_ARCHIVE_HEADER_TYPE, MPQ_ATTRIBUTES_VERSION,
+    MPQ_HASH_ENTRY_EMPTY, MPQ_V1_MAX_TABLE_ENTRIES,
+};
+use super::{
+    DecompressorRegistry, MPQBetTable, MPQBlockTableEntry, MPQFileHeader, MPQHashTableEntry,
+    MPQHashType, MPQHetTable, MPQUserData, MPQ,
+};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use md5::{Digest, Md5};
 use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The sector size shift of the written archives, this is 4096 byte sectors.
+pub const MPQ_BUILDER_SECTOR_SIZE_SHIFT: u16 = 3;
+/// The block flags of the files added through [`MPQBuilder::with_file`]
+pub const MPQ_BUILDER_DEFAULT_FILE_FLAGS: u32 = MPQ_FILE_EXISTS | MPQ_FILE_COMPRESS;
+/// The size of the original format archive header written by [`MPQBuilder::write`]
+const MPQ_BUILDER_HEADER_SIZE: u32 = 0x20;
+/// The hash table entry that has always been empty.
+const MPQ_BUILDER_EMPTY_HASH_ENTRY: MPQHashTableEntry = MPQHashTableEntry {
+    hash_a: 0xFFFFFFFF,
+    hash_b: 0xFFFFFFFF,
+    locale: 0xFFFF,
+    platform: 0xFFFF,
+    block_table_index: MPQ_HASH_ENTRY_EMPTY,
+};
+/// The difference between the Windows FILETIME epoch (1601) and the UNIX epoch
+/// in 100-nanosecond intervals.
+const FILETIME_UNIX_EPOCH: u64 = 116444736000000000;
+
+/// A file to be written in the archive by [`MPQBuilder::write`]
+#[derive(Debug, PartialEq, Clone)]
+pub struct MPQBuilderFile {
+    /// The name of the file inside the archive.
+    pub filename: String,
+    /// The uncompressed contents of the file.
+    pub data: Vec<u8>,
+    /// The language of the file, 0 is the neutral language.
+    pub locale: u16,
+    /// The platform of the file, 0 is the default platform.
+    pub platform: u16,
+    /// The block flags of the file, only [`MPQ_FILE_EXISTS`], [`MPQ_FILE_COMPRESS`],
+    /// [`MPQ_FILE_ENCRYPTED`] and [`MPQ_FILE_SINGLE_UNIT`] are supported, other
+    /// flags are ignored.
+    pub flags: u32,
+}
+
+impl MPQBuilderFile {
+    /// Creates a file with neutral locale and platform and the default flags.
+    pub fn new(filename: &str, data: &[u8]) -> Self {
+        Self {
+            filename: filename.to_string(),
+            data: data.to_vec(),
+            locale: 0,
+            platform: 0,
+            flags: MPQ_BUILDER_DEFAULT_FILE_FLAGS,
+        }
+    }
+}
 
 /// A builder for the MPQ parsing, allowing for building the archive progressively
 #[derive(Debug)]
@@ -21,8 +85,24 @@ pub struct MPQBuilder {
     pub hash_table_entries: Vec<MPQHashTableEntry>,
     /// The MPQ Block Table Entries content
     pub block_table_entries: Vec<MPQBlockTableEntry>,
+    /// The high 16 bits of the block offsets of large archives.
+    pub hi_block_table: Option<Vec<u16>>,
+    /// The HET table of the Cataclysm formats.
+    pub het_table: Option<MPQHetTable>,
+    /// The BET table of the Cataclysm formats.
+    pub bet_table: Option<MPQBetTable>,
     /// An encryption table to lookup, this is shared with the [`crate::MPQ`] object itself.
-    pub encryption_table: HashMap<u32, u32>,
+    pub encryption_table: Arc<HashMap<u32, u32>>,
+    /// The files to write in the archive by [`MPQBuilder::write`]
+    pub files: Vec<MPQBuilderFile>,
+    /// When set, [`MPQBuilder::write`] generates an `(attributes)` file with
+    /// the selected arrays.
+    pub attribute_flags: Option<AttributeFlags>,
+    /// Whether [`MPQBuilder::write`] generates the `(listfile)`, enabled by default.
+    pub listfile: bool,
+    /// The number of entries of the hash table written by [`MPQBuilder::write`],
+    /// by default the smallest power of two that fits the files.
+    pub hash_table_size: Option<u32>,
 }
 
 impl Default for MPQBuilder {
@@ -39,7 +119,14 @@ impl MPQBuilder {
             user_data: None,
             hash_table_entries: vec![],
             block_table_entries: vec![],
-            encryption_table: MPQ::prepare_encryption_table(),
+            hi_block_table: None,
+            het_table: None,
+            bet_table: None,
+            encryption_table: MPQ::shared_encryption_table(),
+            files: vec![],
+            attribute_flags: None,
+            listfile: true,
+            hash_table_size: None,
         }
     }
 
@@ -67,6 +154,57 @@ impl MPQBuilder {
         self
     }
 
+    /// Sets the hi-block table
+    pub fn with_hi_block_table(mut self, hi_block_table: Option<Vec<u16>>) -> Self {
+        self.hi_block_table = hi_block_table;
+        self
+    }
+
+    /// Sets the HET table
+    pub fn with_het_table(mut self, het_table: Option<MPQHetTable>) -> Self {
+        self.het_table = het_table;
+        self
+    }
+
+    /// Sets the BET table
+    pub fn with_bet_table(mut self, bet_table: Option<MPQBetTable>) -> Self {
+        self.bet_table = bet_table;
+        self
+    }
+
+    /// Adds a file to be written with the default flags, see [`MPQBuilder::write`]
+    pub fn with_file(mut self, filename: &str, data: &[u8]) -> Self {
+        self.files.push(MPQBuilderFile::new(filename, data));
+        self
+    }
+
+    /// Adds a file to be written with its own locale, platform and flags.
+    pub fn with_file_entry(mut self, file: MPQBuilderFile) -> Self {
+        self.files.push(file);
+        self
+    }
+
+    /// Enables writing the `(attributes)` file with the selected arrays.
+    pub fn with_attributes(mut self, flags: AttributeFlags) -> Self {
+        self.attribute_flags = Some(flags);
+        self
+    }
+
+    /// Enables or disables writing the `(listfile)`.
+    pub fn with_listfile(mut self, listfile: bool) -> Self {
+        self.listfile = listfile;
+        self
+    }
+
+    /// Sets the number of entries of the hash table, leaving room for files added
+    /// later. It must be a power of two and fit all the files, including the
+    /// `(listfile)` and `(attributes)`, and be below the original format maximum of
+    /// [`MPQ_V1_MAX_TABLE_ENTRIES`], otherwise [`MPQBuilder::write`] fails.
+    pub fn with_hash_table_size(mut self, size: u32) -> Self {
+        self.hash_table_size = Some(size);
+        self
+    }
+
     /// Performs mpq string hashing using the encryption table.
     pub fn mpq_string_hash(
         &self,
@@ -89,6 +227,267 @@ impl MPQBuilder {
         Ok((tail, res))
     }
 
+    /// Uses the encryption table and key to encrypt some bytes
+    #[tracing::instrument(level = "trace", skip(self, data))]
+    pub fn mpq_data_encrypt<'a>(
+        &'a self,
+        data: &'a [u8],
+        key: u32,
+    ) -> MPQResult<&'a [u8], Vec<u8>> {
+        tracing::trace!("Decrypted: {:?}", peek_hex(data));
+        let (tail, res) = MPQ::mpq_data_encrypt(&self.encryption_table, data, key)?;
+        tracing::trace!("Encrypted: {:?}", peek_hex(&res));
+        Ok((tail, res))
+    }
+
+    /// Writes an archive containing the added files.
+    ///
+    /// A `(listfile)` with the names of the files is added unless disabled through
+    /// [`MPQBuilder::with_listfile`], an `(attributes)` file is added when enabled
+    /// through [`MPQBuilder::with_attributes`].
+    /// The archive uses the original format header, followed by the file data,
+    /// the hash table and the block table.
+    /// The output is deterministic, the files are sorted by name, locale and
+    /// platform regardless of the order in which they were added, and the
+    /// `(attributes)` only contain timestamps when requested.
+    pub fn write(&self) -> Result<Vec<u8>, MPQParserError> {
+        let mut files = self.files.clone();
+        files.sort_by(|a, b| {
+            (&a.filename, a.locale, a.platform).cmp(&(&b.filename, b.locale, b.platform))
+        });
+        if self.listfile {
+            let mut listfile: Vec<&str> = files.iter().map(|file| file.filename.as_str()).collect();
+            listfile.dedup();
+            let listfile = listfile.join("\r\n");
+            files.push(MPQBuilderFile::new("(listfile)", listfile.as_bytes()));
+        }
+        if let Some(attribute_flags) = self.attribute_flags {
+            let attributes = Self::attributes_file(&files, attribute_flags);
+            files.push(MPQBuilderFile::new("(attributes)", &attributes));
+        }
+
+        let mut res = vec![0u8; MPQ_BUILDER_HEADER_SIZE as usize];
+        let mut block_table_entries = vec![];
+        for file in &files {
+            let file_data = self.encode_file(file, MPQ_BUILDER_SECTOR_SIZE_SHIFT)?;
+            let flags = file.flags
+                & (MPQ_FILE_EXISTS | MPQ_FILE_COMPRESS | MPQ_FILE_ENCRYPTED | MPQ_FILE_SINGLE_UNIT);
+            block_table_entries.push(MPQBlockTableEntry::new(
+                res.len() as u32,
+                file_data.len() as u32,
+                file.data.len() as u32,
+                flags,
+            ));
+            res.extend_from_slice(&file_data);
+        }
+
+        let hash_table_entries = self.hash_table(&files)?;
+        let hash_table_offset = res.len() as u32;
+        let mut hash_table_data = vec![];
+        for entry in &hash_table_entries {
+            hash_table_data.extend_from_slice(&entry.to_bytes());
+        }
+        let hash_table_key = self.mpq_string_hash("(hash table)", MPQHashType::Table)?;
+        let (_, encrypted_hash_table_data) =
+            self.mpq_data_encrypt(&hash_table_data, hash_table_key)?;
+        res.extend_from_slice(&encrypted_hash_table_data);
+
+        let block_table_offset = res.len() as u32;
+        let mut block_table_data = vec![];
+        for entry in &block_table_entries {
+            block_table_data.extend_from_slice(&entry.to_bytes());
+        }
+        let block_table_key = self.mpq_string_hash("(block table)", MPQHashType::Table)?;
+        let (_, encrypted_block_table_data) =
+            self.mpq_data_encrypt(&block_table_data, block_table_key)?;
+        res.extend_from_slice(&encrypted_block_table_data);
+
+        let mut header = vec![];
+        header.extend_from_slice(b"MPQ");
+        header.push(MPQ_ARCHIVE_HEADER_TYPE);
+        header.extend_from_slice(&MPQ_BUILDER_HEADER_SIZE.to_le_bytes());
+        header.extend_from_slice(&(res.len() as u32).to_le_bytes());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(&MPQ_BUILDER_SECTOR_SIZE_SHIFT.to_le_bytes());
+        header.extend_from_slice(&hash_table_offset.to_le_bytes());
+        header.extend_from_slice(&block_table_offset.to_le_bytes());
+        header.extend_from_slice(&(hash_table_entries.len() as u32).to_le_bytes());
+        header.extend_from_slice(&(block_table_entries.len() as u32).to_le_bytes());
+        res[..header.len()].copy_from_slice(&header);
+        Ok(res)
+    }
+
+    /// Generates the contents of the `(attributes)` file for the files, followed
+    /// by the zeroed entry of the `(attributes)` file itself.
+    fn attributes_file(files: &[MPQBuilderFile], flags: AttributeFlags) -> Vec<u8> {
+        let mut res = vec![];
+        res.extend_from_slice(&MPQ_ATTRIBUTES_VERSION.to_le_bytes());
+        res.extend_from_slice(&u32::from(flags).to_le_bytes());
+        if flags.crc32 {
+            for file in files {
+                res.extend_from_slice(&crc32fast::hash(&file.data).to_le_bytes());
+            }
+            res.extend_from_slice(&0u32.to_le_bytes());
+        }
+        if flags.filetime {
+            let filetime = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_nanos() as u64 / 100 + FILETIME_UNIX_EPOCH)
+                .unwrap_or_default();
+            for _ in files {
+                res.extend_from_slice(&filetime.to_le_bytes());
+            }
+            res.extend_from_slice(&0u64.to_le_bytes());
+        }
+        if flags.md5 {
+            for file in files {
+                res.extend_from_slice(&Md5::digest(&file.data));
+            }
+            res.extend_from_slice(&[0u8; 16]);
+        }
+        if flags.patch_bit {
+            // None of the written files are patch files.
+            res.resize(res.len() + (files.len() + 1).div_ceil(8), 0);
+        }
+        res
+    }
+
+    /// Encodes the file data as stored in its block, either as a single unit or
+    /// split in sectors preceded by the sector offset table.
+    /// Encrypted files have each sector encrypted with the file key plus the
+    /// sector index, and the sector offset table with the file key minus one.
+    pub(crate) fn encode_file(
+        &self,
+        file: &MPQBuilderFile,
+        sector_size_shift: u16,
+    ) -> Result<Vec<u8>, MPQParserError> {
+        if file.data.is_empty() {
+            return Ok(vec![]);
+        }
+        let compress = file.flags & MPQ_FILE_COMPRESS != 0;
+        let file_key = if file.flags & MPQ_FILE_ENCRYPTED != 0 {
+            Some(self.file_key(&file.filename)?)
+        } else {
+            None
+        };
+        if file.flags & MPQ_FILE_SINGLE_UNIT != 0 {
+            let file_data = if compress {
+                Self::compress_sector(&file.data)?
+            } else {
+                file.data.clone()
+            };
+            return match file_key {
+                Some(file_key) => self.encrypt_sector(&file_data, file_key),
+                None => Ok(file_data),
+            };
+        }
+        let sector_size = 512usize << sector_size_shift;
+        let mut sectors = vec![];
+        for (idx, sector) in file.data.chunks(sector_size).enumerate() {
+            let sector = if compress {
+                Self::compress_sector(sector)?
+            } else {
+                sector.to_vec()
+            };
+            match file_key {
+                Some(file_key) => {
+                    sectors.push(self.encrypt_sector(&sector, file_key.wrapping_add(idx as u32))?)
+                }
+                None => sectors.push(sector),
+            }
+        }
+        let mut position = 4 * (sectors.len() + 1);
+        let mut table = vec![];
+        table.extend_from_slice(&(position as u32).to_le_bytes());
+        for sector in &sectors {
+            position += sector.len();
+            table.extend_from_slice(&(position as u32).to_le_bytes());
+        }
+        let mut res = match file_key {
+            Some(file_key) => self.encrypt_sector(&table, file_key.wrapping_sub(1))?,
+            None => table,
+        };
+        for sector in sectors {
+            res.extend_from_slice(&sector);
+        }
+        Ok(res)
+    }
+
+    /// The key used to encrypt a file, the hash of the filename without its path.
+    fn file_key(&self, filename: &str) -> Result<u32, MPQParserError> {
+        let basename = filename.rsplit('\\').next().unwrap_or(filename);
+        self.mpq_string_hash(basename, MPQHashType::Table)
+    }
+
+    /// Encrypts a sector with the key, the trailing bytes that do not fill a 4
+    /// byte word are stored unencrypted.
+    fn encrypt_sector(&self, sector: &[u8], key: u32) -> Result<Vec<u8>, MPQParserError> {
+        let (tail, mut res) = self.mpq_data_encrypt(sector, key)?;
+        res.extend_from_slice(tail);
+        Ok(res)
+    }
+
+    /// Compresses a sector using [`COMPRESSION_ZLIB`], the sector is stored
+    /// as is when compression does not gain at least one byte.
+    fn compress_sector(data: &[u8]) -> Result<Vec<u8>, MPQParserError> {
+        let mut encoder = ZlibEncoder::new(vec![COMPRESSION_ZLIB], Compression::default());
+        encoder.write_all(data)?;
+        let compressed = encoder.finish()?;
+        if compressed.len() < data.len() {
+            Ok(compressed)
+        } else {
+            Ok(data.to_vec())
+        }
+    }
+
+    /// Places the files in the hash table, each file at its home entry or the
+    /// next available entry, the block table index being the file position.
+    fn hash_table(
+        &self,
+        files: &[MPQBuilderFile],
+    ) -> Result<Vec<MPQHashTableEntry>, MPQParserError> {
+        let hash_table_size = match self.hash_table_size {
+            Some(size) if !size.is_power_of_two() || (size as usize) < files.len() => {
+                tracing::error!(
+                    "Hash table size {} is not a power of two fitting {} files",
+                    size,
+                    files.len()
+                );
+                return Err(MPQParserError::InvalidHashTableSize(size));
+            }
+            Some(size) => size as usize,
+            None => files.len().next_power_of_two(),
+        };
+        // The written archives are in the original format, the parser rejects
+        // larger tables.
+        if hash_table_size >= MPQ_V1_MAX_TABLE_ENTRIES as usize {
+            tracing::error!(
+                "Hash table size {} exceeds the maximum of {}",
+                hash_table_size,
+                MPQ_V1_MAX_TABLE_ENTRIES
+            );
+            return Err(MPQParserError::InvalidHashTableSize(
+                u32::try_from(hash_table_size).unwrap_or(u32::MAX),
+            ));
+        }
+        let mut res = vec![MPQ_BUILDER_EMPTY_HASH_ENTRY; hash_table_size];
+        for (block_table_index, file) in files.iter().enumerate() {
+            let home = self.mpq_string_hash(&file.filename, MPQHashType::TableOffset)? as usize;
+            let mut idx = home % hash_table_size;
+            while !res[idx].is_empty() {
+                idx = (idx + 1) % hash_table_size;
+            }
+            res[idx] = MPQHashTableEntry::new(
+                self.mpq_string_hash(&file.filename, MPQHashType::HashA)?,
+                self.mpq_string_hash(&file.filename, MPQHashType::HashB)?,
+                file.locale,
+                file.platform,
+                block_table_index as u32,
+            );
+        }
+        Ok(res)
+    }
+
     /// Consumes self and turns into the final MPQ.
     pub fn build(self, _orig_input: &[u8]) -> Result<MPQ, MPQParserError> {
         let archive_header = self
@@ -97,13 +496,214 @@ impl MPQBuilder {
         let user_data = self.user_data;
         let hash_table_entries = self.hash_table_entries;
         let block_table_entries = self.block_table_entries;
+        let hi_block_table = self.hi_block_table;
+        let het_table = self.het_table;
+        let bet_table = self.bet_table;
         let encryption_table = self.encryption_table;
         Ok(MPQ {
             archive_header,
             user_data,
             hash_table_entries,
             block_table_entries,
+            hi_block_table,
+            het_table,
+            bet_table,
             encryption_table,
+            decompressors: DecompressorRegistry::new(),
+            archive_fingerprint: Default::default(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+    use crate::tests::{events_archive, test_events};
+    use crate::MPQAttributes;
+
+    #[test]
+    fn it_fails_to_build_without_archive_header() {
+        let (_tail, mpq) = parser::parse(&MPQBuilder::new().write().unwrap()).unwrap();
+        let res = MPQBuilder::new()
+            .with_hash_table(mpq.hash_table_entries)
+            .with_block_table(mpq.block_table_entries)
+            .build(&[]);
+        assert!(matches!(res, Err(MPQParserError::MissingArchiveHeader)));
+    }
+
+    #[test]
+    fn it_writes_attributes() {
+        let details = b"replay.details contents ".repeat(100);
+        let events = test_events();
+        let (archive, mpq) = events_archive(
+            MPQBuilder::new()
+                .with_file("replay.details", &details)
+                .with_attributes(AttributeFlags {
+                    crc32: true,
+                    md5: true,
+                    patch_bit: true,
+                    ..Default::default()
+                }),
+        );
+        let (_tail, attributes_data) = mpq
+            .read_mpq_file_sector("(attributes)", false, &archive)
+            .unwrap();
+        let (_tail, attributes) =
+            MPQAttributes::parse(&attributes_data, mpq.block_table_entries.len()).unwrap();
+        assert!(attributes.filetimes.is_none());
+        assert_eq!(
+            attributes.patch_bits,
+            Some(vec![Some(false); mpq.block_table_entries.len()])
+        );
+        let crc32s = attributes.crc32s.unwrap();
+        let md5s = attributes.md5s.unwrap();
+        for (filename, data) in [("replay.details", details), ("replay.game.events", events)] {
+            let block_table_index = mpq
+                .get_hash_table_entry(filename, None)
+                .unwrap()
+                .block_table_index as usize;
+            assert_eq!(crc32s[block_table_index], Some(crc32fast::hash(&data)));
+            assert_eq!(
+                md5s[block_table_index],
+                Some(<[u8; 16]>::from(Md5::digest(&data)))
+            );
+            let (_tail, file_data) = mpq.read_mpq_file_sector(filename, false, &archive).unwrap();
+            assert_eq!(file_data, data);
+        }
+    }
+
+    #[test]
+    fn it_writes_deterministic_archives() {
+        let details = b"replay.details contents ".repeat(100);
+        let events = test_events();
+        let attribute_flags = AttributeFlags {
+            crc32: true,
+            md5: true,
+            ..Default::default()
+        };
+        let (archive, mpq) = events_archive(
+            MPQBuilder::new()
+                .with_file("replay.details", &details)
+                .with_file_entry(MPQBuilderFile {
+                    locale: 0x0407,
+                    ..MPQBuilderFile::new("replay.details", b"Details")
+                })
+                .with_attributes(attribute_flags),
+        );
+        let reordered_archive = MPQBuilder::new()
+            .with_file_entry(MPQBuilderFile {
+                locale: 0x0407,
+                ..MPQBuilderFile::new("replay.details", b"Details")
+            })
+            .with_file("replay.game.events", &events)
+            .with_file("replay.details", &details)
+            .with_attributes(attribute_flags)
+            .write()
+            .unwrap();
+        assert_eq!(archive, reordered_archive);
+        let (_tail, listfile) = mpq
+            .read_mpq_file_sector("(listfile)", false, &archive)
+            .unwrap();
+        assert_eq!(listfile, b"replay.details\r\nreplay.game.events");
+    }
+
+    #[test]
+    fn it_round_trips_written_files() {
+        let details = b"replay.details contents ".repeat(100);
+        let events = test_events();
+        let archive = MPQBuilder::new()
+            .with_file("replay.details", &details)
+            .with_file_entry(MPQBuilderFile {
+                flags: MPQ_BUILDER_DEFAULT_FILE_FLAGS | MPQ_FILE_ENCRYPTED,
+                ..MPQBuilderFile::new("replay.game.events", &events)
+            })
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        for (filename, data) in [
+            ("replay.details", &details),
+            ("replay.game.events", &events),
+        ] {
+            let (_tail, file_data) = mpq.read_mpq_file_sector(filename, false, &archive).unwrap();
+            assert_eq!(&file_data, data);
+        }
+        // The tables are stored encrypted.
+        let hash_table_offset = mpq.archive_header.hash_table_pos() as usize;
+        let plain_hash_table: Vec<u8> = mpq
+            .hash_table_entries
+            .iter()
+            .flat_map(|entry| entry.to_bytes())
+            .collect();
+        assert_ne!(
+            &archive[hash_table_offset..hash_table_offset + plain_hash_table.len()],
+            &plain_hash_table[..]
+        );
+        // The sectors are compressed with zlib.
+        let block_entry = mpq
+            .get_block_table_entry(
+                mpq.get_hash_table_entry("replay.details", None)
+                    .unwrap()
+                    .block_table_index,
+            )
+            .unwrap();
+        assert!(block_entry.archived_size < block_entry.size);
+    }
+
+    #[test]
+    fn it_writes_hash_table_of_given_size() {
+        let archive = MPQBuilder::new()
+            .with_file("replay.details", b"replay.details contents")
+            .with_hash_table_size(64)
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        assert_eq!(mpq.archive_header.hash_table_entries, 64);
+        assert_eq!(mpq.hash_table_entries.len(), 64);
+        assert_eq!(
+            mpq.read_mpq_file_sector("replay.details", false, &archive)
+                .unwrap()
+                .1,
+            b"replay.details contents"
+        );
+        assert!(matches!(
+            MPQBuilder::new()
+                .with_file("replay.details", b"replay.details contents")
+                .with_hash_table_size(48)
+                .write(),
+            Err(MPQParserError::InvalidHashTableSize(48))
+        ));
+        // The file and the (listfile) don't fit.
+        assert!(matches!(
+            MPQBuilder::new()
+                .with_file("replay.details", b"replay.details contents")
+                .with_hash_table_size(1)
+                .write(),
+            Err(MPQParserError::InvalidHashTableSize(1))
+        ));
+        // The largest hash table of the original format.
+        let max_size = MPQ_V1_MAX_TABLE_ENTRIES / 2;
+        let archive = MPQBuilder::new()
+            .with_file("replay.details", b"replay.details contents")
+            .with_hash_table_size(max_size)
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        assert_eq!(mpq.hash_table_entries.len(), max_size as usize);
+        assert_eq!(
+            mpq.read_mpq_file_sector("replay.details", false, &archive)
+                .unwrap()
+                .1,
+            b"replay.details contents"
+        );
+        assert!(matches!(
+            MPQBuilder::new()
+                .with_file("replay.details", b"replay.details contents")
+                .with_hash_table_size(MPQ_V1_MAX_TABLE_ENTRIES)
+                .write(),
+            Err(MPQParserError::InvalidHashTableSize(
+                MPQ_V1_MAX_TABLE_ENTRIES
+            ))
+        ));
+    }
+}
@@ -0,0 +1,94 @@
+//! The Decompressed Cache.
+//! Keeps the contents of recently read files, so reading the same files from
+//! the same archives repeatedly, i.e. in a long-running server, doesn't
+//! decompress them again. The least recently used files are evicted first.
+
+use std::collections::HashMap;
+
+/// Identifies a file, by the fingerprint of its archive, see
+/// [`crate::MPQ::archive_fingerprint`], and its block index.
+pub type CacheKey = ([u8; 32], u32);
+
+/// A cache of decompressed files with least recently used eviction.
+#[derive(Debug, Default, Clone)]
+pub struct DecompressedCache {
+    /// The maximum number of files kept.
+    capacity: usize,
+    /// The contents of each file and the tick of its last use.
+    entries: HashMap<CacheKey, (u64, Vec<u8>)>,
+    /// Increases on every use of the cache, orders the uses.
+    tick: u64,
+}
+
+impl DecompressedCache {
+    /// Creates a cache keeping up to `capacity` files.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the contents of a file, marking it as recently used.
+    pub fn get(&mut self, key: &CacheKey) -> Option<&[u8]> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.entries.get_mut(key).map(|(last_used, file_data)| {
+            *last_used = tick;
+            file_data.as_slice()
+        })
+    }
+
+    /// Adds the contents of a file, evicting the least recently used file when full.
+    pub fn insert(&mut self, key: CacheKey, file_data: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            let least_recently_used = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (last_used, _))| *last_used)
+                .map(|(key, _)| *key);
+            if let Some(least_recently_used) = least_recently_used {
+                tracing::debug!("Evicting block {} from the cache", least_recently_used.1);
+                self.entries.remove(&least_recently_used);
+            }
+        }
+        self.entries.insert(key, (self.tick, file_data));
+    }
+
+    /// The number of files in the cache.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether the cache has no files.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+/// Decompressed Cache Tests
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn it_evicts_least_recently_used() {
+        let mut cache = DecompressedCache::new(2);
+        let key = |block_table_index: u32| ([0u8; 32], block_table_index);
+        cache.insert(key(0), b"a".to_vec());
+        cache.insert(key(1), b"b".to_vec());
+        assert_eq!(cache.get(&key(0)), Some(&b"a"[..]));
+        cache.insert(key(2), b"c".to_vec());
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(&key(1)), None);
+        assert_eq!(cache.get(&key(0)), Some(&b"a"[..]));
+        assert_eq!(cache.get(&key(2)), Some(&b"c"[..]));
+        let mut disabled_cache = DecompressedCache::new(0);
+        disabled_cache.insert(key(0), b"a".to_vec());
+        assert!(disabled_cache.is_empty());
+    }
+}
@@ -0,0 +1,150 @@
+//! IMA ADPCM decompression, used for the WAVE files of the archives.
+//!
+//! The compressed data starts with a zero byte and the bit shift, followed by
+//! the initial 16-bit sample of each channel. Each following byte encodes the
+//! next sample of a channel, the channels alternating, or is one of the
+//! special values that repeat the previous sample or increase the step index.
+//! NOTES:
+//! - Based on StormLib's `adpcm.cpp`.
+
+use crate::{MPQParserError, COMPRESSION_ADPCM_MONO, COMPRESSION_ADPCM_STEREO};
+
+/// The step index of each channel at the start of the data.
+const INITIAL_STEP_INDEX: usize = 0x2c;
+/// The highest step index.
+const MAX_STEP_INDEX: usize = 88;
+/// Repeats the previous sample of the channel and decreases its step index.
+const REPEAT_SAMPLE: u8 = 0x80;
+/// Increases the step index of the channel, without producing a sample.
+const INCREASE_STEP_INDEX: u8 = 0x81;
+
+/// The change of the step index after each encoded sample.
+const NEXT_STEP_TABLE: [i32; 32] = [
+    -1, 0, -1, 4, -1, 2, -1, 6, -1, 0, -1, 4, -1, 2, -1, 6, -1, 0, -1, 4, -1, 2, -1, 6, -1, 0, -1,
+    4, -1, 2, -1, 6,
+];
+
+/// The step size of each step index.
+const STEP_SIZE_TABLE: [i32; MAX_STEP_INDEX + 1] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66,
+    73, 80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449,
+    494, 544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272,
+    2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630, 9493,
+    10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+
+/// Decodes a sample from the previous sample of the channel.
+fn decode_sample(predicted_sample: i32, encoded_sample: u8, step_size: i32, bit_shift: u8) -> i32 {
+    let mut difference = step_size >> bit_shift;
+    for bit in 0..6 {
+        if encoded_sample & (1 << bit) != 0 {
+            difference += step_size >> bit;
+        }
+    }
+    if encoded_sample & 0x40 != 0 {
+        (predicted_sample - difference).max(i16::MIN as i32)
+    } else {
+        (predicted_sample + difference).min(i16::MAX as i32)
+    }
+}
+
+/// Decompresses IMA ADPCM data of 1 or 2 channels into 16-bit little endian samples.
+pub(crate) fn decompress(input: &[u8], channel_count: usize) -> Result<Vec<u8>, MPQParserError> {
+    let compression_type = if channel_count == 1 {
+        COMPRESSION_ADPCM_MONO
+    } else {
+        COMPRESSION_ADPCM_STEREO
+    };
+    let (bit_shift, input) = match input {
+        [_zero, bit_shift, input @ ..] if *bit_shift < 32 => (*bit_shift, input),
+        _ => return Err(MPQParserError::InvalidCompressedData(compression_type)),
+    };
+    if input.len() < 2 * channel_count {
+        return Err(MPQParserError::InvalidCompressedData(compression_type));
+    }
+    let (initial_samples, encoded_samples) = input.split_at(2 * channel_count);
+    let mut res = initial_samples.to_vec();
+    let mut predicted_samples: Vec<i32> = initial_samples
+        .chunks(2)
+        .map(|sample| i16::from_le_bytes([sample[0], sample[1]]) as i32)
+        .collect();
+    let mut step_indexes = vec![INITIAL_STEP_INDEX; channel_count];
+    let mut channel = channel_count - 1;
+    for encoded_sample in encoded_samples {
+        channel = (channel + 1) % channel_count;
+        match *encoded_sample {
+            REPEAT_SAMPLE => {
+                step_indexes[channel] = step_indexes[channel].saturating_sub(1);
+                res.extend_from_slice(&(predicted_samples[channel] as i16).to_le_bytes());
+            }
+            INCREASE_STEP_INDEX => {
+                step_indexes[channel] = (step_indexes[channel] + 8).min(MAX_STEP_INDEX);
+                // The next byte is for the same channel.
+                channel = (channel + channel_count - 1) % channel_count;
+            }
+            encoded_sample => {
+                let step_index = step_indexes[channel];
+                predicted_samples[channel] = decode_sample(
+                    predicted_samples[channel],
+                    encoded_sample,
+                    STEP_SIZE_TABLE[step_index],
+                    bit_shift,
+                );
+                res.extend_from_slice(&(predicted_samples[channel] as i16).to_le_bytes());
+                step_indexes[channel] =
+                    (step_index as i32 + NEXT_STEP_TABLE[(encoded_sample & 0x1f) as usize])
+                        .clamp(0, MAX_STEP_INDEX as i32) as usize;
+            }
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+/// IMA ADPCM Tests
+pub mod tests {
+    use super::*;
+
+    /// Converts 16-bit little endian samples.
+    fn samples(data: &[u8]) -> Vec<i16> {
+        data.chunks(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn it_decompresses_mono() {
+        let sector = [
+            0x00, 0x01, // zero, bit shift
+            0x00, 0x00, // initial sample
+            0x80, // repeat, step index 43
+            0x81, // step index 51
+            0x05, // 963 >> 1 + 963 + 963 >> 2, step index 53
+            0x41, // 1166 >> 1 + 1166 negative
+        ];
+        let data = decompress(&sector, 1).unwrap();
+        assert_eq!(samples(&data), vec![0, 0, 1684, -65]);
+        assert!(matches!(
+            decompress(&sector[..3], 1),
+            Err(MPQParserError::InvalidCompressedData(
+                COMPRESSION_ADPCM_MONO
+            ))
+        ));
+    }
+
+    #[test]
+    fn it_decompresses_stereo() {
+        let sector = [
+            0x00, 0x01, // zero, bit shift
+            0x10, 0x00, // initial sample of the left channel
+            0xf0, 0xff, // initial sample of the right channel
+            0x01, // left: 16 + 494 >> 1 + 494
+            0x41, // right: -16 - (494 >> 1 + 494)
+            0x81, // left: step index 52
+            0x00, // left: + 1060 >> 1
+            0x80, // right: repeat
+        ];
+        let data = decompress(&sector, 2).unwrap();
+        assert_eq!(samples(&data), vec![16, -16, 757, -757, 1287, -757]);
+    }
+}
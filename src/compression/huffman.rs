@@ -0,0 +1,307 @@
+//! Storm Huffman decompression, an adaptive Huffman coder used mostly along with
+//! the IMA ADPCM compression of the WAVE files.
+//!
+//! The compressed data starts with the compression type, which selects the initial
+//! weights of the bytes, followed by the codes read least significant bit first.
+//! The tree is built from the weights and then rebalanced as bytes are decoded.
+//! The code `0x100` marks the end of the data and the code `0x101` is followed by
+//! a byte that is not part of the tree yet.
+//! NOTES:
+//! - Based on StormLib's `huff.cpp`.
+//! - Only the compression type 0, which starts with the same weight for every
+//!   byte and rebalances after each decoded byte, is supported, the weight tables
+//!   of the types 1 to 8 are not ported and these types fail as invalid data.
+
+use super::check_output_limit;
+use crate::{MPQParserError, COMPRESSION_HUFFMAN};
+
+/// The index of the head of the list of items, it is not part of the tree.
+const LIST_HEAD: usize = 0;
+/// The value that marks the end of the data.
+const END_OF_STREAM: usize = 0x100;
+/// The value followed by a byte to add to the tree.
+const NEW_BYTE: usize = 0x101;
+
+/// Returns the initial weight of each byte for a compression type.
+fn weight_table(compression_type: usize) -> Option<[u8; 256]> {
+    match compression_type {
+        0 => Some([1u8; 256]),
+        _ => None,
+    }
+}
+
+/// An item of the tree, either a leaf with a value or a node with two children.
+#[derive(Debug, Default, Clone)]
+struct Item {
+    /// The previous item in the list sorted by descending weight.
+    prev: usize,
+    /// The next item in the list sorted by descending weight.
+    next: usize,
+    /// The weight of the item, for nodes it is the sum of the children weights.
+    weight: u32,
+    /// The decompressed value of a leaf.
+    value: usize,
+    /// The parent node, [`None`] for the root.
+    parent: Option<usize>,
+    /// The child that is next in the list, the other child precedes it.
+    child_lo: Option<usize>,
+}
+
+/// The Huffman tree, its items are kept in a list sorted by descending weight, the
+/// first item is the root and the children of a node are adjacent.
+struct HuffmanTree {
+    /// The items, the first one is the head of the list.
+    items: Vec<Item>,
+    /// The leaf of each value.
+    items_by_value: Vec<Option<usize>>,
+}
+
+impl HuffmanTree {
+    /// Builds the tree from the initial weights of the bytes.
+    fn new(weights: &[u8; 256]) -> Self {
+        let mut tree = Self {
+            items: vec![Item::default()],
+            items_by_value: vec![None; NEW_BYTE + 1],
+        };
+        for (value, weight) in weights.iter().enumerate() {
+            if *weight != 0 {
+                let item = tree.create_item(value, *weight as u32);
+                tree.items_by_value[value] = Some(item);
+                tree.insert_by_weight(item);
+            }
+        }
+        for value in [END_OF_STREAM, NEW_BYTE] {
+            let item = tree.create_item(value, 1);
+            tree.items_by_value[value] = Some(item);
+            tree.insert_after(item, tree.last());
+        }
+        // Pair the items from the lowest weight, the nodes are inserted in order.
+        let mut child_lo = tree.last();
+        while child_lo != LIST_HEAD {
+            let child_hi = tree.items[child_lo].prev;
+            if child_hi == LIST_HEAD {
+                break;
+            }
+            let weight = tree.items[child_hi].weight + tree.items[child_lo].weight;
+            let node = tree.create_item(0, weight);
+            tree.items[child_lo].parent = Some(node);
+            tree.items[child_hi].parent = Some(node);
+            tree.items[node].child_lo = Some(child_lo);
+            tree.insert_by_weight(node);
+            child_lo = tree.items[child_hi].prev;
+        }
+        tree
+    }
+
+    /// The item with the lowest weight.
+    fn last(&self) -> usize {
+        self.items[LIST_HEAD].prev
+    }
+
+    /// Creates an item outside of the list.
+    fn create_item(&mut self, value: usize, weight: u32) -> usize {
+        let item = self.items.len();
+        self.items.push(Item {
+            prev: item,
+            next: item,
+            weight,
+            value,
+            ..Default::default()
+        });
+        item
+    }
+
+    /// Removes an item from the list.
+    fn unlink(&mut self, item: usize) {
+        let Item { prev, next, .. } = self.items[item];
+        self.items[prev].next = next;
+        self.items[next].prev = prev;
+        self.items[item].prev = item;
+        self.items[item].next = item;
+    }
+
+    /// Moves an item after another item of the list.
+    fn insert_after(&mut self, item: usize, insert_point: usize) {
+        self.unlink(item);
+        let next = self.items[insert_point].next;
+        self.items[item].prev = insert_point;
+        self.items[item].next = next;
+        self.items[next].prev = item;
+        self.items[insert_point].next = item;
+    }
+
+    /// Finds, from an item backwards, the first item with at least the weight.
+    fn find_higher_or_equal(&self, mut item: usize, weight: u32) -> usize {
+        while item != LIST_HEAD {
+            if self.items[item].weight >= weight {
+                return item;
+            }
+            item = self.items[item].prev;
+        }
+        LIST_HEAD
+    }
+
+    /// Moves an item after the last item with at least its weight.
+    fn insert_by_weight(&mut self, item: usize) {
+        let insert_point = self.find_higher_or_equal(self.last(), self.items[item].weight);
+        self.insert_after(item, insert_point);
+    }
+
+    /// Increments the weight of a leaf and its parents, swapping each item with the
+    /// first item of its former weight to keep the list sorted.
+    fn increment_weights(&mut self, leaf: usize) {
+        let mut current = Some(leaf);
+        while let Some(item) = current {
+            self.items[item].weight += 1;
+            let higher_item =
+                self.find_higher_or_equal(self.items[item].prev, self.items[item].weight);
+            let swapped = self.items[higher_item].next;
+            if swapped != item {
+                self.insert_after(swapped, item);
+                self.insert_after(item, higher_item);
+                let item_parent = self.items[item]
+                    .parent
+                    .expect("Only the root has no parent");
+                let swapped_parent = self.items[swapped]
+                    .parent
+                    .expect("Only the root has no parent");
+                if item_parent == swapped_parent {
+                    // The siblings swapped places.
+                    self.items[item_parent].child_lo = Some(swapped);
+                } else {
+                    if self.items[item_parent].child_lo == Some(item) {
+                        self.items[item_parent].child_lo = Some(swapped);
+                    }
+                    if self.items[swapped_parent].child_lo == Some(swapped) {
+                        self.items[swapped_parent].child_lo = Some(item);
+                    }
+                    self.items[item].parent = Some(swapped_parent);
+                    self.items[swapped].parent = Some(item_parent);
+                }
+            }
+            current = self.items[item].parent;
+        }
+    }
+
+    /// Adds a new byte by splitting the last leaf into itself and the new byte.
+    fn insert_new_byte(&mut self, value: usize) {
+        let last = self.last();
+        let last_value = self.items[last].value;
+        let last_copy = self.create_item(last_value, self.items[last].weight);
+        self.items_by_value[last_value] = Some(last_copy);
+        self.insert_after(last_copy, last);
+        self.items[last_copy].parent = Some(last);
+        let new_leaf = self.create_item(value, 0);
+        self.items_by_value[value] = Some(new_leaf);
+        self.insert_after(new_leaf, last_copy);
+        self.items[new_leaf].parent = Some(last);
+        self.items[last].child_lo = Some(new_leaf);
+        self.increment_weights(new_leaf);
+    }
+}
+
+/// Reads the compressed data least significant bit first.
+struct BitReader<'a> {
+    /// The remaining input.
+    input: &'a [u8],
+    /// The bits read from the input and not consumed yet.
+    bit_buffer: u32,
+    /// The number of bits in the bit buffer.
+    bit_count: usize,
+}
+
+impl<'a> BitReader<'a> {
+    /// Reads `need` bits, fails when the input is exhausted.
+    fn bits(&mut self, need: usize) -> Result<usize, MPQParserError> {
+        while self.bit_count < need {
+            let (byte, input) = self
+                .input
+                .split_first()
+                .ok_or(MPQParserError::InvalidCompressedData(COMPRESSION_HUFFMAN))?;
+            self.bit_buffer |= (*byte as u32) << self.bit_count;
+            self.input = input;
+            self.bit_count += 8;
+        }
+        let value = self.bit_buffer & ((1 << need) - 1);
+        self.bit_buffer >>= need;
+        self.bit_count -= need;
+        Ok(value as usize)
+    }
+
+    /// Decodes a value walking the tree from the root.
+    fn decode(&mut self, tree: &HuffmanTree) -> Result<usize, MPQParserError> {
+        let mut item = tree.items[LIST_HEAD].next;
+        while let Some(child_lo) = tree.items[item].child_lo {
+            item = if self.bits(1)? == 1 {
+                tree.items[child_lo].prev
+            } else {
+                child_lo
+            };
+        }
+        Ok(tree.items[item].value)
+    }
+}
+
+/// Decompresses data compressed by the Storm Huffman coder, producing at most
+/// `max_output` bytes.
+pub(crate) fn decompress(input: &[u8], max_output: usize) -> Result<Vec<u8>, MPQParserError> {
+    let mut reader = BitReader {
+        input,
+        bit_buffer: 0,
+        bit_count: 0,
+    };
+    let compression_type = reader.bits(8)?;
+    let weights = match weight_table(compression_type) {
+        Some(weights) => weights,
+        None => {
+            tracing::error!("Unsupported Huffman compression type {}", compression_type);
+            return Err(MPQParserError::InvalidCompressedData(COMPRESSION_HUFFMAN));
+        }
+    };
+    let mut tree = HuffmanTree::new(&weights);
+    let mut res = vec![];
+    loop {
+        let mut value = reader.decode(&tree)?;
+        if value == END_OF_STREAM {
+            break;
+        }
+        if value == NEW_BYTE {
+            value = reader.bits(8)?;
+            tree.insert_new_byte(value);
+        }
+        res.push(value as u8);
+        check_output_limit(res.len(), max_output)?;
+        tree.increment_weights(tree.items_by_value[value].expect("Decoded leaf"));
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+/// Storm Huffman Tests
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn it_decompresses_huffman() {
+        let compressed = [
+            0x00, 0x1d, 0xbf, 0xdf, 0x5f, 0xf7, 0xd7, 0xe7, 0x7d, 0xbb, 0x32, 0x35, 0x1e, 0xef,
+            0xaf, 0xbf,
+        ];
+        assert_eq!(
+            decompress(&compressed, usize::MAX).unwrap(),
+            b"AAAABBBAAC aaaa"
+        );
+        // The end of stream code is missing.
+        assert!(matches!(
+            decompress(&compressed[..8], usize::MAX),
+            Err(MPQParserError::InvalidCompressedData(COMPRESSION_HUFFMAN))
+        ));
+        // The weights of the compression type are not available.
+        for compression_type in [0x01, 0x08, 0x09] {
+            assert!(matches!(
+                decompress(&[compression_type, 0x00], usize::MAX),
+                Err(MPQParserError::InvalidCompressedData(COMPRESSION_HUFFMAN))
+            ));
+        }
+    }
+}
@@ -0,0 +1,85 @@
+//! LZMA decompression, used by the archives of format version 3 and later.
+//!
+//! The compressed data starts with a filter byte, followed by the 5 bytes of the
+//! LZMA properties and the 8-byte little endian uncompressed size, which is the
+//! header of the `.lzma` format, and the LZMA stream.
+//! NOTES:
+//! - Based on StormLib's `SCompression.cpp`, only the filter 0 (no filter) is used.
+
+use crate::{MPQParserError, COMPRESSION_LZMA};
+
+/// The only filter byte written by Storm.
+const LZMA_FILTER_NONE: u8 = 0x00;
+
+/// Collects the decompressed data, refusing to grow beyond `max_output` bytes.
+struct LimitedWriter {
+    data: Vec<u8>,
+    max_output: usize,
+    exceeded: bool,
+}
+
+impl std::io::Write for LimitedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.data.len() + buf.len() > self.max_output {
+            self.exceeded = true;
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Decompresses an LZMA sector after its compression type, producing at most
+/// `max_output` bytes.
+pub(crate) fn decompress(input: &[u8], max_output: usize) -> Result<Vec<u8>, MPQParserError> {
+    let mut stream = match input.split_first() {
+        Some((&LZMA_FILTER_NONE, stream)) => stream,
+        _ => return Err(MPQParserError::InvalidCompressedData(COMPRESSION_LZMA)),
+    };
+    let mut res = LimitedWriter {
+        data: vec![],
+        max_output,
+        exceeded: false,
+    };
+    if let Err(err) = lzma_rs::lzma_decompress(&mut stream, &mut res) {
+        if res.exceeded {
+            tracing::warn!("Decompressed output exceeds the limit of {max_output} bytes");
+            return Err(MPQParserError::BudgetExceeded);
+        }
+        tracing::error!("Unable to decompress LZMA data: {:?}", err);
+        return Err(MPQParserError::InvalidCompressedData(COMPRESSION_LZMA));
+    }
+    Ok(res.data)
+}
+
+#[cfg(test)]
+/// LZMA Tests
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn it_decompresses_lzma() {
+        let contents = b"replay.game.events".repeat(10);
+        let mut sector = vec![LZMA_FILTER_NONE];
+        lzma_rs::lzma_compress(&mut &contents[..], &mut sector).unwrap();
+        assert_eq!(decompress(&sector, usize::MAX).unwrap(), contents);
+        assert!(matches!(
+            decompress(&sector, 100),
+            Err(MPQParserError::BudgetExceeded)
+        ));
+        sector.insert(0, COMPRESSION_LZMA);
+        let (_tail, data) = crate::MPQ::decompress(&sector).unwrap();
+        assert_eq!(data, contents);
+        sector.remove(0);
+        // An unknown filter.
+        sector[0] = 0x01;
+        assert!(matches!(
+            decompress(&sector, usize::MAX),
+            Err(MPQParserError::InvalidCompressedData(COMPRESSION_LZMA))
+        ));
+    }
+}
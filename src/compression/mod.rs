@@ -0,0 +1,23 @@
+//! Decompression of the compression types other than zlib and BZ2.
+
+pub mod adpcm;
+pub mod huffman;
+#[cfg(feature = "lzma")]
+pub mod lzma;
+pub mod pkware;
+pub mod sparse;
+
+use crate::MPQParserError;
+
+/// Fails once the decompressed output grows beyond `max_output` bytes, so that
+/// a small sector can't expand without bounds.
+pub(crate) fn check_output_limit(
+    output_len: usize,
+    max_output: usize,
+) -> Result<(), MPQParserError> {
+    if output_len > max_output {
+        tracing::warn!("Decompressed output exceeds the limit of {max_output} bytes");
+        return Err(MPQParserError::BudgetExceeded);
+    }
+    Ok(())
+}
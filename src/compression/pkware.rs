@@ -0,0 +1,201 @@
+//! PKWARE Data Compression Library "explode".
+//!
+//! The imploded data starts with two bytes, whether the literals are Huffman
+//! coded and the number of bits of the dictionary size, followed by a stream of
+//! literals and length/distance pairs read least significant bit first.
+//! The Huffman codes are stored bit-inverted and described by compact tables
+//! of code lengths.
+//! NOTES:
+//! - Based on Mark Adler's `blast.c` from the zlib contrib directory.
+
+use super::check_output_limit;
+use crate::{MPQParserError, COMPRESSION_PKWARE};
+
+/// The maximum number of bits of a code.
+const MAX_BITS: usize = 13;
+/// The length that marks the end of the stream.
+const END_OF_STREAM: usize = 519;
+
+/// Bit lengths of the literal codes, in compact form.
+const LITERAL_LENGTHS: [u8; 98] = [
+    11, 124, 8, 7, 28, 7, 188, 13, 76, 4, 10, 8, 12, 10, 12, 10, 8, 23, 8, 9, 7, 6, 7, 8, 7, 6, 55,
+    8, 23, 24, 12, 11, 7, 9, 11, 12, 6, 7, 22, 5, 7, 24, 6, 11, 9, 6, 7, 22, 7, 11, 38, 7, 9, 8,
+    25, 11, 8, 11, 9, 12, 8, 12, 5, 38, 5, 38, 5, 11, 7, 5, 6, 21, 6, 10, 53, 8, 7, 24, 10, 27, 44,
+    253, 253, 253, 252, 252, 252, 13, 12, 45, 12, 45, 12, 61, 12, 45, 44, 173,
+];
+/// Bit lengths of the length codes, in compact form.
+const LENGTH_LENGTHS: [u8; 6] = [2, 35, 36, 53, 38, 23];
+/// Bit lengths of the distance codes, in compact form.
+const DISTANCE_LENGTHS: [u8; 7] = [2, 20, 53, 230, 247, 151, 248];
+/// The base of each length symbol.
+const LENGTH_BASE: [usize; 16] = [3, 2, 4, 5, 6, 7, 8, 9, 10, 12, 16, 24, 40, 72, 136, 264];
+/// The extra bits of each length symbol.
+const LENGTH_EXTRA: [usize; 16] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8];
+
+/// A canonical Huffman code, the symbols are sorted by code length.
+struct Huffman {
+    /// The number of codes of each length.
+    count: [u16; MAX_BITS + 1],
+    /// The symbols ordered by code.
+    symbol: Vec<u16>,
+}
+
+impl Huffman {
+    /// Builds the code from the compact table of lengths, each byte contains the
+    /// code length in the low nibble and the repetitions minus one in the high nibble.
+    fn new(compact_lengths: &[u8]) -> Self {
+        let mut lengths = vec![];
+        for byte in compact_lengths {
+            let repeat = (byte >> 4) as usize + 1;
+            lengths.extend(std::iter::repeat_n((byte & 15) as usize, repeat));
+        }
+        let mut count = [0u16; MAX_BITS + 1];
+        for length in &lengths {
+            count[*length] += 1;
+        }
+        let mut offsets = [0usize; MAX_BITS + 1];
+        for length in 1..MAX_BITS {
+            offsets[length + 1] = offsets[length] + count[length] as usize;
+        }
+        let mut symbol = vec![0u16; lengths.len()];
+        for (value, length) in lengths.iter().enumerate() {
+            if *length != 0 {
+                symbol[offsets[*length]] = value as u16;
+                offsets[*length] += 1;
+            }
+        }
+        Self { count, symbol }
+    }
+}
+
+/// Reads the imploded stream least significant bit first.
+struct BitReader<'a> {
+    /// The remaining input.
+    input: &'a [u8],
+    /// The bits read from the input and not consumed yet.
+    bit_buffer: u32,
+    /// The number of bits in the bit buffer.
+    bit_count: usize,
+}
+
+impl<'a> BitReader<'a> {
+    /// Reads `need` bits, fails when the input is exhausted.
+    fn bits(&mut self, need: usize) -> Result<usize, MPQParserError> {
+        let mut value = self.bit_buffer;
+        while self.bit_count < need {
+            let (byte, input) = self
+                .input
+                .split_first()
+                .ok_or(MPQParserError::InvalidCompressedData(COMPRESSION_PKWARE))?;
+            value |= (*byte as u32) << self.bit_count;
+            self.input = input;
+            self.bit_count += 8;
+        }
+        self.bit_buffer = value >> need;
+        self.bit_count -= need;
+        Ok((value & ((1 << need) - 1)) as usize)
+    }
+
+    /// Decodes a symbol, the codes are stored bit-inverted.
+    fn decode(&mut self, huffman: &Huffman) -> Result<usize, MPQParserError> {
+        let mut code = 0usize;
+        let mut first = 0usize;
+        let mut index = 0usize;
+        for length in 1..=MAX_BITS {
+            code |= self.bits(1)? ^ 1;
+            let count = huffman.count[length] as usize;
+            if code < first + count {
+                return Ok(huffman.symbol[index + code - first] as usize);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(MPQParserError::InvalidCompressedData(COMPRESSION_PKWARE))
+    }
+}
+
+/// Decompresses data imploded by the PKWARE Data Compression Library, producing
+/// at most `max_output` bytes.
+pub(crate) fn explode(input: &[u8], max_output: usize) -> Result<Vec<u8>, MPQParserError> {
+    let invalid_data = || MPQParserError::InvalidCompressedData(COMPRESSION_PKWARE);
+    let mut reader = BitReader {
+        input,
+        bit_buffer: 0,
+        bit_count: 0,
+    };
+    let coded_literals = match reader.bits(8)? {
+        0 => false,
+        1 => true,
+        _ => return Err(invalid_data()),
+    };
+    let dictionary_bits = reader.bits(8)?;
+    if !(4..=6).contains(&dictionary_bits) {
+        return Err(invalid_data());
+    }
+    let literal_code = Huffman::new(&LITERAL_LENGTHS);
+    let length_code = Huffman::new(&LENGTH_LENGTHS);
+    let distance_code = Huffman::new(&DISTANCE_LENGTHS);
+    let mut res: Vec<u8> = vec![];
+    loop {
+        if reader.bits(1)? == 1 {
+            let symbol = reader.decode(&length_code)?;
+            let length = LENGTH_BASE[symbol] + reader.bits(LENGTH_EXTRA[symbol])?;
+            if length == END_OF_STREAM {
+                break;
+            }
+            let low_bits = if length == 2 { 2 } else { dictionary_bits };
+            let distance =
+                (reader.decode(&distance_code)? << low_bits) + reader.bits(low_bits)? + 1;
+            if distance > res.len() {
+                tracing::error!(
+                    "Distance {} is beyond the {} bytes decompressed",
+                    distance,
+                    res.len()
+                );
+                return Err(invalid_data());
+            }
+            // The copy may overlap the bytes it produces.
+            let start = res.len() - distance;
+            for idx in start..start + length {
+                res.push(res[idx]);
+            }
+            check_output_limit(res.len(), max_output)?;
+        } else {
+            let literal = if coded_literals {
+                reader.decode(&literal_code)?
+            } else {
+                reader.bits(8)?
+            };
+            res.push(literal as u8);
+            check_output_limit(res.len(), max_output)?;
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+/// PKWARE Data Compression Library Tests
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn it_explodes_data() {
+        // The example from blast.c
+        let imploded = [0x00, 0x04, 0x82, 0x24, 0x25, 0x8f, 0x80, 0x7f];
+        assert_eq!(explode(&imploded, usize::MAX).unwrap(), b"AIAIAIAIAIAIA");
+        // The copies stop at the output limit.
+        assert!(matches!(
+            explode(&imploded, 4),
+            Err(MPQParserError::BudgetExceeded)
+        ));
+        assert!(matches!(
+            explode(&imploded[..5], usize::MAX),
+            Err(MPQParserError::InvalidCompressedData(COMPRESSION_PKWARE))
+        ));
+        assert!(matches!(
+            explode(&[0x00, 0x07, 0x82], usize::MAX),
+            Err(MPQParserError::InvalidCompressedData(COMPRESSION_PKWARE))
+        ));
+    }
+}
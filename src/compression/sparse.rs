@@ -0,0 +1,100 @@
+//! Sparse decompression, a run-length encoding of the runs of zeros.
+//!
+//! The compressed data starts with the big-endian uncompressed size, followed by
+//! control bytes. A control byte with the high bit set is followed by a run of
+//! literal bytes, otherwise it stands for a run of zeros.
+//! NOTES:
+//! - Based on StormLib's `sparse.cpp`.
+
+use super::check_output_limit;
+use crate::{MPQParserError, COMPRESSION_SPARSE};
+
+/// Marks a control byte followed by literal bytes.
+const LITERAL_RUN: u8 = 0x80;
+/// The largest expansion of a control byte, a run of 0x7F + 3 zeros.
+const MAX_RUN_EXPANSION: usize = 130;
+
+/// Expands the runs of literals and zeros, the expanded data must match the stored size,
+/// which can't be over `max_output` bytes.
+pub(crate) fn decompress_sparse(
+    input: &[u8],
+    max_output: usize,
+) -> Result<Vec<u8>, MPQParserError> {
+    let invalid_data = || MPQParserError::InvalidCompressedData(COMPRESSION_SPARSE);
+    let (stored_size, mut input) = match input {
+        [b0, b1, b2, b3, input @ ..] => (u32::from_be_bytes([*b0, *b1, *b2, *b3]) as usize, input),
+        _ => return Err(invalid_data()),
+    };
+    check_output_limit(stored_size, max_output)?;
+    // The stored size is untrusted, each control byte expands to at most
+    // `MAX_RUN_EXPANSION` bytes.
+    let mut res: Vec<u8> = Vec::with_capacity(
+        stored_size
+            .min(max_output)
+            .min(input.len().saturating_mul(MAX_RUN_EXPANSION)),
+    );
+    while let Some((control_byte, tail)) = input.split_first() {
+        if res.len() >= stored_size {
+            break;
+        }
+        let remaining = stored_size - res.len();
+        if control_byte & LITERAL_RUN != 0 {
+            let run_length = (control_byte & !LITERAL_RUN) as usize + 1;
+            let literals = tail.get(..run_length).ok_or_else(invalid_data)?;
+            res.extend_from_slice(&literals[..run_length.min(remaining)]);
+            input = &tail[run_length..];
+        } else {
+            let run_length = *control_byte as usize + 3;
+            res.resize(res.len() + run_length.min(remaining), 0);
+            input = tail;
+        }
+    }
+    if res.len() != stored_size {
+        tracing::error!(
+            "Sparse data expands to {} bytes instead of {}",
+            res.len(),
+            stored_size
+        );
+        return Err(invalid_data());
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+/// Sparse Decompression Tests
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn it_decompresses_sparse() {
+        let sparse = [
+            0x00, 0x00, 0x00, 0x0a, // uncompressed size
+            0x82, b'a', b'b', b'c', // 3 literals
+            0x02, // 5 zeros
+            0x81, b'd', b'e', // 2 literals
+        ];
+        let data = decompress_sparse(&sparse, usize::MAX).unwrap();
+        assert_eq!(data, b"abc\0\0\0\0\0de");
+        assert_eq!(data.len(), 0x0a);
+        // The stored size is over the output limit.
+        assert!(matches!(
+            decompress_sparse(&sparse, 9),
+            Err(MPQParserError::BudgetExceeded)
+        ));
+        // The expanded data is shorter than the stored size.
+        assert!(matches!(
+            decompress_sparse(&sparse[..9], usize::MAX),
+            Err(MPQParserError::InvalidCompressedData(COMPRESSION_SPARSE))
+        ));
+        // A huge stored size is not preallocated and fails as the data is short.
+        assert!(matches!(
+            decompress_sparse(&[0xff, 0xff, 0xff, 0xff, 0x7f], usize::MAX),
+            Err(MPQParserError::InvalidCompressedData(COMPRESSION_SPARSE))
+        ));
+        // A run of literals beyond the end of the input.
+        assert!(matches!(
+            decompress_sparse(&sparse[..6], usize::MAX),
+            Err(MPQParserError::InvalidCompressedData(COMPRESSION_SPARSE))
+        ));
+    }
+}
@@ -0,0 +1,49 @@
+//! The Decompressor Registry.
+//! Allows handling compression types that are not supported by the crate,
+//! the registered decompressors take precedence over the built-in ones.
+use crate::MPQParserError;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A function that decompresses the data of a sector, the compression type
+/// prefix has already been removed.
+pub type Decompressor = Arc<dyn Fn(&[u8]) -> Result<Vec<u8>, MPQParserError> + Send + Sync>;
+
+/// The decompressors registered per compression type.
+#[derive(Clone, Default)]
+pub struct DecompressorRegistry {
+    /// The decompressor for each compression type.
+    decompressors: HashMap<u8, Decompressor>,
+}
+
+impl std::fmt::Debug for DecompressorRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut compression_types: Vec<&u8> = self.decompressors.keys().collect();
+        compression_types.sort();
+        f.debug_struct("DecompressorRegistry")
+            .field("compression_types", &compression_types)
+            .finish()
+    }
+}
+
+impl DecompressorRegistry {
+    /// Creates an empty registry, only the built-in decompressors are used.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the decompressor for a compression type, replacing the built-in
+    /// or previously registered one.
+    pub fn register<F>(&mut self, compression_type: u8, decompressor: F)
+    where
+        F: Fn(&[u8]) -> Result<Vec<u8>, MPQParserError> + Send + Sync + 'static,
+    {
+        self.decompressors
+            .insert(compression_type, Arc::new(decompressor));
+    }
+
+    /// Returns the decompressor registered for a compression type.
+    pub fn get(&self, compression_type: u8) -> Option<&Decompressor> {
+        self.decompressors.get(&compression_type)
+    }
+}
@@ -20,6 +20,14 @@ pub enum MPQParserError {
     /// Unable to parse the byte aligned data types
     #[error("Nom ByteAligned Error {0}")]
     ByteAligned(String),
+    /// Unable to parse the byte aligned data types at an offset of the input
+    #[error("Nom ByteAligned Error at offset {offset}: {message}")]
+    ByteAlignedAt {
+        /// The offset within the input where parsing failed
+        offset: usize,
+        /// The nom error
+        message: String,
+    },
     /// An I/O Error
     #[error("IO Error")]
     IoError(#[from] std::io::Error),
@@ -50,6 +58,61 @@ pub enum MPQParserError {
     /// Encryption table index not found
     #[error("Encryption table index not found, check error messages")]
     EncryptionTableIndexNotFound,
+    /// A character of a filename is beyond the range of the encryption table.
+    #[error("Character out of range of the encryption table: {0:?}")]
+    HashCharOutOfRange(char),
+    /// The user data content is not a replay header.
+    #[error("Invalid replay header")]
+    InvalidReplayHeader,
+    /// The block table index is out of range of the block table.
+    #[error("Invalid block table index {0}")]
+    InvalidBlockIndex(u32),
+    /// The sector offset table doesn't match the block data.
+    #[error("Invalid sector offset table")]
+    InvalidSectorOffsetTable,
+    /// The file exceeds the extraction budget.
+    #[error("Extraction budget exceeded")]
+    BudgetExceeded,
+    /// The file flags are not supported by the operation.
+    #[error("Unsupported file flags: {0:#010X}")]
+    UnsupportedFileFlags(u32),
+    /// The sector index is out of range of the sectors of the file.
+    #[error("Sector index {0} out of range")]
+    SectorIndexOutOfRange(usize),
+    /// A table ends beyond the end of the archive.
+    #[error("Offset out of bounds of the archive: {0}")]
+    OffsetOutOfBounds(u64),
+    /// The archive header declares an invalid size.
+    #[error("Invalid archive header size: {0}")]
+    InvalidHeaderSize(u32),
+    /// A file extracts to different contents after rebuilding the archive.
+    #[error("File doesn't round trip: {0}")]
+    RoundtripMismatch(String),
+    /// The compressed data of a sector is invalid for its compression type.
+    #[error("Invalid compressed data for compression type: {0}")]
+    InvalidCompressedData(u8),
+    /// The hash table size is not a power of two, doesn't fit the files or exceeds
+    /// the maximum of the format version.
+    #[error("Invalid hash table size: {0}")]
+    InvalidHashTableSize(u32),
+    /// The block table has more entries than allowed by the format version.
+    #[error("Invalid block table size: {0}")]
+    InvalidBlockTableSize(u32),
+    /// The checksum of a file doesn't match the expected one.
+    #[error("Checksum mismatch for file: {0}")]
+    ChecksumMismatch(String),
+    /// The size of the block doesn't match the size of the file.
+    #[error("Block size doesn't match the file size")]
+    SizeMismatch,
+    /// The MD5 digest of a table or of the header doesn't match the header.
+    #[error("MD5 mismatch for: {0}")]
+    Md5Mismatch(String),
+    /// The `(signature)` file is too small to contain the weak signature.
+    #[error("Invalid weak signature file of {0} bytes")]
+    InvalidSignatureFile(usize),
+    /// The format version of the archive is not supported by the operation.
+    #[error("Unsupported archive format version: {0}")]
+    UnsupportedArchiveFormat(u16),
 }
 
 /// Conversion of errors from byte aligned parser
@@ -68,6 +131,29 @@ where
     }
 }
 
+impl MPQParserError {
+    /// Converts a nom error keeping the offset within `orig_input` where parsing
+    /// failed, computed from the remaining input of the error.
+    /// Falls back to [`MPQParserError::ByteAligned`] when the remaining input is
+    /// not part of `orig_input`.
+    pub fn from_nom_at(orig_input: &[u8], err: nom::Err<nom::error::Error<&[u8]>>) -> Self {
+        let e = match err {
+            nom::Err::Incomplete(_) => {
+                unreachable!("This library is compatible with only complete parsers, not streaming")
+            }
+            nom::Err::Error(e) | nom::Err::Failure(e) => e,
+        };
+        let offset = (e.input.as_ptr() as usize).checked_sub(orig_input.as_ptr() as usize);
+        match offset {
+            Some(offset) if offset <= orig_input.len() => MPQParserError::ByteAlignedAt {
+                offset,
+                message: format!("{:?}", e.code),
+            },
+            _ => MPQParserError::ByteAligned(format!("{:?}", e)),
+        }
+    }
+}
+
 impl<I> ParseError<I> for MPQParserError
 where
     I: Clone,
@@ -5,48 +5,231 @@
 
 #![warn(missing_docs)]
 pub use error::MPQResult;
+use md5::Md5;
 use nom::bytes::complete::take;
 use nom::error::dbg_dmp;
+use nom::multi::count;
 use nom::number::complete::{i32, u32, u8};
 use nom::IResult;
+use parser::input_at;
 use parser::MPQHashType;
-use std::collections::HashMap;
-use std::io::Read;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, OnceLock};
 
 pub mod builder;
+pub mod cache;
+mod compression;
+pub mod decompressor;
 pub mod error;
 pub mod parser;
+pub mod signature;
 pub use builder::MPQBuilder;
+pub use builder::MPQBuilderFile;
+pub use cache::DecompressedCache;
 use compress::zlib;
+pub use decompressor::DecompressorRegistry;
 pub use error::MPQParserError;
+pub use parser::AttributeFlags;
+pub use parser::MPQAttributes;
+pub use parser::MPQBetTable;
+pub use parser::MPQBlockFlags;
 pub use parser::MPQBlockTableEntry;
 pub use parser::MPQFileHeader;
 pub use parser::MPQHashTableEntry;
+pub use parser::MPQHetTable;
 pub use parser::MPQUserData;
+pub use parser::MPQVersion;
 use parser::LITTLE_ENDIAN;
 
-// Unused flags:
-// pub const MPQ_FILE_IMPLODE: u32 = 0x00000100;
-// pub const MPQ_FILE_FIX_KEY: u32 = 0x00020000;
-// pub const MPQ_FILE_DELETE_MARKER: u32 = 0x02000000;
-
+/// The sector is imploded using PKWARE Data Compression Library.
+pub const MPQ_FILE_IMPLODE: u32 = 0x00000100;
 /// The sector is compressed
 pub const MPQ_FILE_COMPRESS: u32 = 0x00000200;
 /// The sector is encrypted.
 pub const MPQ_FILE_ENCRYPTED: u32 = 0x00010000;
+/// The encryption key is adjusted by the block offset and file size.
+pub const MPQ_FILE_FIX_KEY: u32 = 0x00020000;
 /// The sector contains a single file/unit.
 pub const MPQ_FILE_SINGLE_UNIT: u32 = 0x01000000;
+/// The file is a deletion marker, it no longer exists.
+pub const MPQ_FILE_DELETE_MARKER: u32 = 0x02000000;
 /// The sector has cyclic redundancy check.
 pub const MPQ_FILE_SECTOR_CRC: u32 = 0x04000000;
 /// The sector exists (as opposed to marked as deleted)
 pub const MPQ_FILE_EXISTS: u32 = 0x80000000;
 
+/// The well known internal files that may be present in an archive.
+pub const MPQ_INTERNAL_FILES: [&str; 5] = [
+    "(listfile)",
+    "(attributes)",
+    "(signature)",
+    "(user data)",
+    "(patch_metadata)",
+];
+
 /// The sector has no compression
 pub const COMPRESSION_PLAINTEXT: u8 = 0;
 /// The sector is compressed using [`zlib`]
 pub const COMPRESSION_ZLIB: u8 = 2;
 /// The sector is compressed using [`bzip2`]
 pub const COMPRESSION_BZ2: u8 = 16;
+/// The sector is compressed using Huffman, only used for WAVE files.
+pub const COMPRESSION_HUFFMAN: u8 = 0x01;
+/// The sector is compressed using the PKWARE Data Compression Library.
+pub const COMPRESSION_PKWARE: u8 = 0x08;
+/// The sector is compressed using LZMA, it can't be combined with other compressions.
+pub const COMPRESSION_LZMA: u8 = 0x12;
+/// The sector is compressed using the sparse compression.
+pub const COMPRESSION_SPARSE: u8 = 0x20;
+/// The sector is compressed using IMA ADPCM mono, only used for WAVE files.
+pub const COMPRESSION_ADPCM_MONO: u8 = 0x40;
+/// The sector is compressed using IMA ADPCM stereo, only used for WAVE files.
+pub const COMPRESSION_ADPCM_STEREO: u8 = 0x80;
+/// The compressions that may be combined in the compression type of a sector, in
+/// the order they are undone, the reverse of the order they are applied.
+pub const MPQ_DECOMPRESSION_ORDER: [u8; 7] = [
+    COMPRESSION_BZ2,
+    COMPRESSION_PKWARE,
+    COMPRESSION_ZLIB,
+    COMPRESSION_HUFFMAN,
+    COMPRESSION_ADPCM_STEREO,
+    COMPRESSION_ADPCM_MONO,
+    COMPRESSION_SPARSE,
+];
+
+/// Limits the work done when reading a file, useful when handling untrusted input.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ExtractionBudget {
+    /// The maximum number of bytes the file may decompress to.
+    pub max_output_bytes: usize,
+    /// The maximum number of sectors the file may contain.
+    pub max_sectors: usize,
+}
+
+/// The features an archive requires from a reader.
+#[derive(Debug, PartialEq, Default, Clone, Copy)]
+pub struct Capabilities {
+    /// Some files are encrypted, see [`MPQ_FILE_ENCRYPTED`]
+    pub encryption: bool,
+    /// Some files are imploded, see [`MPQ_FILE_IMPLODE`]
+    pub implode: bool,
+    /// Some sectors use [`COMPRESSION_ZLIB`]
+    pub zlib: bool,
+    /// Some sectors use [`COMPRESSION_BZ2`]
+    pub bzip2: bool,
+    /// Some sectors use [`COMPRESSION_PKWARE`]
+    pub pkware: bool,
+    /// Some sectors use [`COMPRESSION_LZMA`]
+    pub lzma: bool,
+    /// Some sectors use [`COMPRESSION_SPARSE`]
+    pub sparse: bool,
+    /// Some sectors use [`COMPRESSION_HUFFMAN`]
+    pub huffman: bool,
+    /// Some sectors use [`COMPRESSION_ADPCM_MONO`] or [`COMPRESSION_ADPCM_STEREO`]
+    pub adpcm: bool,
+    /// The archive contains HET and BET tables.
+    pub het_bet_tables: bool,
+    /// The archive uses offsets beyond 4GB.
+    pub large_archive: bool,
+}
+
+impl Capabilities {
+    /// Adds the compressions of a compression type byte.
+    fn add_compression(&mut self, compression_type: u8) {
+        if compression_type == COMPRESSION_LZMA {
+            self.lzma = true;
+            return;
+        }
+        self.zlib |= compression_type & COMPRESSION_ZLIB != 0;
+        self.bzip2 |= compression_type & COMPRESSION_BZ2 != 0;
+        self.pkware |= compression_type & COMPRESSION_PKWARE != 0;
+        self.sparse |= compression_type & COMPRESSION_SPARSE != 0;
+        self.huffman |= compression_type & COMPRESSION_HUFFMAN != 0;
+        self.adpcm |= compression_type & (COMPRESSION_ADPCM_MONO | COMPRESSION_ADPCM_STEREO) != 0;
+    }
+}
+
+/// The index of the sector that failed to be read and the reason.
+pub type SectorFailure = (usize, MPQParserError);
+
+/// The number of leading bytes of a file used to detect its [`FileType`].
+const FILE_TYPE_PEEK_SIZE: usize = 512;
+
+/// The type of the contents of a file, detected from its magic number.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FileType {
+    /// The file has no contents.
+    Empty,
+    /// A nested MPQ archive, starting with either header kind.
+    Mpq,
+    /// A BLP texture.
+    Blp,
+    /// A DDS texture.
+    Dds,
+    /// A PNG image.
+    Png,
+    /// A JPEG image.
+    Jpeg,
+    /// A RIFF WAVE sound.
+    Wave,
+    /// UTF-8 text.
+    Text,
+    /// Any other contents.
+    Binary,
+}
+
+impl FileType {
+    /// Detects the type from the leading bytes of a file, the data may end in the
+    /// middle of a UTF-8 character.
+    pub fn detect(data: &[u8]) -> Self {
+        if data.is_empty() {
+            Self::Empty
+        } else if parser::is_archive_header(data) || parser::is_user_data_header(data) {
+            Self::Mpq
+        } else if data.starts_with(b"BLP1") || data.starts_with(b"BLP2") {
+            Self::Blp
+        } else if data.starts_with(b"DDS ") {
+            Self::Dds
+        } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Self::Png
+        } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Self::Jpeg
+        } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WAVE") {
+            Self::Wave
+        } else {
+            match std::str::from_utf8(data) {
+                Ok(_) => Self::Text,
+                Err(err) if err.error_len().is_none() => Self::Text,
+                Err(_) => Self::Binary,
+            }
+        }
+    }
+}
+
+/// An [`MPQ`] along with the contents of its archive, so it can be passed around
+/// without borrowing the input, i.e. as returned by [`parser::parse_async`].
+#[derive(Debug, Default)]
+pub struct OwnedMpq {
+    /// The contents of the archive file.
+    pub archive: Vec<u8>,
+    /// The parsed archive.
+    pub mpq: MPQ,
+}
+
+impl OwnedMpq {
+    /// Reads an embedded file, see [`MPQ::read_mpq_file_sector`].
+    pub fn read_file(&self, filename: &str) -> Result<Vec<u8>, MPQParserError> {
+        let (_tail, file_data) = self
+            .mpq
+            .read_mpq_file_sector(filename, false, &self.archive)?;
+        Ok(file_data)
+    }
+}
 
 /// The main MPQ object that contains the parsed entries
 #[derive(Debug, Default)]
@@ -60,24 +243,50 @@ pub struct MPQ {
     pub hash_table_entries: Vec<MPQHashTableEntry>,
     /// The block table entries, after decryption and parsing
     pub block_table_entries: Vec<MPQBlockTableEntry>,
+    /// The hi-block table, present in large archives of the Burning Crusade
+    /// format and later.
+    pub hi_block_table: Option<Vec<u16>>,
+    /// The HET table, present in some archives of the Cataclysm formats.
+    pub het_table: Option<MPQHetTable>,
+    /// The BET table, present in some archives of the Cataclysm formats, when the
+    /// archive has no block table its entries are used as the block table.
+    pub bet_table: Option<MPQBetTable>,
     /// The internal MPQ encryption table.
-    pub encryption_table: HashMap<u32, u32>,
+    pub encryption_table: Arc<HashMap<u32, u32>>,
+    /// The decompressors for compression types not supported by the crate.
+    pub decompressors: DecompressorRegistry,
+    /// The fingerprint of the archive, see [`MPQ::archive_fingerprint`], computed
+    /// on first use.
+    archive_fingerprint: OnceLock<[u8; 32]>,
 }
 
+/// The encryption table shared by all the [`MPQ`] and [`MPQBuilder`], it is only
+/// prepared once.
+static ENCRYPTION_TABLE: OnceLock<Arc<HashMap<u32, u32>>> = OnceLock::new();
+
 impl MPQ {
+    /// Returns the encryption table, it is immutable so it is shared read-only
+    /// between every archive, even across threads.
+    pub fn shared_encryption_table() -> Arc<HashMap<u32, u32>> {
+        ENCRYPTION_TABLE
+            .get_or_init(|| Arc::new(Self::prepare_encryption_table()))
+            .clone()
+    }
+
     /// Prepares the encryption table, this hashmap is used for block-sized
     /// decryption operations.
     fn prepare_encryption_table() -> HashMap<u32, u32> {
-        let mut seed: u32 = 0x00100001;
+        // The seed is kept in u64 so the multiplication can't overflow.
+        let mut seed: u64 = 0x00100001;
         let mut res = HashMap::new();
         for i in (0..256).map(|x| x as u32) {
             let mut idx = i;
             for _ in 0..5 {
                 seed = (seed * 125 + 3) % 0x2AAAAB;
-                let temp1 = (seed & 0xFFFF) << 0x10;
+                let temp1 = ((seed & 0xFFFF) as u32) << 0x10;
 
                 seed = (seed * 125 + 3) % 0x2AAAAB;
-                let temp2 = seed & 0xFFFF;
+                let temp2 = (seed & 0xFFFF) as u32;
 
                 res.insert(idx, temp1 | temp2);
 
@@ -101,6 +310,11 @@ impl MPQ {
         let mut seed2: u64 = 0xEEEEEEEEu64;
         for ch in location.to_uppercase().chars() {
             let ch_ord: u32 = ch.into();
+            // Characters beyond a byte would index the table of another hash type.
+            if ch_ord > 0xFF {
+                tracing::error!("Character {:?} of {} can't be hashed", ch, location);
+                return Err(MPQParserError::HashCharOutOfRange(ch));
+            }
             let hash_type_idx: u32 = hash_type.try_into()?;
             let value = match encryption_table.get(&((hash_type_idx << 8) + ch_ord)) {
                 Some(val) => val,
@@ -119,230 +333,3999 @@ impl MPQ {
         Ok(seed1 as u32)
     }
 
+    /// Get the hash table entries whose hashes match a filename.
+    ///
+    /// The search starts at the home entry of the filename, given by its
+    /// [`MPQHashType::TableOffset`] hash, and probes the following entries until an
+    /// empty entry is found or the whole table has been visited. Deleted entries
+    /// don't stop the search.
+    fn probe_hash_table(&self, filename: &str) -> Result<Vec<&MPQHashTableEntry>, MPQParserError> {
+        let hash_a = Self::mpq_string_hash(&self.encryption_table, filename, MPQHashType::HashA)?;
+        let hash_b = Self::mpq_string_hash(&self.encryption_table, filename, MPQHashType::HashB)?;
+        let table_size = self.hash_table_entries.len();
+        let mut res = vec![];
+        if table_size == 0 {
+            return Ok(res);
+        }
+        let home =
+            Self::mpq_string_hash(&self.encryption_table, filename, MPQHashType::TableOffset)?
+                as usize
+                % table_size;
+        for probe in 0..table_size {
+            let entry = &self.hash_table_entries[(home + probe) % table_size];
+            if entry.is_empty() {
+                break;
+            }
+            if entry.hash_a == hash_a && entry.hash_b == hash_b {
+                res.push(entry);
+            }
+        }
+        Ok(res)
+    }
+
     /// Get the hash table entry corresponding to a given filename.
     ///
     /// A filename is hashed with both [`MPQHashType::HashA`] and [`MPQHashType::HashB`]
-    /// to uniquely identify the filename in the archive
+    /// to uniquely identify the filename in the archive.
+    /// When a `locale` is given only the entry of that locale is returned, otherwise
+    /// the entry of the neutral locale (0) is preferred over the other locales.
     pub fn get_hash_table_entry(
         &self,
         filename: &str,
+        locale: Option<u16>,
     ) -> Result<MPQHashTableEntry, MPQParserError> {
-        let hash_a = Self::mpq_string_hash(&self.encryption_table, filename, MPQHashType::HashA)?;
-        let hash_b = Self::mpq_string_hash(&self.encryption_table, filename, MPQHashType::HashB)?;
-        for entry in &self.hash_table_entries {
-            if entry.hash_a == hash_a && entry.hash_b == hash_b {
+        let entries: Vec<&MPQHashTableEntry> = self
+            .probe_hash_table(filename)?
+            .into_iter()
+            .filter(|entry| !entry.is_deleted())
+            .collect();
+        let entry = match locale {
+            Some(locale) => entries.into_iter().find(|entry| entry.locale == locale),
+            None => entries
+                .iter()
+                .find(|entry| entry.locale == 0)
+                .or(entries.first())
+                .copied(),
+        };
+        match entry {
+            Some(entry) => {
+                tracing::debug!("Found filename: {}, as entry: {:?}", filename, entry);
+                Ok(entry.clone())
+            }
+            None => {
+                tracing::debug!(
+                    "Unable to find hash table entry for {} on locale {:?}",
+                    filename,
+                    locale
+                );
+                Err(MPQParserError::HashTableEntryNotFound(filename.to_string()))
+            }
+        }
+    }
+
+    /// Get all the hash table entries corresponding to a given filename.
+    ///
+    /// A file may be stored several times in the archive, for different locales or
+    /// platforms, each of them with its own hash table entry.
+    pub fn all_hash_table_entries(
+        &self,
+        filename: &str,
+    ) -> Result<Vec<MPQHashTableEntry>, MPQParserError> {
+        Ok(self
+            .probe_hash_table(filename)?
+            .into_iter()
+            .cloned()
+            .collect())
+    }
+
+    /// Returns the [`MPQ_INTERNAL_FILES`] present in the hash table.
+    pub fn internal_files(&self) -> Vec<&'static str> {
+        MPQ_INTERNAL_FILES
+            .into_iter()
+            .filter(|filename| match self.all_hash_table_entries(filename) {
+                Ok(entries) => entries
+                    .iter()
+                    .any(|entry| !entry.is_empty() && !entry.is_deleted()),
+                Err(_) => false,
+            })
+            .collect()
+    }
+
+    /// Get the hash table entry corresponding to a given filename for a specific platform.
+    ///
+    /// Like [`MPQ::get_hash_table_entry`], but only entries whose platform matches
+    /// are considered, 0 being the default platform.
+    pub fn get_hash_table_entry_for_platform(
+        &self,
+        filename: &str,
+        platform: u16,
+    ) -> Result<MPQHashTableEntry, MPQParserError> {
+        for entry in self.probe_hash_table(filename)? {
+            if !entry.is_deleted() && entry.platform == platform {
                 tracing::debug!("Found filename: {}, as entry: {:?}", filename, entry);
                 return Ok(entry.clone());
             }
         }
-        tracing::warn!("Unable to find hash table entry for {}", filename);
+        tracing::warn!(
+            "Unable to find hash table entry for {} on platform {}",
+            filename,
+            platform
+        );
         Err(MPQParserError::HashTableEntryNotFound(filename.to_string()))
     }
 
+    /// Get the hash table entry corresponding to a given filename for a specific locale.
+    ///
+    /// Like [`MPQ::get_hash_table_entry`], but only entries whose locale matches
+    /// are considered, 0 being the neutral locale.
+    pub fn get_hash_table_entry_for_locale(
+        &self,
+        filename: &str,
+        locale: u16,
+    ) -> Result<MPQHashTableEntry, MPQParserError> {
+        self.get_hash_table_entry(filename, Some(locale))
+    }
+
+    /// Reads an embedded file inside the MPQ archive in the preferred locale.
+    ///
+    /// When the file is not available in the `preferred_locale`, the neutral
+    /// locale (0) is used, and otherwise any available locale.
+    pub fn read_file_localized(
+        &self,
+        filename: &str,
+        preferred_locale: u16,
+        orig_input: &[u8],
+    ) -> Result<Vec<u8>, MPQParserError> {
+        let hash_entry = match self.get_hash_table_entry_for_locale(filename, preferred_locale) {
+            Ok(val) => val,
+            Err(_) => match self.get_hash_table_entry_for_locale(filename, 0) {
+                Ok(val) => val,
+                Err(_) => self.get_hash_table_entry(filename, None)?,
+            },
+        };
+        tracing::debug!("Reading {} for locale: {}", filename, hash_entry.locale);
+        let (_tail, file_data) = self.read_block_entry(
+            Some(filename),
+            hash_entry.block_table_index,
+            false,
+            orig_input,
+            None,
+        )?;
+        Ok(file_data)
+    }
+
+    /// Get the block table entry a hash table entry points to.
+    pub fn get_block_table_entry(
+        &self,
+        block_table_index: u32,
+    ) -> Result<&MPQBlockTableEntry, MPQParserError> {
+        match self.block_table_entries.get(block_table_index as usize) {
+            Some(block_entry) => Ok(block_entry),
+            None => {
+                tracing::warn!(
+                    "Block table index {} out of range of {} entries",
+                    block_table_index,
+                    self.block_table_entries.len()
+                );
+                Err(MPQParserError::InvalidBlockIndex(block_table_index))
+            }
+        }
+    }
+
+    /// The offset of the data of a block from the start of the input.
+    ///
+    /// The block table only stores the low 32 bits of the offset relative to the
+    /// archive header, large archives store the high bits in the hi-block table,
+    /// or in the file position of the BET table of the Cataclysm formats.
+    pub fn block_offset(&self, block_table_index: u32) -> Result<usize, MPQParserError> {
+        let block_entry = self.get_block_table_entry(block_table_index)?;
+        let offset_high = match (&self.hi_block_table, &self.bet_table) {
+            (Some(hi_block_table), _) => hi_block_table
+                .get(block_table_index as usize)
+                .map_or(0, |offset_high| *offset_high as u64),
+            (None, Some(bet_table)) => bet_table
+                .entries
+                .get(block_table_index as usize)
+                .map_or(0, |entry| entry.file_pos >> 32),
+            (None, None) => 0,
+        };
+        let offset = (offset_high << 32) | block_entry.offset as u64;
+        (offset as usize)
+            .checked_add(self.archive_header.offset)
+            .ok_or(MPQParserError::IncompleteData)
+    }
+
+    /// Get the block table index and entry of a file, when the block exists and
+    /// isn't a deletion marker.
+    fn existing_block_table_entry(&self, filename: &str) -> Option<(u32, &MPQBlockTableEntry)> {
+        let hash_entry = self.get_hash_table_entry(filename, None).ok()?;
+        let block_entry = self
+            .get_block_table_entry(hash_entry.block_table_index)
+            .ok()?;
+        if !block_entry.flags().exists() || block_entry.flags().is_deleted() {
+            return None;
+        }
+        Some((hash_entry.block_table_index, block_entry))
+    }
+
+    /// Whether the archive contains the file, without reading its data.
+    pub fn file_exists(&self, filename: &str) -> bool {
+        self.existing_block_table_entry(filename).is_some()
+    }
+
+    /// The uncompressed size of the file, without reading its data.
+    pub fn file_size(&self, filename: &str) -> Option<u32> {
+        self.existing_block_table_entry(filename)
+            .map(|(_block_table_index, block_entry)| block_entry.size)
+    }
+
+    /// Returns the `(hash_slot, block_index)` of the live hash table entries that
+    /// point beyond the block table, reading them would fail.
+    pub fn validate_hash_block_consistency(&self) -> Vec<(usize, u32)> {
+        self.hash_table_entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| !entry.is_empty() && !entry.is_deleted())
+            .filter(|(_, entry)| entry.block_table_index as usize >= self.block_table_entries.len())
+            .map(|(hash_slot, entry)| (hash_slot, entry.block_table_index))
+            .collect()
+    }
+
+    /// Returns the fraction of the hash table entries that are in use.
+    ///
+    /// Empty and deleted entries are available for new files, an archive close to
+    /// `1.0` may fail to add new files.
+    pub fn hash_table_utilization(&self) -> f64 {
+        if self.hash_table_entries.is_empty() {
+            return 0.0;
+        }
+        let live_entries = self
+            .hash_table_entries
+            .iter()
+            .filter(|entry| !entry.is_empty() && !entry.is_deleted())
+            .count();
+        live_entries as f64 / self.hash_table_entries.len() as f64
+    }
+
+    /// Returns the features needed to read the archive.
+    ///
+    /// The compression types are read from the sectors of every file, except for
+    /// encrypted files, whose sectors can't be decrypted without their filename.
+    pub fn required_capabilities(&self, orig_input: &[u8]) -> Result<Capabilities, MPQParserError> {
+        let mut res = Capabilities::default();
+        for (block_table_index, block_entry) in self.block_table_entries.iter().enumerate() {
+            if !block_entry.flags().exists() || block_entry.archived_size == 0 {
+                continue;
+            }
+            res.implode |= block_entry.flags().is_imploded();
+            if block_entry.flags().is_encrypted() {
+                res.encryption = true;
+                continue;
+            }
+            if !block_entry.flags().is_compressed() {
+                continue;
+            }
+            let offset = self.block_offset(block_table_index as u32)?;
+            let (_tail, file_data) = dbg_dmp(take(block_entry.archived_size), "file_data")(
+                input_at(orig_input, offset)?,
+            )?;
+            if block_entry.flags().is_single_unit() {
+                if block_entry.size > block_entry.archived_size {
+                    let compression_type =
+                        file_data.first().ok_or(MPQParserError::IncompleteData)?;
+                    res.add_compression(*compression_type);
+                }
+                continue;
+            }
+            let positions = self.parse_sector_offset_table(block_entry, file_data, None)?;
+            let sector_size = 512usize << self.archive_header.sector_size_shift;
+            let mut bytes_left = block_entry.size as usize;
+            for pair in positions.windows(2) {
+                if bytes_left == 0 {
+                    // The sector checksums.
+                    break;
+                }
+                let expected_size = bytes_left.min(sector_size);
+                if pair[1] - pair[0] < expected_size {
+                    // An empty sector has no compression type.
+                    let compression_type = file_data[pair[0]..pair[1]]
+                        .first()
+                        .ok_or(MPQParserError::IncompleteData)?;
+                    res.add_compression(*compression_type);
+                }
+                bytes_left -= expected_size;
+            }
+        }
+        res.het_bet_tables = self.archive_header.het_table_pos.unwrap_or_default() != 0
+            || self.archive_header.bet_table_pos.unwrap_or_default() != 0;
+        res.large_archive = self.hi_block_table.is_some()
+            || self.archive_header.archive_size_bytes() > u32::MAX as u64
+            || self
+                .archive_header
+                .extended_file_header
+                .map(|ext| ext.hash_table_offset_high != 0 || ext.block_table_offset_high != 0)
+                .unwrap_or_default();
+        Ok(res)
+    }
+
+    /// Verifies the MD5 digests of the format version 4 header, they cover the header
+    /// itself and each of the tables as stored in the archive, before decryption.
+    /// A digest of zeroes is not set and is skipped, as are the archives of the
+    /// previous format versions, that have no digests.
+    pub fn verify_header_md5(&self, input: &[u8]) -> Result<(), MPQParserError> {
+        let v4_file_header = match self.archive_header.v4_file_header {
+            Some(v4_file_header) => v4_file_header,
+            None => return Ok(()),
+        };
+        let header_offset = self.archive_header.offset as u64;
+        let hi_block_table_pos = self
+            .archive_header
+            .extended_file_header
+            .map(|ext| ext.extended_block_table_offset as u64)
+            .unwrap_or_default();
+        for (name, pos, size, md5) in [
+            (
+                "(header)",
+                0,
+                parser::MPQ_HEADER_MD5_COVERED_SIZE as u64,
+                v4_file_header.md5_mpq_header,
+            ),
+            (
+                "(hash table)",
+                self.archive_header.hash_table_pos(),
+                v4_file_header.hash_table_size_64,
+                v4_file_header.md5_hash_table,
+            ),
+            (
+                "(block table)",
+                self.archive_header.block_table_pos(),
+                v4_file_header.block_table_size_64,
+                v4_file_header.md5_block_table,
+            ),
+            (
+                "(hi-block table)",
+                hi_block_table_pos,
+                v4_file_header.hi_block_table_size_64,
+                v4_file_header.md5_hi_block_table,
+            ),
+            (
+                "(het table)",
+                self.archive_header.het_table_pos.unwrap_or_default(),
+                v4_file_header.het_table_size_64,
+                v4_file_header.md5_het_table,
+            ),
+            (
+                "(bet table)",
+                self.archive_header.bet_table_pos.unwrap_or_default(),
+                v4_file_header.bet_table_size_64,
+                v4_file_header.md5_bet_table,
+            ),
+        ] {
+            if size == 0 || md5 == [0u8; parser::MPQ_MD5_DIGEST_SIZE] {
+                continue;
+            }
+            let range = header_offset
+                .checked_add(pos)
+                .and_then(|start| Some(start..start.checked_add(size)?))
+                .and_then(|range| {
+                    Some(usize::try_from(range.start).ok()?..usize::try_from(range.end).ok()?)
+                });
+            let data = range.and_then(|range| input.get(range)).ok_or(
+                MPQParserError::OffsetOutOfBounds(
+                    header_offset.saturating_add(pos).saturating_add(size),
+                ),
+            )?;
+            if <[u8; 16]>::from(Md5::digest(data)) != md5 {
+                tracing::error!("MD5 mismatch for {}", name);
+                return Err(MPQParserError::Md5Mismatch(name.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// The MD5 of the archive signed by the weak signature, the block of the
+    /// `(signature)` file is zeroed.
+    pub fn weak_signature_md5(&self, input: &[u8]) -> Result<[u8; 16], MPQParserError> {
+        let hash_entry = self.get_hash_table_entry("(signature)", None)?;
+        let block_entry = self.get_block_table_entry(hash_entry.block_table_index)?;
+        let archive_start = self.archive_header.offset;
+        let archive_end = (archive_start as u64)
+            .checked_add(self.archive_header.archive_size_bytes())
+            .ok_or(MPQParserError::OffsetOutOfBounds(u64::MAX))?;
+        let archive = usize::try_from(archive_end)
+            .ok()
+            .and_then(|end| input.get(archive_start..end))
+            .ok_or(MPQParserError::OffsetOutOfBounds(archive_end))?;
+        let signature_start = (block_entry.offset as usize).min(archive.len());
+        let signature_end =
+            (signature_start + block_entry.archived_size as usize).min(archive.len());
+        let mut hasher = Md5::new();
+        hasher.update(&archive[..signature_start]);
+        hasher.update(vec![0u8; signature_end - signature_start]);
+        hasher.update(&archive[signature_end..]);
+        Ok(hasher.finalize().into())
+    }
+
+    /// Verifies the weak signature of the `(signature)` file with the public key of
+    /// Blizzard, see [`signature::BLIZZARD_WEAK_PUBLIC_KEY`].
+    pub fn verify_weak_signature(&self, input: &[u8]) -> Result<bool, MPQParserError> {
+        self.verify_weak_signature_with_key(input, &signature::BLIZZARD_WEAK_PUBLIC_KEY)
+    }
+
+    /// Verifies the weak signature of the `(signature)` file with the big endian
+    /// `modulus` of a public key, returns whether the archive matches the signature.
+    pub fn verify_weak_signature_with_key(
+        &self,
+        input: &[u8],
+        modulus: &[u8],
+    ) -> Result<bool, MPQParserError> {
+        let (_tail, signature_file) = self.read_mpq_file_sector("(signature)", false, input)?;
+        if signature_file.len() < signature::MPQ_WEAK_SIGNATURE_FILE_SIZE {
+            return Err(MPQParserError::InvalidSignatureFile(signature_file.len()));
+        }
+        // The signature is stored in little endian.
+        let mut weak_signature =
+            signature_file[8..signature::MPQ_WEAK_SIGNATURE_FILE_SIZE].to_vec();
+        weak_signature.reverse();
+        let digest = self.weak_signature_md5(input)?;
+        Ok(signature::verify_md5_signature(
+            &weak_signature,
+            &digest,
+            modulus,
+        ))
+    }
+
+    /// Whether the input contains the whole archive, a truncated archive is missing
+    /// part of its tables or file data.
+    pub fn is_complete(&self, orig_input: &[u8]) -> bool {
+        orig_input.len() as u64
+            >= self.archive_header.offset as u64 + self.archive_header.effective_archive_size()
+    }
+
+    /// Registers a decompressor for a compression type, it is used instead of the
+    /// built-in decompression when reading files.
+    pub fn register_decompressor<F>(&mut self, compression_type: u8, decompressor: F)
+    where
+        F: Fn(&[u8]) -> Result<Vec<u8>, MPQParserError> + Send + Sync + 'static,
+    {
+        self.decompressors.register(compression_type, decompressor);
+    }
+
+    /// Decompresses a sector, using the registered decompressors for its compression
+    /// type or each of the combined compressions if any, otherwise [`MPQ::decompress`].
+    pub fn decompress_sector<'a>(&self, input: &'a [u8]) -> MPQResult<&'a [u8], Vec<u8>> {
+        Self::decompress_with_registry(input, &self.decompressors, usize::MAX)
+    }
+
     /// Read the compression type and decompress file data accordingly.
+    ///
+    /// The compression type may combine several compressions, they are undone in
+    /// the order of [`MPQ_DECOMPRESSION_ORDER`], except [`COMPRESSION_LZMA`] which
+    /// can't be combined and requires the `lzma` feature.
+    /// The input must be a whole sector, the decoders consume all of it so the
+    /// returned remaining input is always empty.
     pub fn decompress(input: &[u8]) -> MPQResult<&[u8], Vec<u8>> {
-        let mut data = vec![];
+        Self::decompress_with_registry(input, &DecompressorRegistry::new(), usize::MAX)
+    }
+
+    /// Decompresses a sector, the registered decompressors take precedence for the
+    /// whole compression type and for each of the combined compressions.
+    ///
+    /// The built-in decompressors stop once they produce more than `max_output`
+    /// bytes, the output of the registered ones is checked when they return.
+    fn decompress_with_registry<'a>(
+        input: &'a [u8],
+        decompressors: &DecompressorRegistry,
+        max_output: usize,
+    ) -> MPQResult<&'a [u8], Vec<u8>> {
         let (tail, compression_type) = dbg_dmp(u8, "compression_type")(input)?;
+        let consumed = &tail[tail.len()..];
+        if let Some(decompressor) = decompressors.get(compression_type) {
+            tracing::debug!("Using registered decompressor for {compression_type}");
+            let data = decompressor(tail)?;
+            compression::check_output_limit(data.len(), max_output)?;
+            return Ok((consumed, data));
+        }
         match compression_type {
             COMPRESSION_PLAINTEXT => {
                 tracing::debug!("Plaintext (no compression)");
-                data = tail[..].to_vec()
+                compression::check_output_limit(tail.len(), max_output)?;
+                return Ok((consumed, tail.to_vec()));
+            }
+            #[cfg(feature = "lzma")]
+            COMPRESSION_LZMA => {
+                tracing::debug!("Attempting LZMA decompression",);
+                return Ok((consumed, compression::lzma::decompress(tail, max_output)?));
+            }
+            #[cfg(not(feature = "lzma"))]
+            COMPRESSION_LZMA => {
+                tracing::error!("LZMA decompression requires the lzma feature");
+                return Err(MPQParserError::UnsupportedCompression(compression_type));
             }
+            _ => {}
+        }
+        let known_compressions = MPQ_DECOMPRESSION_ORDER
+            .iter()
+            .fold(0u8, |acc, compression| acc | compression);
+        if compression_type & !known_compressions != 0 {
+            return Err(MPQParserError::UnsupportedCompression(compression_type));
+        }
+        let mut data: Option<Vec<u8>> = None;
+        for compression in MPQ_DECOMPRESSION_ORDER {
+            if compression_type & compression == 0 {
+                continue;
+            }
+            let compressed = data.as_deref().unwrap_or(tail);
+            let decompressed = match decompressors.get(compression) {
+                Some(decompressor) => {
+                    tracing::debug!("Using registered decompressor for {compression}");
+                    decompressor(compressed)?
+                }
+                None => Self::decompress_single(compression, compressed, max_output)?,
+            };
+            compression::check_output_limit(decompressed.len(), max_output)?;
+            data = Some(decompressed);
+        }
+        Ok((consumed, data.unwrap_or_default()))
+    }
+
+    /// Undoes one of the compressions of [`MPQ_DECOMPRESSION_ORDER`], producing
+    /// at most `max_output` bytes.
+    fn decompress_single(
+        compression: u8,
+        input: &[u8],
+        max_output: usize,
+    ) -> Result<Vec<u8>, MPQParserError> {
+        let mut data = vec![];
+        // The streaming decoders read one byte past the limit to detect it.
+        let stream_limit = (max_output as u64).saturating_add(1);
+        match compression {
             COMPRESSION_ZLIB => {
                 tracing::debug!("Attempting ZLIB compression",);
-                let mut d = zlib::Decoder::new(std::io::BufReader::new(tail));
+                let d = zlib::Decoder::new(std::io::BufReader::new(input));
 
-                let _ = d.read_to_end(&mut data)?;
+                let _ = d.take(stream_limit).read_to_end(&mut data)?;
+            }
+            COMPRESSION_PKWARE => {
+                tracing::debug!("Attempting PKWARE DCL explode",);
+                data = compression::pkware::explode(input, max_output)?;
             }
             COMPRESSION_BZ2 => {
                 tracing::debug!("Attempting BZ2 compression",);
-                let mut decompressor = bzip2_rs::DecoderReader::new(tail);
-                std::io::copy(&mut decompressor, &mut data)?;
+                let decompressor = bzip2_rs::DecoderReader::new(input);
+                std::io::copy(&mut decompressor.take(stream_limit), &mut data)?;
+            }
+            COMPRESSION_HUFFMAN => {
+                tracing::debug!("Attempting Huffman decompression",);
+                data = compression::huffman::decompress(input, max_output)?;
+            }
+            COMPRESSION_ADPCM_MONO => {
+                tracing::debug!("Attempting IMA ADPCM mono decompression",);
+                data = compression::adpcm::decompress(input, 1)?;
             }
-            unknown_version => {
-                return MPQResult::Err(MPQParserError::UnsupportedCompression(unknown_version));
+            COMPRESSION_ADPCM_STEREO => {
+                tracing::debug!("Attempting IMA ADPCM stereo decompression",);
+                data = compression::adpcm::decompress(input, 2)?;
+            }
+            COMPRESSION_SPARSE => {
+                tracing::debug!("Attempting sparse decompression",);
+                data = compression::sparse::decompress_sparse(input, max_output)?;
+            }
+            unsupported => {
+                return Err(MPQParserError::UnsupportedCompression(unsupported));
             }
         };
-
-        Ok((tail, data))
+        Ok(data)
     }
 
     /// Reads an embedded file inside the MPQ archive.
-    #[tracing::instrument(level = "debug", skip(self, orig_input))]
     pub fn read_mpq_file_sector<'a>(
         &'a self,
         filename: &str,
         force_decompress: bool,
         orig_input: &'a [u8],
     ) -> MPQResult<&'a [u8], Vec<u8>> {
-        let mut res = vec![];
-        let hash_entry = self.get_hash_table_entry(filename)?;
-        let block_entry = self.block_table_entries[hash_entry.block_table_index as usize].clone();
+        self.read_mpq_file_sector_with_budget(filename, force_decompress, orig_input, None)
+    }
+
+    /// Reads an embedded file inside the MPQ archive, aborting with
+    /// [`MPQParserError::BudgetExceeded`] when the file would exceed the budget.
+    #[tracing::instrument(level = "debug", skip(self, orig_input))]
+    pub fn read_mpq_file_sector_with_budget<'a>(
+        &'a self,
+        filename: &str,
+        force_decompress: bool,
+        orig_input: &'a [u8],
+        budget: Option<ExtractionBudget>,
+    ) -> MPQResult<&'a [u8], Vec<u8>> {
+        let hash_entry = self.get_hash_table_entry(filename, None)?;
+        self.read_block_entry(
+            Some(filename),
+            hash_entry.block_table_index,
+            force_decompress,
+            orig_input,
+            budget,
+        )
+    }
+
+    /// Reads the file contained in a block of the MPQ archive, without resolving
+    /// its name, i.e. for archives without a `(listfile)`.
+    ///
+    /// Encrypted files can't be read this way, their key is derived from their name.
+    pub fn read_file_by_block_index(
+        &self,
+        block_table_index: usize,
+        orig_input: &[u8],
+    ) -> Result<Vec<u8>, MPQParserError> {
+        let (_tail, file_data) =
+            self.read_block_entry(None, block_table_index as u32, false, orig_input, None)?;
+        Ok(file_data)
+    }
+
+    /// Reads the file contained in a block of the MPQ archive.
+    fn read_block_entry<'a>(
+        &'a self,
+        filename: Option<&str>,
+        block_table_index: u32,
+        force_decompress: bool,
+        orig_input: &'a [u8],
+        budget: Option<ExtractionBudget>,
+    ) -> MPQResult<&'a [u8], Vec<u8>> {
+        let block_entry = self.get_block_table_entry(block_table_index)?;
         tracing::debug!("block_entry {:?}", block_entry);
+        if !Self::block_has_data(block_entry, budget)? {
+            return Ok((orig_input, vec![]));
+        }
         // Read the block
-        if block_entry.flags & MPQ_FILE_EXISTS == 0 {
+        let offset = self.block_offset(block_table_index)?;
+        let (tail, file_data) =
+            dbg_dmp(take(block_entry.archived_size), "file_data")(input_at(orig_input, offset)?)?;
+        let file_data =
+            self.decode_block_data(filename, block_entry, force_decompress, file_data, budget)?;
+        Ok((tail, file_data))
+    }
+
+    /// Whether the block has data to read, deleted and empty files have none.
+    /// Fails when the file can't fit the budget.
+    fn block_has_data(
+        block_entry: &MPQBlockTableEntry,
+        budget: Option<ExtractionBudget>,
+    ) -> Result<bool, MPQParserError> {
+        if !block_entry.flags().exists() {
             tracing::debug!("file is marked as deleted. Returning empty content");
-            return Ok((orig_input, res));
+            return Ok(false);
         }
         if block_entry.archived_size == 0 {
+            if block_entry.size != 0 {
+                tracing::error!(
+                    "File of {} bytes has no data in the archive",
+                    block_entry.size
+                );
+                return Err(MPQParserError::SizeMismatch);
+            }
             tracing::debug!("File is zero size. Returning empty content");
-            return Ok((orig_input, res));
+            return Ok(false);
         }
-        let offset = block_entry.offset as usize + self.archive_header.offset;
-        let (tail, file_data) =
-            dbg_dmp(take(block_entry.archived_size), "file_data")(&orig_input[offset..])?;
+        if let Some(budget) = budget {
+            if block_entry.size as usize > budget.max_output_bytes {
+                tracing::warn!(
+                    "File size {} exceeds budget of {} bytes",
+                    block_entry.size,
+                    budget.max_output_bytes
+                );
+                return Err(MPQParserError::BudgetExceeded);
+            }
+        }
+        Ok(true)
+    }
 
+    /// Decrypts and decompresses the data of a block, as stored in the archive.
+    fn decode_block_data(
+        &self,
+        filename: Option<&str>,
+        block_entry: &MPQBlockTableEntry,
+        force_decompress: bool,
+        file_data: &[u8],
+        budget: Option<ExtractionBudget>,
+    ) -> Result<Vec<u8>, MPQParserError> {
         tracing::debug!("Block table data: {}", parser::peek_hex(file_data));
-        if block_entry.flags & MPQ_FILE_ENCRYPTED != 0 {
-            return MPQResult::Err(MPQParserError::UnsupportedEncryptionType);
-        }
-        if block_entry.flags & MPQ_FILE_SINGLE_UNIT != 0 {
+        let file_key = self.block_file_key(block_entry, filename)?;
+        let file_data = if block_entry.flags().is_single_unit() {
             tracing::debug!("File sector contains a single unit",);
             // Single unit files only need to be decompressed, but
             // compression only happens when at least one byte is gained.
-            if block_entry.flags & MPQ_FILE_COMPRESS != 0
-                && (force_decompress || block_entry.size > block_entry.archived_size)
-            {
-                tracing::debug!("File needs to be decompressed",);
-                let (_tail, decompressed_data) = Self::decompress(file_data)?;
-                return Ok((tail, decompressed_data));
-            }
-            tracing::debug!("File does not needs to be decompressed",);
+            self.read_sector(
+                block_entry.flags(),
+                file_data,
+                file_key,
+                block_entry.size as usize,
+                force_decompress,
+                budget.map_or(usize::MAX, |budget| budget.max_output_bytes),
+            )?
         } else {
-            tracing::debug!("File does not need to be decompressed",);
-            // File consists of many sectors. They all need to be
-            // decompressed separately and united.
-            let sector_size = 512 << self.archive_header.sector_size_shift;
-            let mut sectors =
-                (block_entry.size as f32 / sector_size as f32).floor() as usize + 1usize;
-            tracing::debug!("Total sectors: {sectors}");
-            let crc = if block_entry.flags & MPQ_FILE_SECTOR_CRC != 0 {
-                sectors += 1;
-                true
-            } else {
-                false
-            };
-            let mut positions: Vec<usize> = vec![];
-            let mut position_file_index = &file_data[..4 * (sectors + 1)];
-            for _ in 0..sectors + 1 {
-                // Note: MPyQ format for this is a list of '<I'
-                // as long as there are sectors + 1
-                // `'<%dI' % (sectors + 1)` (Not to confuse the `d` with
-                // double, it's for the `%` format operator.
-                let (new_pos_idx, position) =
-                    dbg_dmp(u32(LITTLE_ENDIAN), "positions")(position_file_index)?;
-                positions.push(position as usize);
-                position_file_index = new_pos_idx;
-            }
-            let mut sector_bytes_left = block_entry.size as usize;
-            let mut total_sectors = positions.len() - 1;
-            if crc {
-                total_sectors -= 1;
-            }
-
-            for i in 0..total_sectors {
-                let mut sector = file_data[positions[i]..positions[i + 1]].to_vec();
-                if block_entry.flags & MPQ_FILE_COMPRESS != 0
-                    && (force_decompress || sector_bytes_left > sector.len())
-                {
-                    let (_tail, mut decompressed_sector) =
-                        Self::decompress(&file_data[positions[i]..positions[i + 1]])?;
-                    res.append(&mut decompressed_sector);
-                } else {
-                    res.append(&mut sector);
-                }
+            self.read_sectors(block_entry, file_data, file_key, force_decompress, budget)?
+        };
+        Self::check_output_budget(&budget, file_data.len())?;
+        Ok(file_data)
+    }
 
-                sector_bytes_left -= sector.len();
-            }
-            return Ok((tail, res));
+    /// Reads an embedded file by seeking the reader to its block, only the block of
+    /// the file is read, see [`parser::parse_reader`] to parse the tables this way.
+    pub fn read_file_from_reader<R: Read + Seek>(
+        &self,
+        filename: &str,
+        reader: &mut R,
+    ) -> Result<Vec<u8>, MPQParserError> {
+        let hash_entry = self.get_hash_table_entry(filename, None)?;
+        let block_entry = self.get_block_table_entry(hash_entry.block_table_index)?;
+        if !Self::block_has_data(block_entry, None)? {
+            return Ok(vec![]);
         }
-        Ok((tail, file_data.to_vec()))
+        reader.seek(SeekFrom::Start(
+            self.block_offset(hash_entry.block_table_index)? as u64,
+        ))?;
+        // The block entry is untrusted, the buffer only grows with the data read.
+        let mut file_data = vec![];
+        reader
+            .take(block_entry.archived_size as u64)
+            .read_to_end(&mut file_data)?;
+        if file_data.len() != block_entry.archived_size as usize {
+            tracing::error!(
+                "Block of {} bytes is beyond the end of the reader",
+                block_entry.archived_size
+            );
+            return Err(MPQParserError::IncompleteData);
+        }
+        self.decode_block_data(Some(filename), block_entry, false, &file_data, None)
     }
 
-    /// Decrypt hash or block table or a sector.
-    ///
-    /// `_decrypt` on MPyQ
-    #[allow(clippy::precedence)]
-    pub fn mpq_data_decrypt<'a>(
-        encryption_table: &'a HashMap<u32, u32>,
-        data: &'a [u8],
-        key: u32,
-    ) -> IResult<&'a [u8], Vec<u8>> {
-        let mut seed1 = key as i64;
-        let mut seed2 = 0xEEEEEEEEi64;
+    /// Reads a file that consists of many sectors, listed by the sector offset
+    /// table at the start of the block. They all need to be decompressed
+    /// separately and united.
+    fn read_sectors(
+        &self,
+        block_entry: &MPQBlockTableEntry,
+        file_data: &[u8],
+        file_key: Option<u32>,
+        force_decompress: bool,
+        budget: Option<ExtractionBudget>,
+    ) -> Result<Vec<u8>, MPQParserError> {
         let mut res = vec![];
+        let positions = self.parse_sector_offset_table(block_entry, file_data, file_key)?;
+        let crc = block_entry.flags().has_sector_crc();
+        let sector_size = 512usize << self.archive_header.sector_size_shift;
+        let mut total_sectors = positions.len() - 1;
+        if crc {
+            total_sectors -= 1;
+        }
+        if let Some(budget) = budget {
+            if total_sectors > budget.max_sectors {
+                tracing::warn!(
+                    "File sectors {total_sectors} exceed budget of {} sectors",
+                    budget.max_sectors
+                );
+                return Err(MPQParserError::BudgetExceeded);
+            }
+        }
 
-        for i in 0..(data.len() as f32 / 4f32).floor() as usize {
-            let encryption_table_value =
-                match encryption_table.get(&(0x400 + (seed1 as u32 & 0xFF))) {
-                    Some(val) => *val as i64,
-                    None => {
-                        tracing::error!(
-                            "Encryption table value not found for: {}",
-                            (0x400 + (seed1 & 0xFF) as i32)
-                        );
-                        continue;
-                    }
-                };
-            seed2 += encryption_table_value;
-            seed2 &= 0xFFFFFFFFi64;
-            let (_tail, value) =
-                dbg_dmp(i32(LITTLE_ENDIAN), "encrypted_value")(&data[i * 4..i * 4 + 4])?;
-            let mut value = value as i64;
-            value = (value ^ (seed1 + seed2)) & 0xFFFFFFFFi64;
-
-            seed1 = ((!seed1 << 0x15) + 0x11111111) | (seed1 >> 0x0B);
-            seed1 &= 0xFFFFFFFF;
-            seed2 = value + seed2 + (seed2 << 5) + 3 & 0xFFFFFFFFi64;
-            let mut le_packed_value = (value as i32).to_le_bytes().to_vec();
+        for i in 0..total_sectors {
+            let sector = &file_data[positions[i]..positions[i + 1]];
+            let expected_size = (block_entry.size as usize)
+                .saturating_sub(res.len())
+                .min(sector_size);
+            let sector_key = file_key.map(|key| key.wrapping_add(i as u32));
+            let max_output = budget.map_or(usize::MAX, |budget| {
+                budget.max_output_bytes.saturating_sub(res.len())
+            });
+            let mut sector = self.read_sector(
+                block_entry.flags(),
+                sector,
+                sector_key,
+                expected_size,
+                force_decompress,
+                max_output,
+            )?;
+            res.append(&mut sector);
+            Self::check_output_budget(&budget, res.len())?;
+        }
+        Ok(res)
+    }
 
-            // pack in little endian
-            res.append(&mut le_packed_value);
+    /// Decompresses a sector of a file according to its block flags, sectors stored
+    /// as is are returned unchanged.
+    ///
+    /// The sectors of imploded files, see [`MPQ_FILE_IMPLODE`], have no compression
+    /// type byte, the sectors of compressed files, see [`MPQ_FILE_COMPRESS`], do.
+    /// The sectors of encrypted files are decrypted with their `sector_key` first.
+    /// Decompression stops once the sector grows beyond `max_output` bytes.
+    fn read_sector(
+        &self,
+        flags: MPQBlockFlags,
+        sector: &[u8],
+        sector_key: Option<u32>,
+        expected_size: usize,
+        force_decompress: bool,
+        max_output: usize,
+    ) -> Result<Vec<u8>, MPQParserError> {
+        let decrypted_sector;
+        let sector = match sector_key {
+            Some(key) => {
+                decrypted_sector = self.decrypt_sector(sector, key)?;
+                &decrypted_sector[..]
+            }
+            None => sector,
+        };
+        if flags.is_imploded() && (force_decompress || sector.len() < expected_size) {
+            tracing::debug!("Sector needs to be exploded");
+            return compression::pkware::explode(sector, max_output);
         }
+        if flags.is_compressed()
+            && (force_decompress || Self::has_compression_prefix(sector, expected_size))
+        {
+            tracing::debug!("Sector needs to be decompressed");
+            let (_tail, decompressed_sector) =
+                Self::decompress_with_registry(sector, &self.decompressors, max_output)?;
+            return Ok(decompressed_sector);
+        }
+        tracing::debug!("Sector does not need to be decompressed");
+        Ok(sector.to_vec())
+    }
 
-        Ok((data, res))
+    /// Whether a sector of a compressed file starts with the compression type byte.
+    ///
+    /// Sectors are only compressed when at least one byte is gained, otherwise they
+    /// are stored as is, so only a sector of exactly its expected size is raw data.
+    /// Some writers store the sectors that gained nothing with a prefix anyway,
+    /// usually [`COMPRESSION_PLAINTEXT`], making them bigger than their data.
+    /// The decision is made per sector, as the sizes of the whole file can't tell
+    /// which of its sectors are compressed.
+    fn has_compression_prefix(sector: &[u8], expected_size: usize) -> bool {
+        sector.len() != expected_size
     }
 
-    /// Returns the list of filenames and their respective size as contained in the MPQ archive.
-    pub fn get_files(&self, orig_input: &[u8]) -> Result<Vec<(String, usize)>, MPQParserError> {
-        let mut res: Vec<(String, usize)> = vec![];
-        let files: Vec<String> = match self.read_mpq_file_sector("(listfile)", false, orig_input) {
-            Ok((_tail, file_buffer)) => {
-                tracing::debug!(
-                    "Successfully read '(listfile)' sector: {:?}",
-                    parser::peek_hex(&file_buffer)
+    /// Reads the sector offset table of a file split in sectors.
+    ///
+    /// The table contains the offset of each sector relative to the start of the
+    /// block, followed by the offset of the end of the last sector and, when the
+    /// file has [`MPQ_FILE_SECTOR_CRC`], the offset of the end of the checksums.
+    /// The table of an encrypted file is encrypted with the file key minus one,
+    /// so the `file_key` must be provided for files with [`MPQ_FILE_ENCRYPTED`].
+    /// Some protected archives compress the table, it is then stored as the offset
+    /// of the first sector followed by the compressed table, and is detected by
+    /// the first sector starting before the end of an uncompressed table.
+    pub fn sector_offset_table(
+        &self,
+        block_table_index: u32,
+        file_key: Option<u32>,
+        orig_input: &[u8],
+    ) -> Result<Vec<usize>, MPQParserError> {
+        let block_entry = self.get_block_table_entry(block_table_index)?;
+        let offset = self.block_offset(block_table_index)?;
+        let (_tail, file_data) =
+            dbg_dmp(take(block_entry.archived_size), "file_data")(input_at(orig_input, offset)?)?;
+        self.parse_sector_offset_table(block_entry, file_data, file_key)
+    }
+
+    /// Parses the sector offset table at the start of the block data.
+    fn parse_sector_offset_table(
+        &self,
+        block_entry: &MPQBlockTableEntry,
+        file_data: &[u8],
+        file_key: Option<u32>,
+    ) -> Result<Vec<usize>, MPQParserError> {
+        let sector_size = 512usize << self.archive_header.sector_size_shift;
+        let mut sectors = (block_entry.size as usize).div_ceil(sector_size);
+        tracing::debug!("Total sectors: {sectors}");
+        if block_entry.flags().has_sector_crc() {
+            sectors += 1;
+        }
+        // Note: MPyQ format for this is a list of '<I'
+        // as long as there are sectors + 1
+        // `'<%dI' % (sectors + 1)` (Not to confuse the `d` with
+        // double, it's for the `%` format operator.
+        let table_size = 4 * (sectors + 1);
+        let decrypt = |table_data: &[u8]| match block_entry.flags().is_encrypted() {
+            true => {
+                let file_key = file_key.ok_or(MPQParserError::UnsupportedEncryptionType)?;
+                let (unaligned_tail, mut decrypted_table_data) = Self::mpq_data_decrypt(
+                    &self.encryption_table,
+                    table_data,
+                    file_key.wrapping_sub(1),
+                )?;
+                decrypted_table_data.extend_from_slice(unaligned_tail);
+                Ok::<_, MPQParserError>(decrypted_table_data)
+            }
+            false => Ok(table_data.to_vec()),
+        };
+        // The first offset is the end of the table, protected archives compress the
+        // table, which is then smaller than the offsets it contains.
+        let (_tail, first_offset) = dbg_dmp(take(4usize), "sector_offset_table")(file_data)?;
+        let first_offset = decrypt(first_offset)?;
+        let table_end = u32::from_le_bytes([
+            first_offset[0],
+            first_offset[1],
+            first_offset[2],
+            first_offset[3],
+        ]) as usize;
+        let compressed = table_end > 4 && table_end < table_size;
+        let table_data = if compressed {
+            tracing::debug!("Compressed sector offset table of {table_end} bytes");
+            let (_tail, table_data) =
+                dbg_dmp(take(table_end), "compressed_sector_offset_table")(file_data)?;
+            let table_data = decrypt(table_data)?;
+            match self.decompress_sector(&table_data[4..]) {
+                Ok((_tail, table_data)) => table_data,
+                Err(err) => {
+                    tracing::error!("Unable to decompress the sector offset table: {err}");
+                    return Err(MPQParserError::InvalidSectorOffsetTable);
+                }
+            }
+        } else {
+            let (_tail, table_data) = dbg_dmp(take(table_size), "sector_offset_table")(file_data)?;
+            decrypt(table_data)?
+        };
+        let (_tail, positions) =
+            count(dbg_dmp(u32(LITTLE_ENDIAN), "positions"), sectors + 1)(&table_data[..])?;
+        let positions: Vec<usize> = positions.into_iter().map(|x| x as usize).collect();
+        // The first sector starts right after the table, a mismatch means the
+        // table was not decrypted correctly or is corrupt.
+        let first_sector = if compressed { table_end } else { table_size };
+        if positions[0] != first_sector
+            || positions.windows(2).any(|pair| pair[0] > pair[1])
+            || positions[sectors] > file_data.len()
+        {
+            tracing::error!("Invalid sector offset table: {:?}", positions);
+            return Err(MPQParserError::InvalidSectorOffsetTable);
+        }
+        Ok(positions)
+    }
+
+    /// Returns the bytes of a sector of a file as stored in the archive, including
+    /// the compression type prefix of compressed sectors.
+    ///
+    /// Single unit files are considered to have only one sector. The bytes of
+    /// encrypted files are returned still encrypted.
+    pub fn raw_sector_bytes<'a>(
+        &self,
+        filename: &str,
+        sector_index: usize,
+        orig_input: &'a [u8],
+    ) -> Result<&'a [u8], MPQParserError> {
+        let hash_entry = self.get_hash_table_entry(filename, None)?;
+        let block_entry = self.get_block_table_entry(hash_entry.block_table_index)?;
+        let offset = self.block_offset(hash_entry.block_table_index)?;
+        let (_tail, file_data) =
+            dbg_dmp(take(block_entry.archived_size), "file_data")(input_at(orig_input, offset)?)?;
+        if block_entry.flags().is_single_unit() {
+            if sector_index != 0 {
+                return Err(MPQParserError::SectorIndexOutOfRange(sector_index));
+            }
+            return Ok(file_data);
+        }
+        let file_key = self.block_file_key(block_entry, Some(filename))?;
+        let positions = self.parse_sector_offset_table(block_entry, file_data, file_key)?;
+        let mut total_sectors = positions.len() - 1;
+        if block_entry.flags().has_sector_crc() {
+            total_sectors -= 1;
+        }
+        if sector_index >= total_sectors {
+            return Err(MPQParserError::SectorIndexOutOfRange(sector_index));
+        }
+        Ok(&file_data[positions[sector_index]..positions[sector_index + 1]])
+    }
+
+    /// Returns the data of a file exactly as stored in the archive, without
+    /// decompressing or decrypting it.
+    ///
+    /// Returns `None` for missing, deleted or empty files, and when the block
+    /// lies beyond the input.
+    pub fn raw_file_data<'a>(&self, filename: &str, orig_input: &'a [u8]) -> Option<&'a [u8]> {
+        let (block_table_index, block_entry) = self.existing_block_table_entry(filename)?;
+        if block_entry.archived_size == 0 {
+            return None;
+        }
+        let offset = self.block_offset(block_table_index).ok()?;
+        let end = offset.checked_add(block_entry.archived_size as usize)?;
+        orig_input.get(offset..end)
+    }
+
+    /// The key used to encrypt a file, the hash of the filename without its path.
+    fn file_key(&self, filename: &str) -> Result<u32, MPQParserError> {
+        let basename = filename.rsplit('\\').next().unwrap_or(filename);
+        Self::mpq_string_hash(&self.encryption_table, basename, MPQHashType::Table)
+    }
+
+    /// The key used to encrypt the file of a block. Files with [`MPQ_FILE_FIX_KEY`]
+    /// have the key adjusted by the block offset and the file size.
+    pub fn compute_file_key(
+        &self,
+        filename: &str,
+        block_entry: &MPQBlockTableEntry,
+    ) -> Result<u32, MPQParserError> {
+        let file_key = self.file_key(filename)?;
+        if !block_entry.flags().fix_key() {
+            return Ok(file_key);
+        }
+        Ok(file_key.wrapping_add(block_entry.offset) ^ block_entry.size)
+    }
+
+    /// The key used to encrypt the file of a block, or `None` when the block has
+    /// no [`MPQ_FILE_ENCRYPTED`] flag. The key is derived from the filename, so
+    /// encrypted blocks of unknown files can't be decrypted.
+    fn block_file_key(
+        &self,
+        block_entry: &MPQBlockTableEntry,
+        filename: Option<&str>,
+    ) -> Result<Option<u32>, MPQParserError> {
+        if !block_entry.flags().is_encrypted() {
+            return Ok(None);
+        }
+        match filename {
+            Some(filename) => Ok(Some(self.compute_file_key(filename, block_entry)?)),
+            None => {
+                tracing::warn!("Unable to derive the key of an encrypted block without its name");
+                Err(MPQParserError::UnsupportedEncryptionType)
+            }
+        }
+    }
+
+    /// Decrypts a sector with the key, the trailing bytes that do not fill a 4
+    /// byte word are stored unencrypted.
+    fn decrypt_sector(&self, sector: &[u8], key: u32) -> Result<Vec<u8>, MPQParserError> {
+        let (tail, mut res) = Self::mpq_data_decrypt(&self.encryption_table, sector, key)?;
+        res.extend_from_slice(tail);
+        Ok(res)
+    }
+
+    /// Verifies the bytes produced so far are within the budget, the declared
+    /// file size may not match the actual decompressed size.
+    fn check_output_budget(
+        budget: &Option<ExtractionBudget>,
+        output_bytes: usize,
+    ) -> Result<(), MPQParserError> {
+        match budget {
+            Some(budget) if output_bytes > budget.max_output_bytes => {
+                tracing::warn!(
+                    "Output of {output_bytes} bytes exceeds budget of {} bytes",
+                    budget.max_output_bytes
                 );
-                match std::str::from_utf8(&file_buffer) {
-                    Ok(val) => val.lines().map(|x| x.to_string()).collect(),
+                Err(MPQParserError::BudgetExceeded)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Decrypt hash or block table or a sector.
+    ///
+    /// Only whole 4 byte words are decrypted, the trailing bytes that do not fill a
+    /// word are left unconsumed and returned as the remaining input.
+    ///
+    /// `_decrypt` on MPyQ
+    #[allow(clippy::precedence)]
+    pub fn mpq_data_decrypt<'a>(
+        encryption_table: &'a HashMap<u32, u32>,
+        data: &'a [u8],
+        key: u32,
+    ) -> IResult<&'a [u8], Vec<u8>> {
+        let mut seed1 = key as i64;
+        let mut seed2 = 0xEEEEEEEEi64;
+        let mut res = vec![];
+
+        let words = data.len() / 4;
+        for i in 0..words {
+            let encryption_table_value =
+                match encryption_table.get(&(0x400 + (seed1 as u32 & 0xFF))) {
+                    Some(val) => *val as i64,
+                    None => {
+                        tracing::error!(
+                            "Encryption table value not found for: {}",
+                            (0x400 + (seed1 & 0xFF) as i32)
+                        );
+                        continue;
+                    }
+                };
+            seed2 += encryption_table_value;
+            seed2 &= 0xFFFFFFFFi64;
+            let (_tail, value) =
+                dbg_dmp(i32(LITTLE_ENDIAN), "encrypted_value")(&data[i * 4..i * 4 + 4])?;
+            let mut value = value as i64;
+            value = (value ^ (seed1 + seed2)) & 0xFFFFFFFFi64;
+
+            seed1 = ((!seed1 << 0x15) + 0x11111111) | (seed1 >> 0x0B);
+            seed1 &= 0xFFFFFFFF;
+            seed2 = value + seed2 + (seed2 << 5) + 3 & 0xFFFFFFFFi64;
+            let mut le_packed_value = (value as i32).to_le_bytes().to_vec();
+
+            // pack in little endian
+            res.append(&mut le_packed_value);
+        }
+
+        Ok((&data[words * 4..], res))
+    }
+
+    /// Encrypt hash or block table or a sector.
+    ///
+    /// The inverse of [`MPQ::mpq_data_decrypt`], trailing bytes that do not
+    /// fill a 4 byte word are returned as the remaining input.
+    /// Fails when the encryption table lacks a value, instead of returning
+    /// corrupt data.
+    pub fn mpq_data_encrypt<'a>(
+        encryption_table: &'a HashMap<u32, u32>,
+        data: &'a [u8],
+        key: u32,
+    ) -> MPQResult<&'a [u8], Vec<u8>> {
+        let mut seed1 = key;
+        let mut seed2 = 0xEEEEEEEEu32;
+        let mut res = vec![];
+
+        let words = data.len() / 4;
+        for i in 0..words {
+            let encryption_table_value = match encryption_table.get(&(0x400 + (seed1 & 0xFF))) {
+                Some(val) => *val,
+                None => {
+                    tracing::error!(
+                        "Encryption table value not found for: {}",
+                        0x400 + (seed1 & 0xFF)
+                    );
+                    return Err(MPQParserError::EncryptionTableIndexNotFound);
+                }
+            };
+            seed2 = seed2.wrapping_add(encryption_table_value);
+            let (_tail, value) =
+                dbg_dmp(u32(LITTLE_ENDIAN), "decrypted_value")(&data[i * 4..i * 4 + 4])?;
+            let encrypted_value = value ^ seed1.wrapping_add(seed2);
+
+            seed1 = ((!seed1 << 0x15).wrapping_add(0x11111111)) | (seed1 >> 0x0B);
+            seed2 = value
+                .wrapping_add(seed2)
+                .wrapping_add(seed2 << 5)
+                .wrapping_add(3);
+
+            // pack in little endian
+            res.extend_from_slice(&encrypted_value.to_le_bytes());
+        }
+
+        Ok((&data[words * 4..], res))
+    }
+
+    /// Replaces the contents of a file, returning the modified archive.
+    ///
+    /// The new contents are encoded with the flags of the file block. When they fit
+    /// in the block they are written in place, otherwise they are appended at the
+    /// end of the archive. The space no longer used by the file is zeroed and
+    /// marked free by a block table entry without flags, the block table is then
+    /// moved to the end of the archive. The `(attributes)` are not updated.
+    ///
+    /// Only the original and Burning Crusade formats without hi-block table are
+    /// supported, the 64-bit sizes, the MD5 digests and the HET and BET tables of
+    /// the later formats are not rewritten.
+    pub fn replace_file(
+        &mut self,
+        filename: &str,
+        new_data: &[u8],
+        orig_input: &[u8],
+    ) -> Result<Vec<u8>, MPQParserError> {
+        if !matches!(
+            self.archive_header.version(),
+            MPQVersion::V1Original | MPQVersion::V2BurningCrusade
+        ) || self.hi_block_table.is_some()
+            || self.bet_table.is_some()
+        {
+            tracing::warn!(
+                "Unable to replace files of {:?} archives",
+                self.archive_header.version()
+            );
+            return Err(MPQParserError::UnsupportedArchiveFormat(
+                self.archive_header.format_version,
+            ));
+        }
+        let hash_entry = self.get_hash_table_entry(filename, None)?;
+        let block_table_index = hash_entry.block_table_index as usize;
+        let block_entry = self.get_block_table_entry(hash_entry.block_table_index)?;
+        if block_entry.flags & (MPQ_FILE_IMPLODE | MPQ_FILE_FIX_KEY | MPQ_FILE_SECTOR_CRC) != 0 {
+            tracing::warn!(
+                "Unable to encode {} with flags {:#010X}",
+                filename,
+                block_entry.flags
+            );
+            return Err(MPQParserError::UnsupportedFileFlags(block_entry.flags));
+        }
+        let builder = MPQBuilder::new();
+        let file_data = builder.encode_file(
+            &MPQBuilderFile {
+                flags: block_entry.flags,
+                ..MPQBuilderFile::new(filename, new_data)
+            },
+            self.archive_header.sector_size_shift,
+        )?;
+        let mut res = orig_input.to_vec();
+        let archive_start = self.archive_header.offset;
+        let old_offset = self.block_offset(hash_entry.block_table_index)?;
+        let old_end = old_offset + block_entry.archived_size as usize;
+        if old_end > res.len() {
+            return Err(MPQParserError::IncompleteData);
+        }
+        let (new_offset, free_range) = if file_data.len() <= block_entry.archived_size as usize {
+            (old_offset, old_offset + file_data.len()..old_end)
+        } else {
+            tracing::debug!("Appending {} at the end of the archive", filename);
+            (res.len(), old_offset..old_end)
+        };
+        let new_end = new_offset + file_data.len();
+        // The block table follows the data, all of it must fit the 32-bit offsets.
+        let block_table_entries =
+            self.block_table_entries.len() + usize::from(!free_range.is_empty());
+        let block_table_end = res.len().max(new_end) + 16 * block_table_entries;
+        if block_table_end - archive_start > u32::MAX as usize {
+            return Err(MPQParserError::OffsetOutOfBounds(block_table_end as u64));
+        }
+        res[free_range.clone()].fill(0);
+        if new_end > res.len() {
+            res.resize(new_end, 0);
+        }
+        res[new_offset..new_end].copy_from_slice(&file_data);
+        let block_entry = &mut self.block_table_entries[block_table_index];
+        block_entry.offset = (new_offset - archive_start) as u32;
+        block_entry.archived_size = file_data.len() as u32;
+        block_entry.size = new_data.len() as u32;
+        if !free_range.is_empty() {
+            self.block_table_entries.push(MPQBlockTableEntry::new(
+                (free_range.start - archive_start) as u32,
+                free_range.len() as u32,
+                0,
+                0,
+            ));
+        }
+
+        let mut block_table_data = vec![];
+        for entry in &self.block_table_entries {
+            block_table_data.extend_from_slice(&entry.to_bytes());
+        }
+        let block_table_key =
+            Self::mpq_string_hash(&self.encryption_table, "(block table)", MPQHashType::Table)?;
+        let (_, encrypted_block_table_data) =
+            Self::mpq_data_encrypt(&self.encryption_table, &block_table_data, block_table_key)?;
+        let block_table_offset = res.len();
+        res.extend_from_slice(&encrypted_block_table_data);
+        self.archive_header.block_table_offset = (block_table_offset - archive_start) as u32;
+        self.archive_header.block_table_entries = self.block_table_entries.len() as u32;
+        self.archive_header.archive_size = (res.len() - archive_start) as u32;
+        for (field_offset, value) in [
+            (0x08, self.archive_header.archive_size),
+            (0x14, self.archive_header.block_table_offset),
+            (0x1c, self.archive_header.block_table_entries),
+        ] {
+            res[archive_start + field_offset..archive_start + field_offset + 4]
+                .copy_from_slice(&value.to_le_bytes());
+        }
+        if let Some(extended_file_header) = self.archive_header.extended_file_header.as_mut() {
+            extended_file_header.block_table_offset_high = 0;
+            res[archive_start + 0x2a..archive_start + 0x2c].copy_from_slice(&0u16.to_le_bytes());
+        }
+        Ok(res)
+    }
+
+    /// Reads an embedded file inside the MPQ archive, the contents of plaintext
+    /// single unit files are borrowed from `orig_input` instead of copied.
+    pub fn read_file_cow<'a>(
+        &self,
+        filename: &str,
+        orig_input: &'a [u8],
+    ) -> Result<Cow<'a, [u8]>, MPQParserError> {
+        let hash_entry = self.get_hash_table_entry(filename, None)?;
+        let block_entry = self.get_block_table_entry(hash_entry.block_table_index)?;
+        let flags = block_entry.flags();
+        // Compressed sectors are stored as is when compression gains nothing.
+        let plaintext = !(flags.is_compressed() || flags.is_imploded())
+            || block_entry.archived_size == block_entry.size;
+        if flags.exists() && flags.is_single_unit() && !flags.is_encrypted() && plaintext {
+            let offset = self.block_offset(hash_entry.block_table_index)?;
+            let (_tail, file_data) = dbg_dmp(take(block_entry.archived_size), "file_data")(
+                input_at(orig_input, offset)?,
+            )?;
+            return Ok(Cow::Borrowed(file_data));
+        }
+        let (_tail, file_data) = self.read_block_entry(
+            Some(filename),
+            hash_entry.block_table_index,
+            false,
+            orig_input,
+            None,
+        )?;
+        Ok(Cow::Owned(file_data))
+    }
+
+    /// Detects the type of the contents of a file, only its leading bytes are read,
+    /// see [`FileType::detect`].
+    pub fn detect_file_type(
+        &self,
+        filename: &str,
+        orig_input: &[u8],
+    ) -> Result<FileType, MPQParserError> {
+        let file_data = self.read_file_range(filename, 0..FILE_TYPE_PEEK_SIZE, orig_input)?;
+        Ok(FileType::detect(&file_data))
+    }
+
+    /// Reads an embedded file inside the MPQ archive and decodes it as UTF-8 text.
+    pub fn read_file_string(
+        &self,
+        filename: &str,
+        orig_input: &[u8],
+    ) -> Result<String, MPQParserError> {
+        let (_tail, file_data) = self.read_mpq_file_sector(filename, false, orig_input)?;
+        match String::from_utf8(file_data) {
+            Ok(val) => Ok(val),
+            Err(err) => {
+                tracing::error!("Invalid UTF-8 sequence: {:?}", err);
+                Err(MPQParserError::InvalidUTF8Sequence(filename.to_string()))
+            }
+        }
+    }
+
+    /// Extracts all the files in the `(listfile)`, returning their names and contents.
+    ///
+    /// Names that point to the same block share its content, the block is only read
+    /// and decompressed once.
+    pub fn extract_all(&self, orig_input: &[u8]) -> Result<Vec<(String, Vec<u8>)>, MPQParserError> {
+        let mut block_contents: HashMap<u32, Vec<u8>> = HashMap::new();
+        let mut res = vec![];
+        for (filename, _size) in self.get_files(orig_input)? {
+            let hash_entry = self.get_hash_table_entry(&filename, None)?;
+            let file_data = match block_contents.get(&hash_entry.block_table_index) {
+                Some(val) => {
+                    tracing::debug!("Reusing shared block for {}", filename);
+                    val.clone()
+                }
+                None => {
+                    let (_tail, file_data) = self.read_block_entry(
+                        Some(&filename),
+                        hash_entry.block_table_index,
+                        false,
+                        orig_input,
+                        None,
+                    )?;
+                    block_contents.insert(hash_entry.block_table_index, file_data.clone());
+                    file_data
+                }
+            };
+            res.push((filename, file_data));
+        }
+        Ok(res)
+    }
+
+    /// Extracts all the files in the `(listfile)` to `out_dir`, returning the paths
+    /// written.
+    ///
+    /// The MPQ paths become subdirectories of `out_dir`, see
+    /// [`MPQ::extraction_path`], files whose names could be written outside of it
+    /// are skipped.
+    pub fn extract_all_to(
+        &self,
+        orig_input: &[u8],
+        out_dir: &Path,
+    ) -> Result<Vec<PathBuf>, MPQParserError> {
+        let mut res = vec![];
+        for (filename, file_data) in self.extract_all(orig_input)? {
+            let Some(path) = Self::extraction_path(out_dir, &filename) else {
+                tracing::warn!("Skipping file with an unsafe name: {}", filename);
+                continue;
+            };
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&path, file_data)?;
+            res.push(path);
+        }
+        Ok(res)
+    }
+
+    /// The path of a file extracted to `out_dir`, the name is split on both `\\`
+    /// and `/`, empty components are dropped.
+    /// Returns `None` for absolute names, names with any component other than a
+    /// plain name, i.e. `.`, `..` or a drive prefix, and names without components.
+    pub fn extraction_path(out_dir: &Path, filename: &str) -> Option<PathBuf> {
+        if filename.starts_with(['\\', '/']) {
+            return None;
+        }
+        let mut path = out_dir.to_path_buf();
+        let mut has_components = false;
+        for part in filename.split(['\\', '/']).filter(|part| !part.is_empty()) {
+            for component in Path::new(part).components() {
+                match component {
+                    Component::Normal(component) => path.push(component),
+                    _ => return None,
+                }
+                has_components = true;
+            }
+        }
+        has_components.then_some(path)
+    }
+
+    /// Reads an embedded file inside the MPQ archive, verifying its contents match
+    /// a CRC32 known out-of-band, otherwise [`MPQParserError::ChecksumMismatch`]
+    /// is returned.
+    pub fn read_file_verified(
+        &self,
+        filename: &str,
+        expected_crc: u32,
+        orig_input: &[u8],
+    ) -> Result<Vec<u8>, MPQParserError> {
+        let (_tail, file_data) = self.read_mpq_file_sector(filename, false, orig_input)?;
+        let crc = crc32fast::hash(&file_data);
+        if crc != expected_crc {
+            tracing::error!(
+                "CRC32 of {} is {:#010x}, expected {:#010x}",
+                filename,
+                crc,
+                expected_crc
+            );
+            return Err(MPQParserError::ChecksumMismatch(filename.to_string()));
+        }
+        Ok(file_data)
+    }
+
+    /// Verifies the files listed in a manifest of expected MD5 digests, distributed
+    /// separately from the archive, without relying on the `(attributes)`.
+    ///
+    /// Returns each filename of the manifest, sorted, and whether its contents match
+    /// the expected digest, files that can't be extracted don't match.
+    pub fn verify_against_manifest(
+        &self,
+        orig_input: &[u8],
+        manifest: &HashMap<String, [u8; 16]>,
+    ) -> Vec<(String, bool)> {
+        let mut filenames: Vec<&String> = manifest.keys().collect();
+        filenames.sort();
+        filenames
+            .into_iter()
+            .map(|filename| {
+                let matches = match self.read_mpq_file_sector(filename, false, orig_input) {
+                    Ok((_tail, file_data)) => {
+                        <[u8; 16]>::from(Md5::digest(&file_data)) == manifest[filename]
+                    }
                     Err(err) => {
-                        tracing::error!("Invalid UTF-8 sequence: {:?}", err);
-                        return Err(MPQParserError::InvalidUTF8Sequence(
-                            "(listfile)".to_string(),
-                        ));
+                        tracing::warn!("Unable to extract {}: {:?}", filename, err);
+                        false
                     }
+                };
+                (filename.clone(), matches)
+            })
+            .collect()
+    }
+
+    /// Returns the list of filenames and their respective size as contained in the MPQ archive.
+    ///
+    /// Fails on the first error reported by [`MPQ::files`].
+    pub fn get_files(&self, orig_input: &[u8]) -> Result<Vec<(String, usize)>, MPQParserError> {
+        self.files(orig_input).collect()
+    }
+
+    /// Returns the filenames of the `(listfile)` as they are listed, without
+    /// resolving them in the hash table, so names that are not present in the
+    /// archive are also returned. Both `\n` and `\r\n` line endings are supported.
+    pub fn list_files(&self, orig_input: &[u8]) -> Result<Vec<String>, MPQParserError> {
+        Self::listfile_lines(&self.read_listfile(orig_input)?)
+            .into_iter()
+            .collect()
+    }
+
+    /// Reads the `(listfile)`, a missing one is reported as
+    /// [`MPQParserError::InvalidListFileSector`].
+    fn read_listfile(&self, orig_input: &[u8]) -> Result<Vec<u8>, MPQParserError> {
+        match self.read_mpq_file_sector("(listfile)", false, orig_input) {
+            Ok((_tail, file_buffer)) => Ok(file_buffer),
+            Err(MPQParserError::HashTableEntryNotFound(_)) => {
+                tracing::error!("Unable to find '(listfile)' hash table entry");
+                Err(MPQParserError::InvalidListFileSector)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Iterates over the filenames of the `(listfile)` and their respective size.
+    ///
+    /// Filenames not present in the hash table are skipped, lines that are not valid
+    /// UTF-8 are reported as [`MPQParserError::InvalidUTF8Sequence`] without stopping
+    /// the iteration. A missing `(listfile)` is reported as a single
+    /// [`MPQParserError::InvalidListFileSector`], the filenames of a corrupt one are
+    /// recovered, see [`MPQ::get_files`].
+    pub fn files<'a>(
+        &'a self,
+        orig_input: &[u8],
+    ) -> impl Iterator<Item = Result<(String, usize), MPQParserError>> + 'a {
+        let (recovered, lines) = match self.read_listfile(orig_input) {
+            Ok(file_buffer) => (vec![], Self::listfile_lines(&file_buffer)),
+            Err(MPQParserError::InvalidListFileSector) => {
+                (vec![], vec![Err(MPQParserError::InvalidListFileSector)])
+            }
+            Err(err) => {
+                tracing::error!("Unable to read '(listfile)' sector: {:?}", err);
+                (self.recover_files(orig_input), vec![])
+            }
+        };
+        recovered
+            .into_iter()
+            .map(Ok)
+            .chain(lines.into_iter().filter_map(|line| match line {
+                Ok(filename) => {
+                    let size = self.resolve_file_size(&filename)?;
+                    Some(Ok((filename, size)))
                 }
+                Err(err) => Some(Err(err)),
+            }))
+    }
+
+    /// Calls `f` with each filename in the `(listfile)` and its size, like [`MPQ::get_files`]
+    /// but without collecting the filenames, so huge listfiles don't need a copy of
+    /// each name. Filenames not present in the hash table are skipped.
+    ///
+    /// Like [`MPQ::files`], lines that are not valid UTF-8 don't stop the
+    /// iteration, the first of their errors is returned once all the files are
+    /// visited.
+    pub fn for_each_file<F: FnMut(&str, usize)>(
+        &self,
+        orig_input: &[u8],
+        mut f: F,
+    ) -> Result<(), MPQParserError> {
+        let file_buffer = match self.read_listfile(orig_input) {
+            Ok(file_buffer) => file_buffer,
+            Err(MPQParserError::InvalidListFileSector) => {
+                return Err(MPQParserError::InvalidListFileSector)
             }
             Err(err) => {
                 tracing::error!("Unable to read '(listfile)' sector: {:?}", err);
-                return Err(MPQParserError::InvalidListFileSector);
+                for (filename, size) in self.recover_files(orig_input) {
+                    f(&filename, size);
+                }
+                return Ok(());
             }
         };
-        for filename in files {
-            let hash_entry = match self.get_hash_table_entry(&filename) {
+        let mut res = Ok(());
+        for line in Self::listfile_lines(&file_buffer) {
+            match line {
+                Ok(filename) => {
+                    if let Some(size) = self.resolve_file_size(&filename) {
+                        f(&filename, size);
+                    }
+                }
+                Err(err) => {
+                    if res.is_ok() {
+                        res = Err(err);
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    /// Recovers the list of files of an archive whose `(listfile)` is corrupt.
+    ///
+    /// The filenames in the sectors that can still be read are used, the blocks
+    /// not referenced by them are named after their block table index as
+    /// `FileXXXXXXXX.xxx`.
+    fn recover_files(&self, orig_input: &[u8]) -> Vec<(String, usize)> {
+        let (listfile_prefix, listfile_complete) =
+            match self.read_file_partial("(listfile)", orig_input) {
+                Ok((data, failure)) => (data, failure.is_none()),
+                Err(_) => (vec![], false),
+            };
+        let mut lines = Self::listfile_lines(&listfile_prefix);
+        if !listfile_complete {
+            // The last filename may be cut in the middle.
+            lines.pop();
+        }
+        let files: Vec<String> = lines.into_iter().flatten().collect();
+        tracing::warn!("Recovered {} filenames from '(listfile)'", files.len());
+        let mut res = self.resolve_file_sizes(files);
+        let mut named_blocks: Vec<u32> = vec![];
+        for filename in res
+            .iter()
+            .map(|(filename, _)| filename.as_str())
+            .chain(MPQ_INTERNAL_FILES)
+        {
+            if let Ok(entries) = self.all_hash_table_entries(filename) {
+                named_blocks.extend(entries.iter().map(|entry| entry.block_table_index));
+            }
+        }
+        for entry in &self.hash_table_entries {
+            if entry.is_empty()
+                || entry.is_deleted()
+                || named_blocks.contains(&entry.block_table_index)
+            {
+                continue;
+            }
+            let block_entry = match self.get_block_table_entry(entry.block_table_index) {
                 Ok(val) => val,
+                Err(_) => continue,
+            };
+            if !block_entry.flags().exists() {
+                continue;
+            }
+            named_blocks.push(entry.block_table_index);
+            res.push((
+                format!("File{:08}.xxx", entry.block_table_index),
+                block_entry.size as usize,
+            ));
+        }
+        res
+    }
+
+    /// Reads an embedded file inside the MPQ archive until the first sector that
+    /// fails to be decompressed.
+    ///
+    /// Returns the data of the sectors read and, when a sector failed, its index
+    /// and the error, single unit files fail as a whole at sector 0.
+    pub fn read_file_partial(
+        &self,
+        filename: &str,
+        orig_input: &[u8],
+    ) -> Result<(Vec<u8>, Option<SectorFailure>), MPQParserError> {
+        let hash_entry = self.get_hash_table_entry(filename, None)?;
+        let block_entry = self.get_block_table_entry(hash_entry.block_table_index)?;
+        let mut res = vec![];
+        if !block_entry.flags().exists() || block_entry.archived_size == 0 {
+            return Ok((res, None));
+        }
+        let file_key = self.block_file_key(block_entry, Some(filename))?;
+        let offset = self.block_offset(hash_entry.block_table_index)?;
+        let (_tail, file_data) =
+            dbg_dmp(take(block_entry.archived_size), "file_data")(input_at(orig_input, offset)?)?;
+        if block_entry.flags().is_single_unit() {
+            return match self.read_sector(
+                block_entry.flags(),
+                file_data,
+                file_key,
+                block_entry.size as usize,
+                false,
+                usize::MAX,
+            ) {
+                Ok(decompressed_data) => Ok((decompressed_data, None)),
+                Err(err) => Ok((res, Some((0, err)))),
+            };
+        }
+        let positions = self.parse_sector_offset_table(block_entry, file_data, file_key)?;
+        let sector_size = 512usize << self.archive_header.sector_size_shift;
+        let mut total_sectors = positions.len() - 1;
+        if block_entry.flags().has_sector_crc() {
+            total_sectors -= 1;
+        }
+        for i in 0..total_sectors {
+            let sector = &file_data[positions[i]..positions[i + 1]];
+            let expected_size = (block_entry.size as usize)
+                .saturating_sub(res.len())
+                .min(sector_size);
+            let sector_key = file_key.map(|key| key.wrapping_add(i as u32));
+            match self.read_sector(
+                block_entry.flags(),
+                sector,
+                sector_key,
+                expected_size,
+                false,
+                usize::MAX,
+            ) {
+                Ok(mut decompressed_sector) => res.append(&mut decompressed_sector),
                 Err(err) => {
                     tracing::warn!(
-                        "Unable to find hash entry for filename: {:?}: {:?}",
+                        "Unable to decompress sector {} of {}: {:?}",
+                        i,
                         filename,
                         err
                     );
-                    continue;
+                    return Ok((res, Some((i, err))));
                 }
+            }
+        }
+        Ok((res, None))
+    }
+
+    /// Reads a range of the contents of a file, only the sectors that cover the range
+    /// are decompressed. The range is clamped to the size of the file.
+    ///
+    /// Single unit files have only one sector, so they are decompressed whole.
+    pub fn read_file_range(
+        &self,
+        filename: &str,
+        range: Range<usize>,
+        orig_input: &[u8],
+    ) -> Result<Vec<u8>, MPQParserError> {
+        let hash_entry = self.get_hash_table_entry(filename, None)?;
+        let block_entry = self.get_block_table_entry(hash_entry.block_table_index)?;
+        let end = range.end.min(block_entry.size as usize);
+        if !block_entry.flags().exists() || range.start >= end {
+            return Ok(vec![]);
+        }
+        let file_key = self.block_file_key(block_entry, Some(filename))?;
+        let offset = self.block_offset(hash_entry.block_table_index)?;
+        let (_tail, file_data) =
+            dbg_dmp(take(block_entry.archived_size), "file_data")(input_at(orig_input, offset)?)?;
+        if block_entry.flags().is_single_unit() {
+            let file_data = self.read_sector(
+                block_entry.flags(),
+                file_data,
+                file_key,
+                block_entry.size as usize,
+                false,
+                usize::MAX,
+            )?;
+            return file_data
+                .get(range.start..end)
+                .map(|data| data.to_vec())
+                .ok_or(MPQParserError::SizeMismatch);
+        }
+        let positions = self.parse_sector_offset_table(block_entry, file_data, file_key)?;
+        let sector_size = 512usize << self.archive_header.sector_size_shift;
+        let first_sector = range.start / sector_size;
+        let last_sector = (end - 1) / sector_size;
+        tracing::debug!("Reading sectors {first_sector} to {last_sector} of {filename}");
+        let mut res = vec![];
+        for i in first_sector..=last_sector {
+            let sector = &file_data[positions[i]..positions[i + 1]];
+            let expected_size = (block_entry.size as usize - i * sector_size).min(sector_size);
+            let sector_key = file_key.map(|key| key.wrapping_add(i as u32));
+            let mut sector = self.read_sector(
+                block_entry.flags(),
+                sector,
+                sector_key,
+                expected_size,
+                false,
+                usize::MAX,
+            )?;
+            res.append(&mut sector);
+        }
+        let start = range.start - first_sector * sector_size;
+        res.get(start..end - first_sector * sector_size)
+            .map(|data| data.to_vec())
+            .ok_or(MPQParserError::SizeMismatch)
+    }
+
+    /// Returns the list of filenames and the absolute offset of their block, sorted
+    /// by offset, this is the order in which the files are stored in the archive.
+    pub fn files_by_offset(
+        &self,
+        orig_input: &[u8],
+    ) -> Result<Vec<(String, usize)>, MPQParserError> {
+        let mut res: Vec<(String, usize)> = vec![];
+        for (filename, _size) in self.get_files(orig_input)? {
+            let offset = match self
+                .get_hash_table_entry(&filename, None)
+                .and_then(|entry| self.block_offset(entry.block_table_index))
+            {
+                Ok(val) => val,
+                Err(_) => continue,
             };
-            let block_entry = &self.block_table_entries[hash_entry.block_table_index as usize];
-            tracing::debug!("{} {1:>8} bytes", filename, block_entry.size as usize);
-            res.push((filename, block_entry.size as usize));
+            res.push((filename, offset));
         }
+        res.sort_by_key(|(_, offset)| *offset);
         Ok(res)
     }
+
+    /// Returns every live block in block table order, along with its name when it
+    /// can be resolved from the `(listfile)` or the [`MPQ_INTERNAL_FILES`].
+    ///
+    /// Unlike [`MPQ::get_files`] and [`MPQ::files_by_offset`], blocks without a known
+    /// name are included, which reflects the order in which the files were written.
+    pub fn files_in_block_order(&self, orig_input: &[u8]) -> Vec<(Option<String>, usize)> {
+        let filenames: Vec<String> = match self.get_files(orig_input) {
+            Ok(files) => files
+                .into_iter()
+                .map(|(filename, _size)| filename)
+                .collect(),
+            Err(err) => {
+                tracing::warn!("Unable to resolve filenames: {:?}", err);
+                vec![]
+            }
+        };
+        let mut block_names: HashMap<u32, String> = HashMap::new();
+        for filename in filenames
+            .iter()
+            .map(|filename| filename.as_str())
+            .chain(MPQ_INTERNAL_FILES)
+        {
+            let Ok(entries) = self.all_hash_table_entries(filename) else {
+                continue;
+            };
+            for entry in entries {
+                if !entry.is_empty() && !entry.is_deleted() {
+                    block_names
+                        .entry(entry.block_table_index)
+                        .or_insert_with(|| filename.to_string());
+                }
+            }
+        }
+        self.block_table_entries
+            .iter()
+            .enumerate()
+            .filter(|(_, block_entry)| block_entry.flags().exists())
+            .map(|(block_index, _)| (block_names.get(&(block_index as u32)).cloned(), block_index))
+            .collect()
+    }
+
+    /// Returns the list of filenames and their respective size, merging the
+    /// `(listfile)` of every locale present in the MPQ archive.
+    /// Filenames present in several listfiles are returned only once.
+    pub fn get_files_all_locales(
+        &self,
+        orig_input: &[u8],
+    ) -> Result<Vec<(String, usize)>, MPQParserError> {
+        let hash_a =
+            Self::mpq_string_hash(&self.encryption_table, "(listfile)", MPQHashType::HashA)?;
+        let hash_b =
+            Self::mpq_string_hash(&self.encryption_table, "(listfile)", MPQHashType::HashB)?;
+        let mut files: Vec<String> = vec![];
+        let mut seen_files: HashSet<String> = HashSet::new();
+        let mut listfile_found = false;
+        for entry in &self.hash_table_entries {
+            if entry.hash_a != hash_a || entry.hash_b != hash_b {
+                continue;
+            }
+            if entry.block_table_index as usize >= self.block_table_entries.len() {
+                continue;
+            }
+            tracing::debug!("Reading '(listfile)' for locale: {}", entry.locale);
+            let file_buffer = match self.read_block_entry(
+                Some("(listfile)"),
+                entry.block_table_index,
+                false,
+                orig_input,
+                None,
+            ) {
+                Ok((_tail, val)) => val,
+                Err(err) => {
+                    tracing::error!("Unable to read '(listfile)' sector: {:?}", err);
+                    return Err(MPQParserError::InvalidListFileSector);
+                }
+            };
+            listfile_found = true;
+            for filename in Self::listfile_lines(&file_buffer) {
+                let filename = filename?;
+                if seen_files.insert(filename.clone()) {
+                    files.push(filename);
+                }
+            }
+        }
+        if !listfile_found {
+            tracing::error!("Unable to find any '(listfile)' hash table entry");
+            return Err(MPQParserError::InvalidListFileSector);
+        }
+        Ok(self.resolve_file_sizes(files))
+    }
+
+    /// Exports the hash and block table entries as JSON, all fields in hex.
+    pub fn tables_to_json(&self) -> String {
+        let hash_table: Vec<serde_json::Value> = self
+            .hash_table_entries
+            .iter()
+            .map(|entry| {
+                serde_json::json!({
+                    "hash_a": format!("{:#010X}", entry.hash_a),
+                    "hash_b": format!("{:#010X}", entry.hash_b),
+                    "locale": format!("{:#06X}", entry.locale),
+                    "platform": format!("{:#06X}", entry.platform),
+                    "block_table_index": format!("{:#010X}", entry.block_table_index),
+                })
+            })
+            .collect();
+        let block_table: Vec<serde_json::Value> = self
+            .block_table_entries
+            .iter()
+            .map(|entry| {
+                serde_json::json!({
+                    "offset": format!("{:#010X}", entry.offset),
+                    "archived_size": format!("{:#010X}", entry.archived_size),
+                    "size": format!("{:#010X}", entry.size),
+                    "flags": format!("{:#010X}", entry.flags),
+                })
+            })
+            .collect();
+        serde_json::json!({
+            "hash_table": hash_table,
+            "block_table": block_table,
+        })
+        .to_string()
+    }
+
+    /// Exports the hash and block table entries as CSV, all fields in hex.
+    ///
+    /// Each row starts with the table it belongs to and the index in the table,
+    /// fields that do not apply to the table are left empty.
+    pub fn tables_to_csv(&self) -> String {
+        let mut res = String::from(
+            "table,index,hash_a,hash_b,locale,platform,block_table_index,offset,archived_size,size,flags\n",
+        );
+        for (idx, entry) in self.hash_table_entries.iter().enumerate() {
+            res.push_str(&format!(
+                "hash,{idx},{:#010X},{:#010X},{:#06X},{:#06X},{:#010X},,,,\n",
+                entry.hash_a, entry.hash_b, entry.locale, entry.platform, entry.block_table_index
+            ));
+        }
+        for (idx, entry) in self.block_table_entries.iter().enumerate() {
+            res.push_str(&format!(
+                "block,{idx},,,,,,{:#010X},{:#010X},{:#010X},{:#010X}\n",
+                entry.offset, entry.archived_size, entry.size, entry.flags
+            ));
+        }
+        res
+    }
+
+    /// Dumps the parsed state of the archive as text, useful to attach to bug reports.
+    ///
+    /// The report contains the header fields, every hash and block table entry in hex,
+    /// and the flags and sizes of each file of the `(listfile)`.
+    pub fn debug_dump(&self, orig_input: &[u8]) -> String {
+        let header = &self.archive_header;
+        let mut res = String::from("[archive header]\n");
+        res.push_str(&format!("offset: {:#010X}\n", header.offset));
+        res.push_str(&format!("header_size: {}\n", header.header_size));
+        res.push_str(&format!("archive_size: {}\n", header.archive_size));
+        res.push_str(&format!(
+            "format_version: {} ({:?})\n",
+            header.format_version,
+            header.version()
+        ));
+        res.push_str(&format!(
+            "sector_size_shift: {}\n",
+            header.sector_size_shift
+        ));
+        res.push_str(&format!(
+            "hash_table: offset {:#010X}, {} entries\n",
+            header.hash_table_offset, header.hash_table_entries
+        ));
+        res.push_str(&format!(
+            "block_table: offset {:#010X}, {} entries\n",
+            header.block_table_offset, header.block_table_entries
+        ));
+        if let Some(user_data) = &self.user_data {
+            res.push_str("[user data]\n");
+            res.push_str(&format!("user_data_size: {}\n", user_data.user_data_size));
+            res.push_str(&format!(
+                "archive_header_offset: {:#010X}\n",
+                user_data.archive_header_offset
+            ));
+            res.push_str(&format!(
+                "user_data_header_size: {}\n",
+                user_data.user_data_header_size
+            ));
+        }
+        res.push_str("[hash table]\n");
+        for (idx, entry) in self.hash_table_entries.iter().enumerate() {
+            res.push_str(&format!(
+                "{idx}: hash_a {:#010X}, hash_b {:#010X}, locale {:#06X}, platform {:#06X}, block_table_index {:#010X}\n",
+                entry.hash_a, entry.hash_b, entry.locale, entry.platform, entry.block_table_index
+            ));
+        }
+        res.push_str("[block table]\n");
+        for (idx, entry) in self.block_table_entries.iter().enumerate() {
+            res.push_str(&format!(
+                "{idx}: offset {:#010X}, archived_size {:#010X}, size {:#010X}, flags {:#010X}\n",
+                entry.offset, entry.archived_size, entry.size, entry.flags
+            ));
+        }
+        res.push_str("[files]\n");
+        let files = match self.get_files(orig_input) {
+            Ok(val) => val,
+            Err(err) => {
+                res.push_str(&format!("(listfile) unavailable: {err}\n"));
+                return res;
+            }
+        };
+        for (filename, _size) in files {
+            let block_entry = match self
+                .get_hash_table_entry(&filename, None)
+                .and_then(|entry| self.get_block_table_entry(entry.block_table_index))
+            {
+                Ok(val) => val,
+                Err(err) => {
+                    res.push_str(&format!("{filename}: {err}\n"));
+                    continue;
+                }
+            };
+            res.push_str(&format!(
+                "{filename}: size {}, archived_size {}, {:?}\n",
+                block_entry.size,
+                block_entry.archived_size,
+                block_entry.flags()
+            ));
+        }
+        res
+    }
+
+    /// Computes a SHA-256 fingerprint of the logical content of the archive.
+    ///
+    /// The fingerprint covers the sorted filenames of the `(listfile)` and the
+    /// SHA-256 of their decompressed content, so it doesn't depend on the
+    /// layout or the compression of the archive.
+    pub fn content_fingerprint(&self, orig_input: &[u8]) -> Result<[u8; 32], MPQParserError> {
+        let mut files = self.get_files(orig_input)?;
+        files.sort();
+        let mut hasher = Sha256::new();
+        for (filename, _size) in files {
+            let (_tail, file_data) = self.read_mpq_file_sector(&filename, false, orig_input)?;
+            hasher.update(filename.as_bytes());
+            hasher.update([0u8]);
+            hasher.update(Sha256::digest(&file_data));
+        }
+        Ok(hasher.finalize().into())
+    }
+
+    /// Computes a SHA-256 fingerprint of the header offset and the hash and block
+    /// tables of the archive, identifying it without reading its file data.
+    pub fn tables_fingerprint(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update((self.archive_header.offset as u64).to_le_bytes());
+        for entry in &self.hash_table_entries {
+            hasher.update(entry.to_bytes());
+        }
+        for entry in &self.block_table_entries {
+            hasher.update(entry.to_bytes());
+        }
+        hasher.finalize().into()
+    }
+
+    /// Reads an embedded file inside the MPQ archive, consulting the cache first.
+    ///
+    /// Files are cached by the [`MPQ::archive_fingerprint`] and their block index,
+    /// so archives with different contents never share entries.
+    pub fn read_file_cached(
+        &self,
+        filename: &str,
+        orig_input: &[u8],
+        cache: &mut DecompressedCache,
+    ) -> Result<Vec<u8>, MPQParserError> {
+        let hash_entry = self.get_hash_table_entry(filename, None)?;
+        let key = (
+            self.archive_fingerprint(orig_input),
+            hash_entry.block_table_index,
+        );
+        if let Some(file_data) = cache.get(&key) {
+            tracing::debug!("Found {} in the cache", filename);
+            return Ok(file_data.to_vec());
+        }
+        let (_tail, file_data) = self.read_mpq_file_sector(filename, false, orig_input)?;
+        cache.insert(key, file_data.clone());
+        Ok(file_data)
+    }
+
+    /// Computes a SHA-256 fingerprint of the [`MPQ::tables_fingerprint`] and the
+    /// archive as stored in the input, from its header to its end, identifying its
+    /// contents without decompressing them.
+    ///
+    /// It is computed once, on first use, so the same `orig_input` must be passed
+    /// on every call and the tables must not be modified afterwards.
+    pub fn archive_fingerprint(&self, orig_input: &[u8]) -> [u8; 32] {
+        *self.archive_fingerprint.get_or_init(|| {
+            let mut hasher = Sha256::new();
+            hasher.update(self.tables_fingerprint());
+            let archive_start = self.archive_header.offset.min(orig_input.len());
+            let archive_end = (self.archive_header.offset as u64)
+                .saturating_add(self.archive_header.archive_size_bytes())
+                .min(orig_input.len() as u64) as usize;
+            hasher.update(&orig_input[archive_start..archive_end.max(archive_start)]);
+            hasher.finalize().into()
+        })
+    }
+
+    /// Returns the name as stored in the `(listfile)` of a file whose casing is
+    /// unknown, since the hashing of filenames is case-insensitive the stored name
+    /// is the one whose uppercase form matches the uppercase form of the query.
+    pub fn resolve_stored_name(&self, query: &str, orig_input: &[u8]) -> Option<String> {
+        let file_buffer = self.read_listfile(orig_input).ok()?;
+        let query = query.to_uppercase();
+        Self::listfile_lines(&file_buffer)
+            .into_iter()
+            .flatten()
+            .find(|filename| filename.to_uppercase() == query)
+    }
+
+    /// Reads and parses the `(attributes)` file, its arrays are indexed like the
+    /// block table.
+    pub fn read_attributes(&self, orig_input: &[u8]) -> Result<MPQAttributes, MPQParserError> {
+        let (_tail, attributes_data) =
+            self.read_mpq_file_sector("(attributes)", false, orig_input)?;
+        let (_tail, attributes) =
+            MPQAttributes::parse(&attributes_data, self.block_table_entries.len())?;
+        Ok(attributes)
+    }
+
+    /// Splits the `(listfile)` in lines, each of them decoded separately so an
+    /// invalid line doesn't prevent reading the others. Empty lines are skipped.
+    fn listfile_lines(file_buffer: &[u8]) -> Vec<Result<String, MPQParserError>> {
+        tracing::debug!(
+            "Successfully read '(listfile)' sector: {:?}",
+            parser::peek_hex(file_buffer)
+        );
+        file_buffer
+            .split(|byte| *byte == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+            .filter(|line| !line.is_empty())
+            .map(|line| match std::str::from_utf8(line) {
+                Ok(val) => Ok(val.to_string()),
+                Err(err) => {
+                    tracing::error!("Invalid UTF-8 sequence: {:?}", err);
+                    Err(MPQParserError::InvalidUTF8Sequence(
+                        "(listfile)".to_string(),
+                    ))
+                }
+            })
+            .collect()
+    }
+
+    /// Looks up the size of each of the filenames, filenames not present in the
+    /// hash table are skipped.
+    fn resolve_file_sizes(&self, files: Vec<String>) -> Vec<(String, usize)> {
+        files
+            .into_iter()
+            .filter_map(|filename| {
+                let size = self.resolve_file_size(&filename)?;
+                Some((filename, size))
+            })
+            .collect()
+    }
+
+    /// Looks up the size of a filename, [`None`] if it's not present in the hash table.
+    fn resolve_file_size(&self, filename: &str) -> Option<usize> {
+        let hash_entry = match self.get_hash_table_entry(filename, None) {
+            Ok(val) => val,
+            Err(err) => {
+                tracing::warn!(
+                    "Unable to find hash entry for filename: {:?}: {:?}",
+                    filename,
+                    err
+                );
+                return None;
+            }
+        };
+        let block_entry = match self.get_block_table_entry(hash_entry.block_table_index) {
+            Ok(val) => val,
+            Err(err) => {
+                tracing::warn!(
+                    "Unable to find block for filename: {:?}: {:?}",
+                    filename,
+                    err
+                );
+                return None;
+            }
+        };
+        tracing::debug!("{} {1:>8} bytes", filename, block_entry.size as usize);
+        Some(block_entry.size as usize)
+    }
+
+    /// Checks the archive is fully understood by rebuilding it.
+    ///
+    /// Every file in the `(listfile)` is extracted, for all of its locales and
+    /// platforms, and written to a new archive with the [`MPQBuilder`]. The new
+    /// archive is then parsed and each file must extract to the same contents,
+    /// otherwise [`MPQParserError::RoundtripMismatch`] is returned with its name.
+    pub fn assert_roundtrips(&self, orig_input: &[u8]) -> Result<(), MPQParserError> {
+        let mut files = vec![];
+        for (filename, _size) in self.get_files(orig_input)? {
+            for hash_entry in self.all_hash_table_entries(&filename)? {
+                if hash_entry.is_empty() || hash_entry.is_deleted() {
+                    continue;
+                }
+                let (_tail, data) = self.read_block_entry(
+                    Some(&filename),
+                    hash_entry.block_table_index,
+                    false,
+                    orig_input,
+                    None,
+                )?;
+                files.push(MPQBuilderFile {
+                    locale: hash_entry.locale,
+                    platform: hash_entry.platform,
+                    ..MPQBuilderFile::new(&filename, &data)
+                });
+            }
+        }
+        let archive = files
+            .iter()
+            .cloned()
+            .fold(MPQBuilder::new(), |builder, file| {
+                builder.with_file_entry(file)
+            })
+            .write()?;
+        let (_tail, mpq) = parser::parse(&archive)?;
+        for file in &files {
+            let hash_entry = mpq
+                .all_hash_table_entries(&file.filename)?
+                .into_iter()
+                .find(|entry| entry.locale == file.locale && entry.platform == file.platform)
+                .ok_or_else(|| MPQParserError::RoundtripMismatch(file.filename.clone()))?;
+            let (_tail, data) = mpq.read_block_entry(
+                Some(&file.filename),
+                hash_entry.block_table_index,
+                false,
+                &archive,
+                None,
+            )?;
+            if data != file.data {
+                tracing::error!("File {} doesn't round trip", file.filename);
+                return Err(MPQParserError::RoundtripMismatch(file.filename.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the filenames present in both archives whose contents differ,
+/// the contents are compared by their SHA-256.
+pub fn changed_files(
+    a: &MPQ,
+    a_input: &[u8],
+    b: &MPQ,
+    b_input: &[u8],
+) -> Result<Vec<String>, MPQParserError> {
+    let b_files = b.get_files(b_input)?;
+    let b_filenames: HashSet<&str> = b_files
+        .iter()
+        .map(|(filename, _size)| filename.as_str())
+        .collect();
+    let mut res = vec![];
+    for (filename, _size) in a.get_files(a_input)? {
+        if !b_filenames.contains(filename.as_str()) {
+            continue;
+        }
+        let (_tail, a_data) = a.read_mpq_file_sector(&filename, false, a_input)?;
+        let (_tail, b_data) = b.read_mpq_file_sector(&filename, false, b_input)?;
+        if Sha256::digest(&a_data) != Sha256::digest(&b_data) {
+            tracing::debug!("File {} changed", filename);
+            res.push(filename);
+        }
+    }
+    res.sort();
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The contents of `replay.game.events` in the test archives, 18000 bytes
+    /// spanning 5 sectors of 4096 bytes.
+    pub(crate) fn test_events() -> Vec<u8> {
+        b"replay.game.events".repeat(1000)
+    }
+
+    /// Writes the archive of the builder with [`test_events`] added as
+    /// `replay.game.events`, and parses it.
+    pub(crate) fn events_archive(builder: MPQBuilder) -> (Vec<u8>, MPQ) {
+        let archive = builder
+            .with_file("replay.game.events", &test_events())
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        (archive, mpq)
+    }
+
+    #[test]
+    fn it_enforces_extraction_budget() {
+        let events = test_events();
+        let (archive, mpq) = events_archive(MPQBuilder::new());
+        let sector_budget = ExtractionBudget {
+            max_output_bytes: 1 << 20,
+            max_sectors: 2,
+        };
+        assert!(matches!(
+            mpq.read_mpq_file_sector_with_budget(
+                "replay.game.events",
+                false,
+                &archive,
+                Some(sector_budget)
+            ),
+            Err(MPQParserError::BudgetExceeded)
+        ));
+        let output_budget = ExtractionBudget {
+            max_output_bytes: 1000,
+            max_sectors: 5,
+        };
+        assert!(matches!(
+            mpq.read_mpq_file_sector_with_budget(
+                "replay.game.events",
+                false,
+                &archive,
+                Some(output_budget)
+            ),
+            Err(MPQParserError::BudgetExceeded)
+        ));
+        let budget = ExtractionBudget {
+            max_output_bytes: events.len(),
+            max_sectors: 5,
+        };
+        let (_tail, file_data) = mpq
+            .read_mpq_file_sector_with_budget("replay.game.events", false, &archive, Some(budget))
+            .unwrap();
+        assert_eq!(file_data, events);
+    }
+
+    #[test]
+    fn it_reads_capabilities_of_empty_blocks() {
+        let events = test_events();
+        let archive = MPQBuilder::new()
+            .with_file_entry(MPQBuilderFile {
+                flags: MPQ_FILE_EXISTS | MPQ_FILE_COMPRESS | MPQ_FILE_SINGLE_UNIT,
+                ..MPQBuilderFile::new("replay.game.events", &events)
+            })
+            .write()
+            .unwrap();
+        let (_tail, mut mpq) = parser::parse(&archive).unwrap();
+        assert!(mpq.required_capabilities(&archive).unwrap().zlib);
+        // A single unit block without data has no compression type to read.
+        for block_entry in &mut mpq.block_table_entries {
+            block_entry.archived_size = 0;
+        }
+        assert_eq!(
+            mpq.required_capabilities(&archive).unwrap(),
+            Capabilities::default()
+        );
+    }
+
+    #[test]
+    fn it_fails_capabilities_of_empty_sectors() {
+        let (mut archive, mut mpq) = events_archive(MPQBuilder::new().with_listfile(false));
+        let positions = mpq.sector_offset_table(0, None, &archive).unwrap();
+        // The last sector is empty and ends the block.
+        let block_start = mpq.block_offset(0).unwrap();
+        let last_position = block_start + 4 * (positions.len() - 1);
+        archive[last_position..last_position + 4]
+            .copy_from_slice(&(positions[positions.len() - 2] as u32).to_le_bytes());
+        mpq.block_table_entries[0].archived_size = positions[positions.len() - 2] as u32;
+        assert!(matches!(
+            mpq.required_capabilities(&archive),
+            Err(MPQParserError::IncompleteData)
+        ));
+    }
+
+    #[test]
+    fn it_caps_the_output_of_single_unit_files() {
+        let events = test_events();
+        let archive = MPQBuilder::new()
+            .with_file_entry(MPQBuilderFile {
+                flags: MPQ_FILE_EXISTS | MPQ_FILE_COMPRESS | MPQ_FILE_SINGLE_UNIT,
+                ..MPQBuilderFile::new("replay.game.events", &events)
+            })
+            .write()
+            .unwrap();
+        let (_tail, mut mpq) = parser::parse(&archive).unwrap();
+        // The declared size fits the budget, the decompressed data does not.
+        let hash_entry = mpq
+            .get_hash_table_entry("replay.game.events", None)
+            .unwrap();
+        let block_table_index = hash_entry.block_table_index as usize;
+        mpq.block_table_entries[block_table_index].size = 500;
+        let budget = ExtractionBudget {
+            max_output_bytes: 1000,
+            max_sectors: 1,
+        };
+        assert!(matches!(
+            mpq.read_mpq_file_sector_with_budget(
+                "replay.game.events",
+                false,
+                &archive,
+                Some(budget)
+            ),
+            Err(MPQParserError::BudgetExceeded)
+        ));
+    }
+
+    #[test]
+    fn it_merges_listfiles_of_all_locales() {
+        let english_listfile = MPQBuilderFile {
+            locale: 0x0409,
+            ..MPQBuilderFile::new("(listfile)", b"english.txt")
+        };
+        let german_listfile = MPQBuilderFile {
+            locale: 0x0407,
+            ..MPQBuilderFile::new("(listfile)", b"german.txt\r\nenglish.txt")
+        };
+        let archive = MPQBuilder::new()
+            .with_listfile(false)
+            .with_file("english.txt", b"Hello")
+            .with_file("german.txt", b"Hallo")
+            .with_file_entry(english_listfile)
+            .with_file_entry(german_listfile)
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        let mut files = mpq.get_files_all_locales(&archive).unwrap();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                ("english.txt".to_string(), 5),
+                ("german.txt".to_string(), 5)
+            ]
+        );
+    }
+
+    #[test]
+    fn it_fingerprints_content_independently_of_layout() {
+        let details = b"replay.details contents ".repeat(100);
+        let events = test_events();
+        let (archive, mpq) =
+            events_archive(MPQBuilder::new().with_file("replay.details", &details));
+        // The same files, in a different order, stored uncompressed as single units.
+        let repacked_archive = MPQBuilder::new()
+            .with_file_entry(MPQBuilderFile {
+                flags: MPQ_FILE_EXISTS | MPQ_FILE_SINGLE_UNIT,
+                ..MPQBuilderFile::new("replay.game.events", &events)
+            })
+            .with_file_entry(MPQBuilderFile {
+                flags: MPQ_FILE_EXISTS | MPQ_FILE_SINGLE_UNIT,
+                ..MPQBuilderFile::new("replay.details", &details)
+            })
+            .write()
+            .unwrap();
+        assert_ne!(archive, repacked_archive);
+        let (_tail, repacked_mpq) = parser::parse(&repacked_archive).unwrap();
+        let fingerprint = mpq.content_fingerprint(&archive).unwrap();
+        assert_eq!(
+            fingerprint,
+            repacked_mpq.content_fingerprint(&repacked_archive).unwrap()
+        );
+        let changed_archive = MPQBuilder::new()
+            .with_file("replay.details", &details)
+            .with_file("replay.game.events", &events[1..])
+            .write()
+            .unwrap();
+        let (_tail, changed_mpq) = parser::parse(&changed_archive).unwrap();
+        assert_ne!(
+            fingerprint,
+            changed_mpq.content_fingerprint(&changed_archive).unwrap()
+        );
+    }
+
+    #[test]
+    fn it_probes_hash_table_from_home_entry() {
+        let home = |filename: &str| {
+            MPQ::mpq_string_hash(
+                &MPQ::shared_encryption_table(),
+                filename,
+                MPQHashType::TableOffset,
+            )
+            .unwrap()
+                % 16
+        };
+        let colliding = (0..)
+            .map(|idx| format!("file{idx}.txt"))
+            .find(|filename| home(filename) == home("replay.details"))
+            .unwrap();
+        let archive = MPQBuilder::new()
+            .with_file("replay.details", b"details")
+            .with_file(&colliding, b"colliding")
+            .with_hash_table_size(16)
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        let home_idx = home("replay.details") as usize;
+        let details_entry = mpq.get_hash_table_entry("replay.details", None).unwrap();
+        let colliding_entry = mpq.get_hash_table_entry(&colliding, None).unwrap();
+        assert_ne!(details_entry, colliding_entry);
+        // One of the files is at the home entry, the other one right after it.
+        let displaced = if mpq.hash_table_entries[home_idx] == details_entry {
+            assert_eq!(mpq.hash_table_entries[(home_idx + 1) % 16], colliding_entry);
+            colliding.as_str()
+        } else {
+            assert_eq!(mpq.hash_table_entries[home_idx], colliding_entry);
+            assert_eq!(mpq.hash_table_entries[(home_idx + 1) % 16], details_entry);
+            "replay.details"
+        };
+        assert_eq!(
+            mpq.read_file_string("replay.details", &archive).unwrap(),
+            "details"
+        );
+        assert_eq!(
+            mpq.read_file_string(&colliding, &archive).unwrap(),
+            "colliding"
+        );
+        // The search stops at the first empty entry.
+        let mut mpq = mpq;
+        mpq.hash_table_entries[home_idx].block_table_index = parser::MPQ_HASH_ENTRY_EMPTY;
+        assert!(matches!(
+            mpq.get_hash_table_entry(displaced, None),
+            Err(MPQParserError::HashTableEntryNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn it_finds_hash_table_entry_for_locale() {
+        let archive = MPQBuilder::new()
+            .with_file_entry(MPQBuilderFile {
+                locale: 0x0407,
+                ..MPQBuilderFile::new("strings.txt", b"Hallo Welt")
+            })
+            .with_file("strings.txt", b"Hello")
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        let neutral_entry = mpq.get_hash_table_entry("strings.txt", None).unwrap();
+        assert_eq!(neutral_entry.locale, 0);
+        let block_entry = mpq
+            .get_block_table_entry(neutral_entry.block_table_index)
+            .unwrap();
+        assert_eq!(block_entry.size, 5);
+        let german_entry = mpq
+            .get_hash_table_entry("strings.txt", Some(0x0407))
+            .unwrap();
+        let block_entry = mpq
+            .get_block_table_entry(german_entry.block_table_index)
+            .unwrap();
+        assert_eq!(block_entry.size, 10);
+        assert!(matches!(
+            mpq.get_hash_table_entry("strings.txt", Some(0x0409)),
+            Err(MPQParserError::HashTableEntryNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn it_reads_file_by_block_index() {
+        let events = test_events();
+        let (archive, mpq) = events_archive(
+            MPQBuilder::new()
+                .with_file_entry(MPQBuilderFile {
+                    flags: MPQ_FILE_EXISTS | MPQ_FILE_COMPRESS | MPQ_FILE_ENCRYPTED,
+                    ..MPQBuilderFile::new("replay.encrypted.events", &events)
+                })
+                .with_listfile(false),
+        );
+        let hash_entry = mpq
+            .get_hash_table_entry("replay.game.events", None)
+            .unwrap();
+        let (_tail, expected) = mpq
+            .read_mpq_file_sector("replay.game.events", false, &archive)
+            .unwrap();
+        assert_eq!(expected, events);
+        assert_eq!(
+            mpq.read_file_by_block_index(hash_entry.block_table_index as usize, &archive)
+                .unwrap(),
+            expected
+        );
+        let hash_entry = mpq
+            .get_hash_table_entry("replay.encrypted.events", None)
+            .unwrap();
+        assert!(matches!(
+            mpq.read_file_by_block_index(hash_entry.block_table_index as usize, &archive),
+            Err(MPQParserError::UnsupportedEncryptionType)
+        ));
+        assert!(matches!(
+            mpq.read_file_by_block_index(2, &archive),
+            Err(MPQParserError::InvalidBlockIndex(2))
+        ));
+    }
+
+    #[test]
+    fn it_finds_hash_table_entry_for_platform() {
+        let archive = MPQBuilder::new()
+            .with_file("units.txt", b"default")
+            .with_file_entry(MPQBuilderFile {
+                platform: 1,
+                ..MPQBuilderFile::new("units.txt", b"platform")
+            })
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        let default_entry = mpq
+            .get_hash_table_entry_for_platform("units.txt", 0)
+            .unwrap();
+        assert_eq!(default_entry.platform, 0);
+        assert_eq!(default_entry.block_table_index, 0);
+        let platform_entry = mpq
+            .get_hash_table_entry_for_platform("units.txt", 1)
+            .unwrap();
+        assert_eq!(platform_entry.platform, 1);
+        assert_eq!(platform_entry.block_table_index, 1);
+        assert!(matches!(
+            mpq.get_hash_table_entry_for_platform("units.txt", 2),
+            Err(MPQParserError::HashTableEntryNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn it_combines_the_high_bits_of_block_offsets() {
+        let (_archive, mut mpq) = events_archive(MPQBuilder::new());
+        let low_offset = mpq.block_table_entries[0].offset as usize;
+        assert_eq!(mpq.block_offset(0).unwrap(), low_offset);
+        mpq.hi_block_table = Some(vec![0x0001; mpq.block_table_entries.len()]);
+        assert_eq!(mpq.block_offset(0).unwrap(), (1 << 32) + low_offset);
+        assert!(matches!(
+            mpq.block_offset(mpq.block_table_entries.len() as u32),
+            Err(MPQParserError::InvalidBlockIndex(_))
+        ));
+    }
+
+    #[test]
+    fn it_reads_encrypted_sector_offset_table() {
+        let events = test_events();
+        let (archive, mpq) = events_archive(MPQBuilder::new());
+        let positions = mpq.sector_offset_table(0, None, &archive).unwrap();
+        // 5 sectors and the end of the last sector.
+        assert_eq!(positions.len(), 6);
+        let encrypted_archive = MPQBuilder::new()
+            .with_file_entry(MPQBuilderFile {
+                flags: MPQ_FILE_EXISTS | MPQ_FILE_COMPRESS | MPQ_FILE_ENCRYPTED,
+                ..MPQBuilderFile::new("replay.game.events", &events)
+            })
+            .write()
+            .unwrap();
+        let (_tail, encrypted_mpq) = parser::parse(&encrypted_archive).unwrap();
+        let file_key = MPQ::mpq_string_hash(
+            &encrypted_mpq.encryption_table,
+            "replay.game.events",
+            MPQHashType::Table,
+        )
+        .unwrap();
+        assert_eq!(
+            encrypted_mpq
+                .sector_offset_table(0, Some(file_key), &encrypted_archive)
+                .unwrap(),
+            positions
+        );
+        assert!(matches!(
+            encrypted_mpq.sector_offset_table(0, None, &encrypted_archive),
+            Err(MPQParserError::UnsupportedEncryptionType)
+        ));
+        assert!(matches!(
+            encrypted_mpq.sector_offset_table(0, Some(file_key + 1), &encrypted_archive),
+            Err(MPQParserError::InvalidSectorOffsetTable)
+        ));
+    }
+
+    #[test]
+    fn it_reads_compressed_sector_offset_table() {
+        use flate2::write::ZlibEncoder;
+        use std::io::Write;
+
+        // 100 uncompressed sectors, the offsets of the table compress well.
+        let data: Vec<u8> = (0..100 * 4096).map(|i| (i % 251) as u8).collect();
+        let mut archive = MPQBuilder::new()
+            .with_file_entry(MPQBuilderFile {
+                flags: MPQ_FILE_EXISTS | MPQ_FILE_ENCRYPTED,
+                ..MPQBuilderFile::new("replay.game.events", &data)
+            })
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        let block_entry = &mpq.block_table_entries[0];
+        let file_key = MPQ::mpq_string_hash(
+            &mpq.encryption_table,
+            "replay.game.events",
+            MPQHashType::Table,
+        )
+        .unwrap();
+        let positions = mpq
+            .sector_offset_table(0, Some(file_key), &archive)
+            .unwrap();
+        assert_eq!(positions[0], 4 * 101);
+        // Move the sectors 4 bytes closer to the start of the block, the table is
+        // then stored compressed before them.
+        let table_end = positions[0] - 4;
+        let mut table = vec![];
+        for position in &positions {
+            table.extend_from_slice(&(*position as u32 - 4).to_le_bytes());
+        }
+        let mut encoder = ZlibEncoder::new(vec![COMPRESSION_ZLIB], Default::default());
+        encoder.write_all(&table).unwrap();
+        let compressed_table = encoder.finish().unwrap();
+        let mut compressed_table_data = (table_end as u32).to_le_bytes().to_vec();
+        compressed_table_data.extend_from_slice(&compressed_table);
+        compressed_table_data.resize(table_end, 0);
+        let (unaligned_tail, mut block) = MPQ::mpq_data_encrypt(
+            &mpq.encryption_table,
+            &compressed_table_data,
+            file_key.wrapping_sub(1),
+        )
+        .unwrap();
+        block.extend_from_slice(unaligned_tail);
+        let block_start = block_entry.offset as usize;
+        let block_end = block_start + block_entry.archived_size as usize;
+        block.extend_from_slice(&archive[block_start + positions[0]..block_end]);
+        block.resize(block_entry.archived_size as usize, 0);
+        archive[block_start..block_end].copy_from_slice(&block);
+        let compressed_positions = mpq
+            .sector_offset_table(0, Some(file_key), &archive)
+            .unwrap();
+        assert_eq!(compressed_positions[0], table_end);
+        assert_eq!(compressed_positions.len(), positions.len());
+        let (_tail, file_data) = mpq
+            .read_mpq_file_sector("replay.game.events", false, &archive)
+            .unwrap();
+        assert_eq!(file_data, data);
+        // A table that doesn't decompress.
+        let mut corrupt_table = archive.clone();
+        corrupt_table[block_start + 4] ^= 0xff;
+        assert!(matches!(
+            mpq.sector_offset_table(0, Some(file_key), &corrupt_table),
+            Err(MPQParserError::InvalidSectorOffsetTable)
+        ));
+    }
+
+    #[test]
+    fn it_parses_archive_without_block_table() {
+        let mut archive = MPQBuilder::new()
+            .with_file("replay.details", b"details")
+            .write()
+            .unwrap();
+        // Zero the block_table_entries field of the archive header.
+        archive[0x1c..0x20].copy_from_slice(&0u32.to_le_bytes());
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        assert!(mpq.block_table_entries.is_empty());
+        assert_eq!(mpq.hash_table_entries.len(), 2);
+        assert!(matches!(
+            mpq.read_mpq_file_sector("replay.details", false, &archive),
+            Err(MPQParserError::InvalidBlockIndex(_))
+        ));
+        assert!(matches!(
+            mpq.read_mpq_file_sector("(listfile)", false, &archive),
+            Err(MPQParserError::InvalidBlockIndex(_))
+        ));
+    }
+
+    #[test]
+    fn it_validates_hash_block_consistency() {
+        let mut archive = MPQBuilder::new()
+            .with_file("replay.details", b"details")
+            .with_file("replay.initData", b"initData")
+            .with_file("replay.game.events", b"events")
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        assert!(mpq.validate_hash_block_consistency().is_empty());
+        // Keep only the first two entries of the block table.
+        archive[0x1c..0x20].copy_from_slice(&2u32.to_le_bytes());
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        let dangling = mpq.validate_hash_block_consistency();
+        let mut dangling_blocks: Vec<u32> = dangling.iter().map(|(_, block)| *block).collect();
+        dangling_blocks.sort();
+        assert_eq!(dangling_blocks, vec![2, 3]);
+        for (hash_slot, block_index) in dangling {
+            assert_eq!(
+                mpq.hash_table_entries[hash_slot].block_table_index,
+                block_index
+            );
+        }
+    }
+
+    #[test]
+    fn it_reads_file_with_locale_fallback() {
+        let archive = MPQBuilder::new()
+            .with_file_entry(MPQBuilderFile {
+                locale: 0x0407,
+                ..MPQBuilderFile::new("greeting.txt", b"Hallo")
+            })
+            .with_file_entry(MPQBuilderFile {
+                locale: 0x040c,
+                ..MPQBuilderFile::new("farewell.txt", b"Au revoir")
+            })
+            .with_file("farewell.txt", b"Goodbye")
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        // Only the german greeting exists.
+        assert_eq!(
+            mpq.read_file_localized("greeting.txt", 0x0409, &archive)
+                .unwrap(),
+            b"Hallo"
+        );
+        // The neutral farewell is preferred over the french one.
+        assert_eq!(
+            mpq.read_file_localized("farewell.txt", 0x0409, &archive)
+                .unwrap(),
+            b"Goodbye"
+        );
+        assert_eq!(
+            mpq.read_file_localized("farewell.txt", 0x040c, &archive)
+                .unwrap(),
+            b"Au revoir"
+        );
+        assert!(matches!(
+            mpq.read_file_localized("missing.txt", 0x0409, &archive),
+            Err(MPQParserError::HashTableEntryNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn it_replaces_file() {
+        let details = b"replay.details contents ".repeat(100);
+        let (archive, mut mpq) =
+            events_archive(MPQBuilder::new().with_file("replay.details", &details));
+        // The smaller contents are written in place.
+        let smaller_events = b"replay.game.events".repeat(10);
+        let archive = mpq
+            .replace_file("replay.game.events", &smaller_events, &archive)
+            .unwrap();
+        // The bigger contents are appended.
+        let bigger_details = b"replay.details new contents ".repeat(1000);
+        let archive = mpq
+            .replace_file("replay.details", &bigger_details, &archive)
+            .unwrap();
+        let (_tail, replaced_mpq) = parser::parse(&archive).unwrap();
+        assert_eq!(replaced_mpq.block_table_entries, mpq.block_table_entries);
+        // The space freed by each replacement is marked by an entry without flags.
+        assert_eq!(replaced_mpq.block_table_entries.len(), 5);
+        for free_entry in &replaced_mpq.block_table_entries[3..] {
+            assert_eq!(free_entry.flags, 0);
+            assert_eq!(free_entry.size, 0);
+            assert!(free_entry.archived_size > 0);
+        }
+        assert_eq!(replaced_mpq.get_files(&archive).unwrap().len(), 2);
+        assert_eq!(
+            replaced_mpq.archive_header.archive_size as usize,
+            archive.len()
+        );
+        for (filename, data) in [
+            ("replay.details", bigger_details),
+            ("replay.game.events", smaller_events),
+        ] {
+            let (_tail, file_data) = replaced_mpq
+                .read_mpq_file_sector(filename, false, &archive)
+                .unwrap();
+            assert_eq!(file_data, data);
+        }
+        // The later formats are rejected.
+        let (_tail, mut mpq) = parser::parse(&archive).unwrap();
+        mpq.archive_header.format_version = 2;
+        assert!(matches!(
+            mpq.replace_file("replay.details", &details, &archive),
+            Err(MPQParserError::UnsupportedArchiveFormat(2))
+        ));
+    }
+
+    #[test]
+    fn it_reads_raw_sector_bytes() {
+        let events = test_events();
+        let (archive, mpq) = events_archive(MPQBuilder::new().with_file_entry(MPQBuilderFile {
+            flags: MPQ_FILE_EXISTS | MPQ_FILE_COMPRESS | MPQ_FILE_ENCRYPTED,
+            ..MPQBuilderFile::new("replay.encrypted.events", &events)
+        }));
+        let raw_sector = mpq
+            .raw_sector_bytes("replay.game.events", 0, &archive)
+            .unwrap();
+        assert_eq!(raw_sector[0], COMPRESSION_ZLIB);
+        let (_tail, sector) = MPQ::decompress(raw_sector).unwrap();
+        assert_eq!(sector, events[..4096]);
+        let raw_sector = mpq
+            .raw_sector_bytes("replay.game.events", 4, &archive)
+            .unwrap();
+        let (_tail, sector) = MPQ::decompress(raw_sector).unwrap();
+        assert_eq!(sector, events[4 * 4096..]);
+        assert!(matches!(
+            mpq.raw_sector_bytes("replay.game.events", 5, &archive),
+            Err(MPQParserError::SectorIndexOutOfRange(5))
+        ));
+        // Encrypted sectors are returned as stored.
+        let raw_sector = mpq
+            .raw_sector_bytes("replay.encrypted.events", 0, &archive)
+            .unwrap();
+        assert_ne!(raw_sector[0], COMPRESSION_ZLIB);
+    }
+
+    #[test]
+    fn it_finds_all_hash_table_entries() {
+        let archive = MPQBuilder::new()
+            .with_file("greeting.txt", b"Hello")
+            .with_file_entry(MPQBuilderFile {
+                locale: 0x0407,
+                ..MPQBuilderFile::new("greeting.txt", b"Hallo")
+            })
+            .with_file_entry(MPQBuilderFile {
+                platform: 1,
+                ..MPQBuilderFile::new("greeting.txt", b"Hi")
+            })
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        let mut entries: Vec<(u16, u16, u32)> = mpq
+            .all_hash_table_entries("greeting.txt")
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.locale, entry.platform, entry.block_table_index))
+            .collect();
+        entries.sort();
+        assert_eq!(entries, vec![(0, 0, 0), (0, 1, 1), (0x0407, 0, 2)]);
+        assert!(mpq
+            .all_hash_table_entries("missing.txt")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn it_recovers_files_from_corrupt_listfile() {
+        // The listfile spans 3 sectors of 4096 bytes.
+        let filenames: Vec<String> = (0..600).map(|idx| format!("file{idx:04}.txt")).collect();
+        let mut builder = MPQBuilder::new();
+        for filename in &filenames {
+            builder = builder.with_file(filename, filename.as_bytes());
+        }
+        let mut archive = builder.write().unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        let listfile_entry = mpq.get_hash_table_entry("(listfile)", None).unwrap();
+        let listfile_block = mpq
+            .get_block_table_entry(listfile_entry.block_table_index)
+            .unwrap();
+        let positions = mpq
+            .sector_offset_table(listfile_entry.block_table_index, None, &archive)
+            .unwrap();
+        assert_eq!(positions.len(), 4);
+        // Corrupt the compressed data of the last sector.
+        let last_sector_start = listfile_block.offset as usize + positions[2] + 1;
+        let last_sector_end = listfile_block.offset as usize + positions[3];
+        archive[last_sector_start..last_sector_end].fill(0xff);
+        assert!(mpq
+            .read_mpq_file_sector("(listfile)", false, &archive)
+            .is_err());
+        let files = mpq.get_files(&archive).unwrap();
+        assert_eq!(files.len(), filenames.len());
+        // Each filename takes 14 bytes, 585 are complete in the first 2 sectors.
+        let recovered_files: Vec<String> = files
+            .iter()
+            .map(|(filename, _)| filename.clone())
+            .filter(|filename| filenames.contains(filename))
+            .collect();
+        assert_eq!(recovered_files, filenames[..585]);
+        for (filename, size) in &files[585..] {
+            assert!(filename.starts_with("File") && filename.ends_with(".xxx"));
+            assert_eq!(*size, 12);
+        }
+    }
+
+    #[test]
+    fn it_shares_encryption_table_between_threads() {
+        let archive = MPQBuilder::new()
+            .with_file("replay.details", b"replay.details contents")
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        let (_tail, other_mpq) = parser::parse(&archive).unwrap();
+        assert!(Arc::ptr_eq(
+            &mpq.encryption_table,
+            &other_mpq.encryption_table
+        ));
+        let expected =
+            MPQ::mpq_string_hash(&mpq.encryption_table, "(listfile)", MPQHashType::HashA).unwrap();
+        let handles: Vec<_> = (0..2)
+            .map(|_| {
+                let encryption_table = Arc::clone(&mpq.encryption_table);
+                std::thread::spawn(move || {
+                    MPQ::mpq_string_hash(&encryption_table, "(listfile)", MPQHashType::HashA)
+                        .unwrap()
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn it_rejects_block_without_data_for_non_empty_file() {
+        let archive = MPQBuilder::new()
+            .with_file("replay.details", b"details")
+            .with_file("empty.txt", b"")
+            .write()
+            .unwrap();
+        let (_tail, mut mpq) = parser::parse(&archive).unwrap();
+        let (_tail, empty_file) = mpq
+            .read_mpq_file_sector("empty.txt", false, &archive)
+            .unwrap();
+        assert!(empty_file.is_empty());
+        let block_table_index = mpq
+            .get_hash_table_entry("replay.details", None)
+            .unwrap()
+            .block_table_index as usize;
+        mpq.block_table_entries[block_table_index].size = 100;
+        mpq.block_table_entries[block_table_index].archived_size = 0;
+        assert!(matches!(
+            mpq.read_mpq_file_sector("replay.details", false, &archive),
+            Err(MPQParserError::SizeMismatch)
+        ));
+    }
+
+    #[test]
+    fn it_uses_registered_decompressor() {
+        // A run-length encoded file using an unused compression type.
+        let archive = MPQBuilder::new()
+            .with_file_entry(MPQBuilderFile {
+                flags: MPQ_FILE_EXISTS | MPQ_FILE_SINGLE_UNIT,
+                ..MPQBuilderFile::new("run.length", &[0x04, 100, b'a'])
+            })
+            .write()
+            .unwrap();
+        let (_tail, mut mpq) = parser::parse(&archive).unwrap();
+        let block_table_index = mpq
+            .get_hash_table_entry("run.length", None)
+            .unwrap()
+            .block_table_index as usize;
+        mpq.block_table_entries[block_table_index].flags |= MPQ_FILE_COMPRESS;
+        mpq.block_table_entries[block_table_index].size = 100;
+        assert!(matches!(
+            mpq.read_mpq_file_sector("run.length", false, &archive),
+            Err(MPQParserError::UnsupportedCompression(0x04))
+        ));
+        mpq.register_decompressor(0x04, |data| {
+            Ok(data
+                .chunks(2)
+                .flat_map(|run| vec![run[1]; run[0] as usize])
+                .collect())
+        });
+        let (_tail, file_data) = mpq
+            .read_mpq_file_sector("run.length", false, &archive)
+            .unwrap();
+        assert_eq!(file_data, vec![b'a'; 100]);
+    }
+
+    #[test]
+    fn it_finds_changed_files() {
+        let details = b"replay.details contents ".repeat(100);
+        let events = test_events();
+        let (archive, mpq) = events_archive(
+            MPQBuilder::new()
+                .with_file("replay.details", &details)
+                .with_file("replay.removed", b"removed"),
+        );
+        let changed_archive = MPQBuilder::new()
+            .with_file("replay.game.events", &events[1..])
+            .with_file("replay.details", &details)
+            .with_file("replay.added", b"added")
+            .write()
+            .unwrap();
+        let (_tail, changed_mpq) = parser::parse(&changed_archive).unwrap();
+        assert_eq!(
+            changed_files(&mpq, &archive, &changed_mpq, &changed_archive).unwrap(),
+            vec!["replay.game.events".to_string()]
+        );
+        assert!(changed_files(&mpq, &archive, &mpq, &archive)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn it_reads_file_partially() {
+        let events = test_events();
+        let (mut archive, mpq) = events_archive(MPQBuilder::new());
+        let (file_data, failure) = mpq
+            .read_file_partial("replay.game.events", &archive)
+            .unwrap();
+        assert_eq!(file_data, events);
+        assert!(failure.is_none());
+        let block_table_index = mpq
+            .get_hash_table_entry("replay.game.events", None)
+            .unwrap()
+            .block_table_index;
+        let block_entry = mpq.get_block_table_entry(block_table_index).unwrap();
+        let positions = mpq
+            .sector_offset_table(block_table_index, None, &archive)
+            .unwrap();
+        // Corrupt the compressed data of the third sector.
+        let sector_start = block_entry.offset as usize + positions[2] + 1;
+        let sector_end = block_entry.offset as usize + positions[3];
+        archive[sector_start..sector_end].fill(0xff);
+        let (file_data, failure) = mpq
+            .read_file_partial("replay.game.events", &archive)
+            .unwrap();
+        assert_eq!(file_data, events[..2 * 4096]);
+        assert!(matches!(failure, Some((2, _))));
+    }
+
+    #[test]
+    fn it_strips_plaintext_sector_prefix_in_compressed_file() {
+        use flate2::write::ZlibEncoder;
+        use std::io::Write;
+        // A file of 612 bytes in sectors of 512 bytes, the first sector is zlib
+        // compressed and the second one is stored with the plaintext prefix.
+        let file_contents: Vec<u8> = (0..612u32).map(|i| (i % 7) as u8).collect();
+        let mut encoder = ZlibEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(&file_contents[..512]).unwrap();
+        let mut first_sector = vec![COMPRESSION_ZLIB];
+        first_sector.append(&mut encoder.finish().unwrap());
+        let mut second_sector = vec![COMPRESSION_PLAINTEXT];
+        second_sector.extend_from_slice(&file_contents[512..]);
+        let table_size = 12u32;
+        let positions = [
+            table_size,
+            table_size + first_sector.len() as u32,
+            table_size + (first_sector.len() + second_sector.len()) as u32,
+        ];
+        let mut orig_input: Vec<u8> = positions.iter().flat_map(|x| x.to_le_bytes()).collect();
+        orig_input.append(&mut first_sector);
+        orig_input.append(&mut second_sector);
+        let builder = MPQBuilder::new();
+        let mpq = MPQBuilder::new()
+            .with_archive_header(MPQFileHeader {
+                hash_table_entries: 1,
+                block_table_entries: 1,
+                ..Default::default()
+            })
+            .with_hash_table(vec![MPQHashTableEntry::new(
+                builder
+                    .mpq_string_hash("mixed.file", MPQHashType::HashA)
+                    .unwrap(),
+                builder
+                    .mpq_string_hash("mixed.file", MPQHashType::HashB)
+                    .unwrap(),
+                0,
+                0,
+                0,
+            )])
+            .with_block_table(vec![MPQBlockTableEntry::new(
+                0,
+                orig_input.len() as u32,
+                file_contents.len() as u32,
+                MPQ_FILE_EXISTS | MPQ_FILE_COMPRESS,
+            )])
+            .build(&orig_input)
+            .unwrap();
+        let (_tail, file_data) = mpq
+            .read_mpq_file_sector("mixed.file", false, &orig_input)
+            .unwrap();
+        assert_eq!(file_data, file_contents);
+        let (file_data, failure) = mpq.read_file_partial("mixed.file", &orig_input).unwrap();
+        assert_eq!(file_data, file_contents);
+        assert!(failure.is_none());
+    }
+
+    #[test]
+    fn it_resolves_stored_name() {
+        let archive = MPQBuilder::new()
+            .with_file("replay.details", b"replay.details contents")
+            .with_file("Replay.Game.Events", b"replay.game.events contents")
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        assert_eq!(
+            mpq.resolve_stored_name("REPLAY.DETAILS", &archive),
+            Some("replay.details".to_string())
+        );
+        assert_eq!(
+            mpq.resolve_stored_name("replay.game.events", &archive),
+            Some("Replay.Game.Events".to_string())
+        );
+        assert_eq!(mpq.resolve_stored_name("replay.missing", &archive), None);
+    }
+
+    #[test]
+    fn it_extracts_shared_blocks_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let archive = MPQBuilder::new()
+            .with_file("enUS\\strings.txt", &b"strings ".repeat(100))
+            .with_file("deDE\\strings.txt", &b"strings ".repeat(100))
+            .write()
+            .unwrap();
+        let (_tail, mut mpq) = parser::parse(&archive).unwrap();
+        // Point both names to the same block.
+        let shared_block_index = mpq
+            .get_hash_table_entry("enUS\\strings.txt", None)
+            .unwrap()
+            .block_table_index;
+        let hash_entry = mpq.get_hash_table_entry("deDE\\strings.txt", None).unwrap();
+        for entry in mpq.hash_table_entries.iter_mut() {
+            if *entry == hash_entry {
+                entry.block_table_index = shared_block_index;
+            }
+        }
+        let decompressions = Arc::new(AtomicUsize::new(0));
+        let counter = decompressions.clone();
+        mpq.register_decompressor(COMPRESSION_ZLIB, move |data| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let (_tail, res) = MPQ::decompress(&[&[COMPRESSION_ZLIB], data].concat())?;
+            Ok(res)
+        });
+        mpq.get_files(&archive).unwrap();
+        let listfile_decompressions = decompressions.swap(0, Ordering::SeqCst);
+        let files = mpq.extract_all(&archive).unwrap();
+        assert_eq!(
+            decompressions.load(Ordering::SeqCst),
+            listfile_decompressions + 1
+        );
+        assert_eq!(files.len(), 2);
+        assert!(files
+            .iter()
+            .all(|(_filename, file_data)| *file_data == b"strings ".repeat(100)));
+    }
+
+    #[test]
+    fn it_verifies_against_manifest() {
+        let details = b"replay.details contents ".repeat(100);
+        let (archive, mpq) =
+            events_archive(MPQBuilder::new().with_file("replay.details", &details));
+        let manifest = HashMap::from([
+            (
+                "replay.details".to_string(),
+                <[u8; 16]>::from(Md5::digest(&details)),
+            ),
+            (
+                "replay.game.events".to_string(),
+                <[u8; 16]>::from(Md5::digest(b"replay.game.events")),
+            ),
+            ("replay.missing".to_string(), [0u8; 16]),
+        ]);
+        assert_eq!(
+            mpq.verify_against_manifest(&archive, &manifest),
+            vec![
+                ("replay.details".to_string(), true),
+                ("replay.game.events".to_string(), false),
+                ("replay.missing".to_string(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_reads_file_verified() {
+        let details = b"replay.details contents ".repeat(100);
+        let archive = MPQBuilder::new()
+            .with_file("replay.details", &details)
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        let crc = crc32fast::hash(&details);
+        assert_eq!(
+            mpq.read_file_verified("replay.details", crc, &archive)
+                .unwrap(),
+            details
+        );
+        assert!(matches!(
+            mpq.read_file_verified("replay.details", !crc, &archive),
+            Err(MPQParserError::ChecksumMismatch(filename)) if filename == "replay.details"
+        ));
+    }
+
+    #[test]
+    fn it_returns_error_for_unsupported_compression() {
+        assert!(matches!(
+            MPQ::decompress(&[0x04, 0x00, 0x06, 0x00]),
+            Err(MPQParserError::UnsupportedCompression(0x04))
+        ));
+        let (mut archive, mpq) = events_archive(MPQBuilder::new());
+        let block_table_index = mpq
+            .get_hash_table_entry("replay.game.events", None)
+            .unwrap()
+            .block_table_index;
+        let block_entry = mpq.get_block_table_entry(block_table_index).unwrap();
+        let positions = mpq
+            .sector_offset_table(block_table_index, None, &archive)
+            .unwrap();
+        // Change the compression type of the first sector.
+        archive[block_entry.offset as usize + positions[0]] = 0x04;
+        assert!(matches!(
+            mpq.read_mpq_file_sector("replay.game.events", false, &archive),
+            Err(MPQParserError::UnsupportedCompression(0x04))
+        ));
+    }
+
+    #[test]
+    fn it_consumes_whole_sector_when_decompressing() {
+        use flate2::write::ZlibEncoder;
+        use std::io::Write;
+        let sector_contents = b"replay.game.events".repeat(100);
+        let mut encoder = ZlibEncoder::new(vec![COMPRESSION_ZLIB], flate2::Compression::default());
+        encoder.write_all(&sector_contents).unwrap();
+        let sector = encoder.finish().unwrap();
+        let (tail, data) = MPQ::decompress(&sector).unwrap();
+        assert_eq!(data, sector_contents);
+        assert!(tail.is_empty());
+        let (tail, data) = MPQ::decompress(&[COMPRESSION_PLAINTEXT, b'a', b'b']).unwrap();
+        assert_eq!(data, b"ab");
+        assert!(tail.is_empty());
+    }
+
+    #[test]
+    fn it_reads_imploded_file() {
+        // The example from blast.c, imploding "AIAIAIAIAIAIA".
+        let imploded = [0x00, 0x04, 0x82, 0x24, 0x25, 0x8f, 0x80, 0x7f];
+        let (_tail, file_data) =
+            MPQ::decompress(&[&[COMPRESSION_PKWARE], &imploded[..]].concat()).unwrap();
+        assert_eq!(file_data, b"AIAIAIAIAIAIA");
+        let archive = MPQBuilder::new()
+            .with_file_entry(MPQBuilderFile {
+                flags: MPQ_FILE_EXISTS | MPQ_FILE_SINGLE_UNIT,
+                ..MPQBuilderFile::new("imploded.txt", &imploded)
+            })
+            .write()
+            .unwrap();
+        let (_tail, mut mpq) = parser::parse(&archive).unwrap();
+        let block_table_index = mpq
+            .get_hash_table_entry("imploded.txt", None)
+            .unwrap()
+            .block_table_index as usize;
+        mpq.block_table_entries[block_table_index].flags |= MPQ_FILE_IMPLODE;
+        mpq.block_table_entries[block_table_index].size = 13;
+        let (_tail, file_data) = mpq
+            .read_mpq_file_sector("imploded.txt", false, &archive)
+            .unwrap();
+        assert_eq!(file_data, b"AIAIAIAIAIAIA");
+    }
+
+    #[test]
+    fn it_strips_prefix_of_compressed_file_without_savings() {
+        // A compressed file whose sectors gained nothing, stored with the
+        // plaintext prefix, the archived size is then bigger than the file size.
+        let file_contents: Vec<u8> = (0..1024u32).map(|i| (i * 37 % 251) as u8).collect();
+        let mut single_unit = vec![COMPRESSION_PLAINTEXT];
+        single_unit.extend_from_slice(&file_contents);
+        let archive = MPQBuilder::new()
+            .with_file_entry(MPQBuilderFile {
+                flags: MPQ_FILE_EXISTS | MPQ_FILE_SINGLE_UNIT,
+                ..MPQBuilderFile::new("single.unit", &single_unit)
+            })
+            .write()
+            .unwrap();
+        let (_tail, mut mpq) = parser::parse(&archive).unwrap();
+        let block_table_index = mpq
+            .get_hash_table_entry("single.unit", None)
+            .unwrap()
+            .block_table_index as usize;
+        mpq.block_table_entries[block_table_index].flags |= MPQ_FILE_COMPRESS;
+        mpq.block_table_entries[block_table_index].size = file_contents.len() as u32;
+        let (_tail, file_data) = mpq
+            .read_mpq_file_sector("single.unit", false, &archive)
+            .unwrap();
+        assert_eq!(file_data, file_contents);
+        // The same data split in two prefixed sectors of 512 bytes.
+        let table_size = 12u32;
+        let positions = [table_size, table_size + 513, table_size + 2 * 513];
+        let mut orig_input: Vec<u8> = positions.iter().flat_map(|x| x.to_le_bytes()).collect();
+        for sector in file_contents.chunks(512) {
+            orig_input.push(COMPRESSION_PLAINTEXT);
+            orig_input.extend_from_slice(sector);
+        }
+        let builder = MPQBuilder::new();
+        let mpq = MPQBuilder::new()
+            .with_archive_header(MPQFileHeader {
+                hash_table_entries: 1,
+                block_table_entries: 1,
+                ..Default::default()
+            })
+            .with_hash_table(vec![MPQHashTableEntry::new(
+                builder
+                    .mpq_string_hash("sectors.file", MPQHashType::HashA)
+                    .unwrap(),
+                builder
+                    .mpq_string_hash("sectors.file", MPQHashType::HashB)
+                    .unwrap(),
+                0,
+                0,
+                0,
+            )])
+            .with_block_table(vec![MPQBlockTableEntry::new(
+                0,
+                orig_input.len() as u32,
+                file_contents.len() as u32,
+                MPQ_FILE_EXISTS | MPQ_FILE_COMPRESS,
+            )])
+            .build(&orig_input)
+            .unwrap();
+        let (_tail, file_data) = mpq
+            .read_mpq_file_sector("sectors.file", false, &orig_input)
+            .unwrap();
+        assert_eq!(file_data, file_contents);
+    }
+
+    #[test]
+    fn it_reads_cached_file() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let events = test_events();
+        let (archive, mut mpq) = events_archive(MPQBuilder::new());
+        let decompressions = Arc::new(AtomicUsize::new(0));
+        let counter = decompressions.clone();
+        mpq.register_decompressor(COMPRESSION_ZLIB, move |data| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let (_tail, res) = MPQ::decompress(&[&[COMPRESSION_ZLIB], data].concat())?;
+            Ok(res)
+        });
+        let mut cache = DecompressedCache::new(4);
+        let file_data = mpq
+            .read_file_cached("replay.game.events", &archive, &mut cache)
+            .unwrap();
+        assert_eq!(file_data, events);
+        let first_read_decompressions = decompressions.load(Ordering::SeqCst);
+        assert!(first_read_decompressions > 0);
+        let file_data = mpq
+            .read_file_cached("replay.game.events", &archive, &mut cache)
+            .unwrap();
+        assert_eq!(file_data, events);
+        assert_eq!(
+            decompressions.load(Ordering::SeqCst),
+            first_read_decompressions
+        );
+        assert_eq!(cache.len(), 1);
+        // The fingerprint is computed once, the input is only hashed on first use.
+        assert_eq!(
+            mpq.archive_fingerprint(&archive),
+            mpq.archive_fingerprint(&[])
+        );
+    }
+
+    #[test]
+    fn it_caches_files_by_contents() {
+        let player_one = MPQBuilder::new()
+            .with_file("replay.details", b"player one wins!")
+            .write()
+            .unwrap();
+        let player_two = MPQBuilder::new()
+            .with_file("replay.details", b"player two wins!")
+            .write()
+            .unwrap();
+        let (_tail, player_one_mpq) = parser::parse(&player_one).unwrap();
+        let (_tail, player_two_mpq) = parser::parse(&player_two).unwrap();
+        // Same names and sizes, the tables are the same.
+        assert_eq!(
+            player_one_mpq.tables_fingerprint(),
+            player_two_mpq.tables_fingerprint()
+        );
+        let mut cache = DecompressedCache::new(4);
+        assert_eq!(
+            player_one_mpq
+                .read_file_cached("replay.details", &player_one, &mut cache)
+                .unwrap(),
+            b"player one wins!"
+        );
+        assert_eq!(
+            player_two_mpq
+                .read_file_cached("replay.details", &player_two, &mut cache)
+                .unwrap(),
+            b"player two wins!"
+        );
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn it_decompresses_combined_compressions() {
+        use flate2::write::ZlibEncoder;
+        use std::io::Write;
+        // The ADPCM stage is replaced by reversing the bytes, it is applied first
+        // when compressing so it must be undone after zlib.
+        let sector_contents = b"replay.game.events".repeat(100);
+        let reversed: Vec<u8> = sector_contents.iter().rev().copied().collect();
+        let mut encoder = ZlibEncoder::new(
+            vec![COMPRESSION_ZLIB | COMPRESSION_ADPCM_MONO],
+            flate2::Compression::default(),
+        );
+        encoder.write_all(&reversed).unwrap();
+        let sector = encoder.finish().unwrap();
+        let mut registry = DecompressorRegistry::new();
+        registry.register(COMPRESSION_ADPCM_MONO, |data| {
+            Ok(data.iter().rev().copied().collect())
+        });
+        let (tail, data) = MPQ::decompress_with_registry(&sector, &registry, usize::MAX).unwrap();
+        assert_eq!(data, sector_contents);
+        assert!(tail.is_empty());
+        // The built-in ADPCM decompression, undone after zlib.
+        let adpcm_sector = [0x00, 0x01, 0x00, 0x00, 0x80, 0x81, 0x05, 0x41];
+        let mut encoder = ZlibEncoder::new(
+            vec![COMPRESSION_ZLIB | COMPRESSION_ADPCM_MONO],
+            flate2::Compression::default(),
+        );
+        encoder.write_all(&adpcm_sector).unwrap();
+        let sector = encoder.finish().unwrap();
+        let (_tail, data) = MPQ::decompress(&sector).unwrap();
+        let pcm_samples: Vec<u8> = [0i16, 0, 1684, -65]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        assert_eq!(data, pcm_samples);
+        // The built-in sparse decompression, undone after zlib.
+        let sparse_sector = [0x00, 0x00, 0x00, 0x06, 0x80, 0x01, 0x02];
+        let mut encoder = ZlibEncoder::new(
+            vec![COMPRESSION_ZLIB | COMPRESSION_SPARSE],
+            flate2::Compression::default(),
+        );
+        encoder.write_all(&sparse_sector).unwrap();
+        let sector = encoder.finish().unwrap();
+        let (_tail, data) = MPQ::decompress(&sector).unwrap();
+        assert_eq!(data, [0x01, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        // Bits that are not part of any compression.
+        assert!(matches!(
+            MPQ::decompress(&[COMPRESSION_ZLIB | 0x04, 0x00]),
+            Err(MPQParserError::UnsupportedCompression(0x06))
+        ));
+    }
+
+    #[test]
+    fn it_returns_error_for_truncated_sector() {
+        use flate2::write::ZlibEncoder;
+        use std::io::Write;
+        let mut encoder = ZlibEncoder::new(vec![COMPRESSION_ZLIB], flate2::Compression::default());
+        encoder
+            .write_all(&b"replay.game.events".repeat(100))
+            .unwrap();
+        let sector = encoder.finish().unwrap();
+        assert!(MPQ::decompress(&sector).is_ok());
+        assert!(matches!(
+            MPQ::decompress(&sector[..sector.len() / 2]),
+            Err(MPQParserError::IoError(_))
+        ));
+        let mut bz2_sector = vec![COMPRESSION_BZ2];
+        bz2_sector.extend_from_slice(b"BZh9\x31\x41\x59\x26\x53\x59");
+        assert!(matches!(
+            MPQ::decompress(&bz2_sector),
+            Err(MPQParserError::IoError(_))
+        ));
+    }
+
+    #[test]
+    fn it_reads_file_range() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let events: Vec<u8> = (0..480_000u32).map(|x| (x % 251) as u8).collect();
+        let archive = MPQBuilder::new()
+            .with_file("replay.game.events", &events)
+            .write()
+            .unwrap();
+        let (_tail, mut mpq) = parser::parse(&archive).unwrap();
+        let decompressions = Arc::new(AtomicUsize::new(0));
+        let counter = decompressions.clone();
+        mpq.register_decompressor(COMPRESSION_ZLIB, move |data| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let (_tail, res) = MPQ::decompress(&[&[COMPRESSION_ZLIB], data].concat())?;
+            Ok(res)
+        });
+        let file_data = mpq
+            .read_file_range("replay.game.events", 10000..20000, &archive)
+            .unwrap();
+        assert_eq!(file_data, &events[10000..20000]);
+        // Only the sectors 2 to 4 of 4096 bytes are decompressed.
+        assert_eq!(decompressions.load(Ordering::SeqCst), 3);
+        let file_data = mpq
+            .read_file_range("replay.game.events", 479_990..500_000, &archive)
+            .unwrap();
+        assert_eq!(file_data, &events[479_990..]);
+        assert!(mpq
+            .read_file_range("replay.game.events", 500_000..500_010, &archive)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn it_reads_encrypted_file() {
+        // 18001 bytes span 5 sectors of 4096 bytes, the last one not filling a
+        // 4 byte word.
+        let events: Vec<u8> = (0..18_001u32).map(|x| (x % 251) as u8).collect();
+        let archive = MPQBuilder::new()
+            .with_file_entry(MPQBuilderFile {
+                flags: MPQ_FILE_EXISTS | MPQ_FILE_COMPRESS | MPQ_FILE_ENCRYPTED,
+                ..MPQBuilderFile::new("replay.game.events", &events)
+            })
+            .with_file_entry(MPQBuilderFile {
+                flags: MPQ_FILE_EXISTS | MPQ_FILE_ENCRYPTED | MPQ_FILE_SINGLE_UNIT,
+                ..MPQBuilderFile::new("replay.details", b"Hello, World!")
+            })
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        let (_tail, file_data) = mpq
+            .read_mpq_file_sector("replay.game.events", false, &archive)
+            .unwrap();
+        assert_eq!(file_data, events);
+        let (_tail, file_data) = mpq
+            .read_mpq_file_sector("replay.details", false, &archive)
+            .unwrap();
+        assert_eq!(file_data, b"Hello, World!");
+        let file_data = mpq
+            .read_file_range("replay.game.events", 10000..18001, &archive)
+            .unwrap();
+        assert_eq!(file_data, &events[10000..]);
+    }
+
+    #[test]
+    fn it_reads_file_with_fixed_key() {
+        let details = b"replay.details contents ".repeat(10);
+        let archive = MPQBuilder::new()
+            .with_file_entry(MPQBuilderFile {
+                flags: MPQ_FILE_EXISTS | MPQ_FILE_SINGLE_UNIT,
+                ..MPQBuilderFile::new("replay.details", &details)
+            })
+            .write()
+            .unwrap();
+        let (_tail, mut mpq) = parser::parse(&archive).unwrap();
+        mpq.block_table_entries[0].flags |= MPQ_FILE_ENCRYPTED | MPQ_FILE_FIX_KEY;
+        let block_entry = mpq.block_table_entries[0].clone();
+        let file_key = mpq
+            .compute_file_key("replay.details", &block_entry)
+            .unwrap();
+        let base_key =
+            MPQ::mpq_string_hash(&mpq.encryption_table, "replay.details", MPQHashType::Table)
+                .unwrap();
+        assert_eq!(
+            file_key,
+            base_key.wrapping_add(block_entry.offset) ^ block_entry.size
+        );
+        // Encrypt the block in place with the adjusted key.
+        let offset = block_entry.offset as usize + mpq.archive_header.offset;
+        let (_tail, encrypted_details) =
+            MPQ::mpq_data_encrypt(&mpq.encryption_table, &details, file_key).unwrap();
+        let mut encrypted_archive = archive.clone();
+        encrypted_archive[offset..offset + details.len()].copy_from_slice(&encrypted_details);
+        let (_tail, file_data) = mpq
+            .read_mpq_file_sector("replay.details", false, &encrypted_archive)
+            .unwrap();
+        assert_eq!(file_data, details);
+    }
+
+    #[test]
+    fn it_borrows_plaintext_file() {
+        let details = b"replay.details contents ".repeat(100);
+        let archive = MPQBuilder::new()
+            .with_file_entry(MPQBuilderFile {
+                flags: MPQ_FILE_EXISTS | MPQ_FILE_SINGLE_UNIT,
+                ..MPQBuilderFile::new("replay.details", &details)
+            })
+            .with_file_entry(MPQBuilderFile {
+                flags: MPQ_FILE_EXISTS | MPQ_FILE_COMPRESS | MPQ_FILE_SINGLE_UNIT,
+                ..MPQBuilderFile::new("replay.initData", &details)
+            })
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        let file_data = mpq.read_file_cow("replay.details", &archive).unwrap();
+        assert!(matches!(file_data, Cow::Borrowed(_)));
+        assert_eq!(file_data, &details[..]);
+        let file_data = mpq.read_file_cow("replay.initData", &archive).unwrap();
+        assert!(matches!(file_data, Cow::Owned(_)));
+        assert_eq!(file_data, &details[..]);
+    }
+
+    #[test]
+    fn it_reads_single_unit_and_multi_sector_files() {
+        let details = b"replay.details contents ".repeat(1000);
+        let archive = MPQBuilder::new()
+            .with_file_entry(MPQBuilderFile {
+                flags: MPQ_FILE_EXISTS | MPQ_FILE_COMPRESS | MPQ_FILE_SINGLE_UNIT,
+                ..MPQBuilderFile::new("replay.details", &details)
+            })
+            .with_file("replay.game.events", &details)
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        let single_unit_entry = &mpq.block_table_entries[0];
+        assert_ne!(single_unit_entry.flags & MPQ_FILE_SINGLE_UNIT, 0);
+        assert!(single_unit_entry.archived_size < single_unit_entry.size);
+        let (_tail, file_data) = mpq
+            .read_mpq_file_sector("replay.details", false, &archive)
+            .unwrap();
+        assert_eq!(file_data, details);
+        // 24000 bytes span 6 sectors of 4096 bytes.
+        let multi_sector_entry = &mpq.block_table_entries[1];
+        assert_eq!(multi_sector_entry.flags & MPQ_FILE_SINGLE_UNIT, 0);
+        let positions = mpq.sector_offset_table(1, None, &archive).unwrap();
+        assert_eq!(positions.len(), 7);
+        let (_tail, file_data) = mpq
+            .read_mpq_file_sector("replay.game.events", false, &archive)
+            .unwrap();
+        assert_eq!(file_data, details);
+    }
+
+    #[test]
+    fn it_detects_file_type() {
+        let nested_archive = MPQBuilder::new()
+            .with_file("replay.details", b"replay.details contents")
+            .write()
+            .unwrap();
+        let archive = MPQBuilder::new()
+            .with_file("nested.SC2Replay", &nested_archive)
+            .with_file("texture.blp", b"BLP2\x01\x02\x00\x00")
+            .with_file("binary.dat", &[0x00, 0xFF, 0xFE, 0x80])
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        for (filename, file_type) in [
+            ("(listfile)", FileType::Text),
+            ("nested.SC2Replay", FileType::Mpq),
+            ("texture.blp", FileType::Blp),
+            ("binary.dat", FileType::Binary),
+        ] {
+            assert_eq!(mpq.detect_file_type(filename, &archive).unwrap(), file_type);
+        }
+        // A multi-byte character cut by the end of the peeked bytes is still text.
+        assert_eq!(FileType::detect(&"é".as_bytes()[..1]), FileType::Text);
+    }
+
+    #[test]
+    fn it_returns_undecrypted_tail() {
+        let encryption_table = MPQ::shared_encryption_table();
+        let data: Vec<u8> = (0..10u8).collect();
+        let (tail, encrypted_data) = MPQ::mpq_data_encrypt(&encryption_table, &data, 42).unwrap();
+        assert_eq!(encrypted_data.len(), 8);
+        assert_eq!(tail, &data[8..]);
+        // Aligned input is consumed entirely.
+        let (tail, decrypted_data) =
+            MPQ::mpq_data_decrypt(&encryption_table, &encrypted_data, 42).unwrap();
+        assert!(tail.is_empty());
+        assert_eq!(decrypted_data, &data[..8]);
+        // The unaligned bytes are left as the remaining input.
+        let unaligned_data = [&encrypted_data[..], &data[8..]].concat();
+        let (tail, decrypted_data) =
+            MPQ::mpq_data_decrypt(&encryption_table, &unaligned_data, 42).unwrap();
+        assert_eq!(tail, &data[8..]);
+        assert_eq!(decrypted_data, &data[..8]);
+    }
+
+    #[test]
+    fn it_fails_to_encrypt_without_encryption_table() {
+        let data: Vec<u8> = (0..8u8).collect();
+        assert!(matches!(
+            MPQ::mpq_data_encrypt(&HashMap::new(), &data, 42),
+            Err(MPQParserError::EncryptionTableIndexNotFound)
+        ));
+    }
+
+    #[test]
+    fn it_prepares_encryption_table() {
+        let encryption_table = MPQ::prepare_encryption_table();
+        assert_eq!(encryption_table.len(), 0x500);
+        let builder = MPQBuilder::new();
+        for (idx, value) in [
+            (0x000, 0x55C636E2),
+            (0x001, 0x02BE0170),
+            (0x100, 0x76F8C1B1),
+            (0x4FF, 0x7303286C),
+        ] {
+            assert_eq!(encryption_table[&idx], value);
+            assert_eq!(builder.encryption_table[&idx], value);
+        }
+    }
+
+    #[test]
+    fn it_returns_error_for_unhashable_filename() {
+        let archive = MPQBuilder::new()
+            .with_file("replay.details", b"replay.details contents")
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        assert!(matches!(
+            MPQ::mpq_string_hash(&mpq.encryption_table, "replay€.details", MPQHashType::HashA),
+            Err(MPQParserError::HashCharOutOfRange('€'))
+        ));
+        assert!(matches!(
+            mpq.get_hash_table_entry("replay€.details", None),
+            Err(MPQParserError::HashCharOutOfRange('€'))
+        ));
+        assert!(matches!(
+            MPQBuilder::new().mpq_string_hash("replay€.details", MPQHashType::Table),
+            Err(MPQParserError::HashCharOutOfRange('€'))
+        ));
+        // Characters within a byte are hashed.
+        assert!(mpq
+            .get_hash_table_entry("replayé.details", None)
+            .is_err_and(|err| matches!(err, MPQParserError::HashTableEntryNotFound(_))));
+    }
+
+    #[test]
+    fn it_iterates_files_past_invalid_lines() {
+        let archive = MPQBuilder::new()
+            .with_file("replay.details", b"replay.details contents")
+            .with_file("replay.initData", b"replay.initData contents")
+            .with_listfile(false)
+            .with_file(
+                "(listfile)",
+                b"replay.details\r\nreplay\xFF.bad\r\nunknown.file\r\nreplay.initData\r\n",
+            )
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        let files: Vec<_> = mpq.files(&archive).collect();
+        assert_eq!(files.len(), 3);
+        assert_eq!(
+            files[0].as_ref().unwrap(),
+            &("replay.details".to_string(), 23)
+        );
+        assert!(matches!(
+            files[1],
+            Err(MPQParserError::InvalidUTF8Sequence(_))
+        ));
+        assert_eq!(
+            files[2].as_ref().unwrap(),
+            &("replay.initData".to_string(), 24)
+        );
+        assert!(matches!(
+            mpq.get_files(&archive),
+            Err(MPQParserError::InvalidUTF8Sequence(_))
+        ));
+        // The same files are visited, the error is reported at the end.
+        let mut visited = vec![];
+        assert!(matches!(
+            mpq.for_each_file(&archive, |filename, size| visited
+                .push((filename.to_string(), size))),
+            Err(MPQParserError::InvalidUTF8Sequence(_))
+        ));
+        let valid_files: Vec<_> = files.into_iter().flatten().collect();
+        assert_eq!(visited, valid_files);
+        assert_eq!(
+            mpq.list_files(&archive).unwrap_err().to_string(),
+            "Invalid UTF-8 Sequence on section (listfile)"
+        );
+    }
+
+    #[test]
+    fn it_returns_error_for_missing_or_invalid_listfile() {
+        let archive = MPQBuilder::new()
+            .with_file("replay.details", b"replay.details contents")
+            .with_listfile(false)
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        assert!(matches!(
+            mpq.get_files(&archive),
+            Err(MPQParserError::InvalidListFileSector)
+        ));
+        assert!(matches!(
+            mpq.for_each_file(&archive, |_, _| {}),
+            Err(MPQParserError::InvalidListFileSector)
+        ));
+        let archive = MPQBuilder::new()
+            .with_file("replay.details", b"replay.details contents")
+            .with_listfile(false)
+            .with_file("(listfile)", b"replay\xE9.details\r\n")
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        assert!(matches!(
+            mpq.get_files(&archive),
+            Err(MPQParserError::InvalidUTF8Sequence(_))
+        ));
+        assert!(matches!(
+            mpq.for_each_file(&archive, |_, _| {}),
+            Err(MPQParserError::InvalidUTF8Sequence(_))
+        ));
+    }
+
+    #[test]
+    fn it_extracts_all_to_nested_directories() {
+        let archive = MPQBuilder::new()
+            .with_file("Base.SC2Data\\GameData\\Units.xml", b"<Units/>")
+            .with_file("..\\escape.txt", b"escape")
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        let out_dir = std::env::temp_dir().join(format!("nom-mpq-nested-{}", std::process::id()));
+        let mut paths = mpq.extract_all_to(&archive, &out_dir).unwrap();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![out_dir
+                .join("Base.SC2Data")
+                .join("GameData")
+                .join("Units.xml"),]
+        );
+        assert_eq!(std::fs::read(&paths[0]).unwrap(), b"<Units/>");
+        std::fs::remove_dir_all(&out_dir).unwrap();
+    }
+
+    #[test]
+    fn it_skips_names_escaping_the_extraction_directory() {
+        let archive = MPQBuilder::new()
+            .with_file("..\\..\\x", b"escape")
+            .with_file("a/../../x", b"escape")
+            .with_file("/abs/x", b"escape")
+            .with_file("\\abs\\x", b"escape")
+            .with_file("./x", b"escape")
+            .with_file("a/b\\c.txt", b"nested")
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        let root = std::env::temp_dir().join(format!("nom-mpq-escape-{}", std::process::id()));
+        let out_dir = root.join("out");
+        let paths = mpq.extract_all_to(&archive, &out_dir).unwrap();
+        assert_eq!(paths, vec![out_dir.join("a").join("b").join("c.txt")]);
+        // Nothing was written beside the extraction directory.
+        let entries: Vec<_> = std::fs::read_dir(&root)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(entries, vec![std::ffi::OsString::from("out")]);
+        assert!(!Path::new("/abs/x").exists());
+        assert_eq!(MPQ::extraction_path(&out_dir, ""), None);
+        assert_eq!(
+            MPQ::extraction_path(&out_dir, "a\\\\"),
+            Some(out_dir.join("a"))
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn it_checks_file_existence_and_size() {
+        let archive = MPQBuilder::new()
+            .with_file("replay.details", b"replay.details contents")
+            .with_file("replay.initData", b"replay.initData contents")
+            .write()
+            .unwrap();
+        let (_tail, mut mpq) = parser::parse(&archive).unwrap();
+        assert!(mpq.file_exists("replay.details"));
+        assert_eq!(mpq.file_size("replay.details"), Some(23));
+        assert!(!mpq.file_exists("replay.message.events"));
+        assert_eq!(mpq.file_size("replay.message.events"), None);
+        // The file is marked as deleted.
+        let hash_entry = mpq.get_hash_table_entry("replay.initData", None).unwrap();
+        mpq.block_table_entries[hash_entry.block_table_index as usize].flags |=
+            MPQ_FILE_DELETE_MARKER;
+        assert!(!mpq.file_exists("replay.initData"));
+        assert_eq!(mpq.file_size("replay.initData"), None);
+        // The block doesn't exist.
+        let hash_entry = mpq.get_hash_table_entry("replay.details", None).unwrap();
+        mpq.block_table_entries[hash_entry.block_table_index as usize].flags &= !MPQ_FILE_EXISTS;
+        assert!(!mpq.file_exists("replay.details"));
+    }
+
+    #[test]
+    fn it_lists_files_without_resolving_them() {
+        let archive = MPQBuilder::new()
+            .with_file("replay.details", b"replay.details contents")
+            .with_file("replay.initData", b"replay.initData contents")
+            .with_listfile(false)
+            .with_file(
+                "(listfile)",
+                b"replay.details\r\nreplay.initData\nreplay.missing\r\n",
+            )
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        assert_eq!(
+            mpq.list_files(&archive).unwrap(),
+            vec!["replay.details", "replay.initData", "replay.missing"]
+        );
+        // Names not in the hash table are only skipped when resolving their sizes.
+        assert_eq!(mpq.get_files(&archive).unwrap().len(), 2);
+        let archive = MPQBuilder::new()
+            .with_file("replay.details", b"replay.details contents")
+            .with_listfile(false)
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        assert!(matches!(
+            mpq.list_files(&archive),
+            Err(MPQParserError::InvalidListFileSector)
+        ));
+    }
+
+    #[test]
+    fn it_verifies_weak_signature() {
+        let archive = MPQBuilder::new()
+            .with_file("replay.details", b"replay.details contents")
+            .with_file_entry(MPQBuilderFile {
+                flags: MPQ_FILE_EXISTS | MPQ_FILE_SINGLE_UNIT,
+                ..MPQBuilderFile::new(
+                    "(signature)",
+                    &[0u8; signature::MPQ_WEAK_SIGNATURE_FILE_SIZE],
+                )
+            })
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        // Sign the archive with the test key.
+        let digest = mpq.weak_signature_md5(&archive).unwrap();
+        let mut weak_signature = signature::tests::test_sign(&digest);
+        weak_signature.reverse();
+        let hash_entry = mpq.get_hash_table_entry("(signature)", None).unwrap();
+        let signature_offset =
+            mpq.block_table_entries[hash_entry.block_table_index as usize].offset as usize + 8;
+        let mut signed_archive = archive.clone();
+        signed_archive[signature_offset..signature_offset + signature::MPQ_WEAK_SIGNATURE_SIZE]
+            .copy_from_slice(&weak_signature);
+        assert!(mpq
+            .verify_weak_signature_with_key(&signed_archive, &signature::tests::TEST_PUBLIC_KEY)
+            .unwrap());
+        assert!(!mpq.verify_weak_signature(&signed_archive).unwrap());
+        // Tamper with the contents of a file.
+        let hash_entry = mpq.get_hash_table_entry("replay.details", None).unwrap();
+        let details_offset =
+            mpq.block_table_entries[hash_entry.block_table_index as usize].offset as usize;
+        let mut tampered_archive = signed_archive.clone();
+        tampered_archive[details_offset] ^= 0xff;
+        assert!(!mpq
+            .verify_weak_signature_with_key(&tampered_archive, &signature::tests::TEST_PUBLIC_KEY)
+            .unwrap());
+        // An archive size beyond the input.
+        let (_tail, mut mpq) = parser::parse(&signed_archive).unwrap();
+        mpq.archive_header.offset = 1;
+        mpq.archive_header.archive_size_64 = Some(u64::MAX);
+        assert!(matches!(
+            mpq.weak_signature_md5(&signed_archive),
+            Err(MPQParserError::OffsetOutOfBounds(u64::MAX))
+        ));
+        // Archives without signature.
+        let archive = MPQBuilder::new()
+            .with_file("replay.details", b"replay.details contents")
+            .write()
+            .unwrap();
+        let (_tail, mpq) = parser::parse(&archive).unwrap();
+        assert!(matches!(
+            mpq.verify_weak_signature(&archive),
+            Err(MPQParserError::HashTableEntryNotFound(_))
+        ));
+    }
 }
@@ -65,7 +65,7 @@ fn main() {
                     .user_data
                     .as_ref()
                     .expect("Unable to get user data, not provided in MPQ Archive");
-                for word in &user_data.content {
+                for word in user_data.content.as_bytes() {
                     let bytes = word.to_le_bytes();
                     let _ = std::io::stdout().write_all(&bytes);
                 }
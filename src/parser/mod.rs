@@ -9,26 +9,51 @@
 
 use crate::{MPQParserError, MPQResult};
 
+#[cfg(feature = "tokio")]
+use super::OwnedMpq;
 use super::{MPQBuilder, MPQ};
 use nom::bytes::complete::{tag, take};
 use nom::error::dbg_dmp;
 use nom::multi::count;
+use nom::number::complete::u32;
 use nom::number::Endianness;
 use std::convert::From;
 use std::convert::TryFrom;
 use std::fs::File;
 use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::Path;
 
+pub mod mpq_attributes;
+pub mod mpq_bet_table;
 pub mod mpq_block_table_entry;
 pub mod mpq_file_header;
 pub mod mpq_file_header_ext;
+pub mod mpq_file_header_v4;
 pub mod mpq_hash_table_entry;
+pub mod mpq_het_table;
+pub mod mpq_hi_block_table;
 pub mod mpq_user_data;
+pub use mpq_attributes::AttributeFlags;
+pub use mpq_attributes::MPQAttributes;
+pub use mpq_attributes::{
+    MPQ_ATTRIBUTES_VERSION, MPQ_ATTRIBUTE_CRC32, MPQ_ATTRIBUTE_FILETIME, MPQ_ATTRIBUTE_MD5,
+    MPQ_ATTRIBUTE_PATCH_BIT,
+};
+pub use mpq_bet_table::{MPQBetTable, MPQBetTableEntry, MPQ_BET_TABLE_SIGNATURE};
+pub use mpq_block_table_entry::BlockFlags;
+pub use mpq_block_table_entry::MPQBlockFlags;
 pub use mpq_block_table_entry::MPQBlockTableEntry;
 pub use mpq_file_header::MPQFileHeader;
+pub use mpq_file_header::MPQVersion;
 pub use mpq_file_header_ext::MPQFileHeaderExt;
+pub use mpq_file_header_v4::{MPQFileHeaderV4, MPQ_HEADER_MD5_COVERED_SIZE, MPQ_MD5_DIGEST_SIZE};
 pub use mpq_hash_table_entry::MPQHashTableEntry;
+pub use mpq_hash_table_entry::{MPQ_HASH_ENTRY_DELETED, MPQ_HASH_ENTRY_EMPTY};
+pub use mpq_het_table::{MPQHetTable, MPQ_HET_TABLE_SIGNATURE};
+pub use mpq_hi_block_table::MPQHiBlockTable;
 pub use mpq_user_data::MPQUserData;
+pub use mpq_user_data::{ReplayHeader, ReplayVersion, UserDataContent};
 
 /// Final byte of the magic to identify particularly the Archive Header.
 pub const MPQ_ARCHIVE_HEADER_TYPE: u8 = 0x1a;
@@ -38,6 +63,22 @@ pub const MPQ_USER_DATA_HEADER_TYPE: u8 = 0x1b;
 pub const LITTLE_ENDIAN: Endianness = Endianness::Little;
 /// The characters used as displayable by [`peek_hex`]
 pub static CHARS: &[u8] = b"0123456789abcdef";
+/// Archives embedded or concatenated in other files start at a multiple of
+/// this many bytes.
+pub const MPQ_ARCHIVE_ALIGNMENT: usize = 512;
+/// The number of bytes after the [`MPQUserData::archive_header_offset`] in which
+/// the archive header is searched for when it is not exactly at the offset.
+pub const MPQ_HEADER_SCAN_WINDOW: usize = 64;
+/// The size of the archive header in the original format, the smallest valid
+/// [`MPQFileHeader::header_size`].
+pub const MPQ_HEADER_MIN_SIZE: u32 = 0x20;
+/// The exclusive maximum number of hash or block table entries in the original format.
+pub const MPQ_V1_MAX_TABLE_ENTRIES: u32 = 1 << 16;
+/// The exclusive maximum number of hash or block table entries since the Burning
+/// Crusade format.
+pub const MPQ_MAX_TABLE_ENTRIES: u32 = 1 << 20;
+/// The maximum width in bits of a field of the HET and BET tables, see [`read_bits`].
+pub const MPQ_MAX_BIT_FIELD_SIZE: u32 = 64;
 
 /// Validates the first three bytes of the magic, it must be followed by either the
 /// [`MPQ_ARCHIVE_HEADER_TYPE`] or the [`MPQ_USER_DATA_HEADER_TYPE`]
@@ -162,23 +203,55 @@ pub fn get_header_type(input: &[u8]) -> MPQResult<&[u8], MPQSectionType> {
     Ok((input, mpq_type))
 }
 
+/// Whether the input starts with the magic of a User Data header.
+pub fn is_user_data_header(input: &[u8]) -> bool {
+    matches!(get_header_type(input), Ok((_, MPQSectionType::UserData)))
+}
+
+/// Whether the input starts with the magic of an Archive Header.
+pub fn is_archive_header(input: &[u8]) -> bool {
+    matches!(get_header_type(input), Ok((_, MPQSectionType::Header)))
+}
+
 /// Reads the file headers, headers must contain the Archive File Header
 /// but they may optionally contain the User Data Headers.
 #[tracing::instrument(level = "trace", skip(input), fields(input = peek_hex(input)))]
 pub fn read_headers(input: &[u8]) -> MPQResult<&[u8], (MPQFileHeader, Option<MPQUserData>)> {
+    let orig_input = input;
+    let at_offset = |err| MPQParserError::from_nom_at(orig_input, err);
     let mut user_data: Option<MPQUserData> = None;
     let (input, mpq_type) = get_header_type(input)?;
     let (input, archive_header) = match mpq_type {
         MPQSectionType::UserData => {
-            let (input, parsed_user_data) = MPQUserData::parse(input)?;
+            let (input, parsed_user_data) = MPQUserData::parse(input).map_err(at_offset)?;
             let header_offset = parsed_user_data.archive_header_offset;
             user_data = Some(parsed_user_data);
-            // If there is user data, it must be immediately followed by the Archive Header
-            let (input, mpq_type) = get_header_type(input)?;
-            assert!(MPQSectionType::Header == mpq_type);
-            MPQFileHeader::parse(input, header_offset as usize)?
+            // If there is user data, it must be immediately followed by the Archive Header,
+            // but corrupt files may have it a few bytes after the declared offset.
+            let header_magic = [b'M', b'P', b'Q', MPQ_ARCHIVE_HEADER_TYPE];
+            let skipped = match (0..=MPQ_HEADER_SCAN_WINDOW.min(input.len()))
+                .find(|skipped| input[*skipped..].starts_with(&header_magic))
+            {
+                Some(val) => val,
+                None => {
+                    tracing::error!(
+                        "Unable to find the archive header at offset {}",
+                        header_offset
+                    );
+                    return MPQResult::Err(MPQParserError::MissingArchiveHeader);
+                }
+            };
+            if skipped > 0 {
+                tracing::warn!(
+                    "Archive header found {} bytes after the declared offset {}",
+                    skipped,
+                    header_offset
+                );
+            }
+            let (input, _mpq_type) = get_header_type(&input[skipped..])?;
+            MPQFileHeader::parse(input, header_offset as usize + skipped).map_err(at_offset)?
         }
-        MPQSectionType::Header => MPQFileHeader::parse(input, 0)?,
+        MPQSectionType::Header => MPQFileHeader::parse(input, 0).map_err(at_offset)?,
         MPQSectionType::Unknown => {
             tracing::error!("Unable to identify magic/section-type combination");
             return MPQResult::Err(MPQParserError::MissingArchiveHeader);
@@ -187,18 +260,13 @@ pub fn read_headers(input: &[u8]) -> MPQResult<&[u8], (MPQFileHeader, Option<MPQ
     Ok((input, (archive_header, user_data)))
 }
 
-/// Parses the whole input into an MPQ
-pub fn parse(orig_input: &[u8]) -> MPQResult<&[u8], MPQ> {
-    let builder = MPQBuilder::new();
+/// Decrypts and parses the hash table entries.
+fn parse_hash_table(
+    builder: &MPQBuilder,
+    encrypted_hash_table_data: &[u8],
+    hash_table_entries: u32,
+) -> Result<Vec<MPQHashTableEntry>, MPQParserError> {
     let hash_table_key = builder.mpq_string_hash("(hash table)", MPQHashType::Table)?;
-    let block_table_key = builder.mpq_string_hash("(block table)", MPQHashType::Table)?;
-    let (tail, (archive_header, user_data)) = read_headers(orig_input)?;
-    // "seek" to the hash table offset.
-    let hash_table_offset = archive_header.hash_table_offset as usize + archive_header.offset;
-    let (_, encrypted_hash_table_data) = dbg_dmp(
-        take(16usize * archive_header.hash_table_entries as usize),
-        "encrypted_hash_table_data",
-    )(&orig_input[hash_table_offset..])?;
     let decrypted_hash_table_data =
         match builder.mpq_data_decrypt(encrypted_hash_table_data, hash_table_key) {
             Ok((_, value)) => value,
@@ -214,46 +282,631 @@ pub fn parse(orig_input: &[u8]) -> MPQResult<&[u8], MPQ> {
                 ));
             }
         };
-    let (_, hash_table_entries) = match count(
-        MPQHashTableEntry::parse,
-        archive_header.hash_table_entries as usize,
-    )(&decrypted_hash_table_data)
-    {
-        Ok((tail, value)) => (tail, value),
+    let res =
+        count(MPQHashTableEntry::parse, hash_table_entries as usize)(&decrypted_hash_table_data);
+    match res {
+        Ok((_tail, value)) => Ok(value),
+        Err(err) => {
+            tracing::error!("Unable to use decrypted data: {:?}", err);
+            Err(MPQParserError::IncompleteData)
+        }
+    }
+}
+
+/// Decrypts and parses the block table entries.
+fn parse_block_table(
+    builder: &MPQBuilder,
+    encrypted_block_table_data: &[u8],
+    block_table_entries: u32,
+) -> Result<Vec<MPQBlockTableEntry>, MPQParserError> {
+    let block_table_key = builder.mpq_string_hash("(block table)", MPQHashType::Table)?;
+    let (_, decrypted_block_table_data) =
+        builder.mpq_data_decrypt(encrypted_block_table_data, block_table_key)?;
+    let res =
+        count(MPQBlockTableEntry::parse, block_table_entries as usize)(&decrypted_block_table_data);
+    match res {
+        Ok((_tail, value)) => Ok(value),
         Err(err) => {
             tracing::error!("Unable to use decrypted data: {:?}", err);
-            return Err(MPQParserError::IncompleteData);
+            Err(MPQParserError::IncompleteData)
         }
+    }
+}
+
+/// Returns the input starting at `offset`, failing with
+/// [`MPQParserError::IncompleteData`] when the offset is beyond its end, i.e. the
+/// offsets of a corrupt archive.
+pub fn input_at(input: &[u8], offset: usize) -> Result<&[u8], MPQParserError> {
+    input.get(offset..).ok_or(MPQParserError::IncompleteData)
+}
+
+/// Reads `bit_count` bits, up to [`MPQ_MAX_BIT_FIELD_SIZE`], at `bit_offset` of a
+/// little-endian bit array, as used by the HET and BET tables.
+/// Returns `None` when the bits are beyond the end of the array or don't fit a `u64`.
+pub fn read_bits(data: &[u8], bit_offset: usize, bit_count: u32) -> Option<u64> {
+    if bit_count > MPQ_MAX_BIT_FIELD_SIZE {
+        return None;
+    }
+    let mut res = 0u64;
+    for bit in 0..bit_count as usize {
+        let position = bit_offset + bit;
+        let byte = data.get(position / 8)?;
+        res |= (((byte >> (position % 8)) & 1) as u64) << bit;
+    }
+    Some(res)
+}
+
+/// Reads the common header of an extended table at `table_offset` and decrypts the
+/// rest of the table, see [`MPQHetTable`] and [`MPQBetTable`].
+fn read_ext_table(
+    builder: &MPQBuilder,
+    orig_input: &[u8],
+    table_offset: u64,
+    signature: u32,
+    key_name: &str,
+) -> Result<Vec<u8>, MPQParserError> {
+    let input = orig_input
+        .get(table_offset as usize..)
+        .ok_or(MPQParserError::OffsetOutOfBounds(table_offset))?;
+    let (input, _signature) = dbg_dmp(tag(signature.to_le_bytes()), "signature")(input)?;
+    let (input, _version) = dbg_dmp(u32(LITTLE_ENDIAN), "version")(input)?;
+    let (input, data_size) = dbg_dmp(u32(LITTLE_ENDIAN), "data_size")(input)?;
+    let (_, encrypted_data) = dbg_dmp(take(data_size), "encrypted_data")(input)?;
+    let key = builder.mpq_string_hash(key_name, MPQHashType::Table)?;
+    let (unaligned_tail, mut res) = builder.mpq_data_decrypt(encrypted_data, key)?;
+    res.extend_from_slice(unaligned_tail);
+    Ok(res)
+}
+
+/// Parses the HET and BET tables of the Cataclysm formats, when their offsets are set.
+fn parse_het_bet_tables(
+    builder: &MPQBuilder,
+    orig_input: &[u8],
+    archive_header: &MPQFileHeader,
+) -> Result<(Option<MPQHetTable>, Option<MPQBetTable>), MPQParserError> {
+    let het_table = match archive_header.het_table_pos {
+        Some(het_table_pos) if het_table_pos != 0 => {
+            let het_table_data = read_ext_table(
+                builder,
+                orig_input,
+                archive_header.offset as u64 + het_table_pos,
+                MPQ_HET_TABLE_SIGNATURE,
+                "(hash table)",
+            )?;
+            let (_, het_table) = MPQHetTable::parse(&het_table_data)?;
+            Some(het_table)
+        }
+        _ => None,
     };
+    let bet_table = match archive_header.bet_table_pos {
+        Some(bet_table_pos) if bet_table_pos != 0 => {
+            let bet_table_data = read_ext_table(
+                builder,
+                orig_input,
+                archive_header.offset as u64 + bet_table_pos,
+                MPQ_BET_TABLE_SIGNATURE,
+                "(block table)",
+            )?;
+            let (_, bet_table) = MPQBetTable::parse(&bet_table_data)?;
+            Some(bet_table)
+        }
+        _ => None,
+    };
+    Ok((het_table, bet_table))
+}
+
+/// Converts the entries of the BET table into block table entries, along with the
+/// hi-block table when a file is beyond 4GB, for the archives without a block table.
+fn bet_block_table(bet_table: &MPQBetTable) -> (Vec<MPQBlockTableEntry>, Option<Vec<u16>>) {
+    let block_table_entries = bet_table
+        .entries
+        .iter()
+        .map(|entry| MPQBlockTableEntry {
+            offset: entry.file_pos as u32,
+            archived_size: entry.cmp_size as u32,
+            size: entry.file_size as u32,
+            flags: entry.flags,
+        })
+        .collect();
+    let hi_block_table = if bet_table
+        .entries
+        .iter()
+        .any(|entry| entry.file_pos > u32::MAX as u64)
+    {
+        Some(
+            bet_table
+                .entries
+                .iter()
+                .map(|entry| (entry.file_pos >> 32) as u16)
+                .collect(),
+        )
+    } else {
+        None
+    };
+    (block_table_entries, hi_block_table)
+}
+
+/// Validates the hash and block tables are within the archive, a table beyond the
+/// end of the archive means it is corrupt.
+fn validate_table_bounds(
+    archive_header: &MPQFileHeader,
+    input_len: u64,
+) -> Result<(), MPQParserError> {
+    // The archive size is only reliable in the original format.
+    let archive_size = if archive_header.version() == MPQVersion::V1Original {
+        archive_header.archive_size as u64
+    } else {
+        archive_header.effective_archive_size()
+    };
+    let archive_end = (archive_header.offset as u64)
+        .checked_add(archive_size)
+        .map_or(input_len, |archive_end| archive_end.min(input_len));
+    for (table_offset, table_entries) in [
+        (
+            archive_header.hash_table_pos(),
+            archive_header.hash_table_entries,
+        ),
+        (
+            archive_header.block_table_pos(),
+            archive_header.block_table_entries,
+        ),
+    ] {
+        let table_end = (archive_header.offset as u64)
+            .checked_add(table_offset)
+            .and_then(|table_start| table_start.checked_add(16u64 * table_entries as u64))
+            .ok_or(MPQParserError::OffsetOutOfBounds(table_offset))?;
+        if table_end > archive_end {
+            tracing::error!(
+                "Table at offset {} ends at {}, beyond the end of the archive at {}",
+                table_offset,
+                table_end,
+                archive_end
+            );
+            return Err(MPQParserError::OffsetOutOfBounds(table_end));
+        }
+    }
+    Ok(())
+}
+
+/// Validates the declared size of the archive header fits the fields of its format
+/// version, protected archives set it to bogus values, see [`parse_protected`].
+fn validate_header_size(archive_header: &MPQFileHeader) -> Result<(), MPQParserError> {
+    let min_size = archive_header
+        .version()
+        .header_size()
+        .max(MPQ_HEADER_MIN_SIZE);
+    if archive_header.header_size < min_size {
+        tracing::error!(
+            "Invalid archive header size {} for format version {}, expected at least {}",
+            archive_header.header_size,
+            archive_header.format_version,
+            min_size
+        );
+        return Err(MPQParserError::InvalidHeaderSize(
+            archive_header.header_size,
+        ));
+    }
+    Ok(())
+}
+
+/// Validates the number of hash and block table entries is below the maximum of the
+/// format version, so a corrupt count doesn't cause huge allocations.
+fn validate_table_entries(archive_header: &MPQFileHeader) -> Result<(), MPQParserError> {
+    let max_entries = if archive_header.version() == MPQVersion::V1Original {
+        MPQ_V1_MAX_TABLE_ENTRIES
+    } else {
+        MPQ_MAX_TABLE_ENTRIES
+    };
+    if archive_header.hash_table_entries >= max_entries {
+        tracing::error!(
+            "Hash table of {} entries exceeds the maximum of {}",
+            archive_header.hash_table_entries,
+            max_entries
+        );
+        return Err(MPQParserError::InvalidHashTableSize(
+            archive_header.hash_table_entries,
+        ));
+    }
+    if archive_header.block_table_entries >= max_entries {
+        tracing::error!(
+            "Block table of {} entries exceeds the maximum of {}",
+            archive_header.block_table_entries,
+            max_entries
+        );
+        return Err(MPQParserError::InvalidBlockTableSize(
+            archive_header.block_table_entries,
+        ));
+    }
+    Ok(())
+}
+
+/// The offset in the file of the hi-block table, only present in the Burning
+/// Crusade format and later when the extended block table offset is set.
+fn hi_block_table_offset(archive_header: &MPQFileHeader) -> Option<u64> {
+    match archive_header.extended_file_header {
+        Some(extended_file_header) if extended_file_header.extended_block_table_offset != 0 => {
+            Some(
+                extended_file_header.extended_block_table_offset as u64
+                    + archive_header.offset as u64,
+            )
+        }
+        _ => None,
+    }
+}
+
+/// Finds where the archive starts in an input that doesn't start with the magic,
+/// i.e. when a shebang or some other text wrapper precedes the archive.
+/// The archive must start at a multiple of [`MPQ_ARCHIVE_ALIGNMENT`], when no magic
+/// is found at any boundary the input is assumed to start with the archive.
+fn find_archive_start(input: &[u8]) -> usize {
+    let is_magic_at = |offset: &usize| {
+        matches!(
+            input[*offset..],
+            [
+                b'M',
+                b'P',
+                b'Q',
+                MPQ_ARCHIVE_HEADER_TYPE | MPQ_USER_DATA_HEADER_TYPE,
+                ..
+            ]
+        )
+    };
+    if is_magic_at(&0) {
+        return 0;
+    }
+    match (MPQ_ARCHIVE_ALIGNMENT..input.len())
+        .step_by(MPQ_ARCHIVE_ALIGNMENT)
+        .find(is_magic_at)
+    {
+        Some(archive_start) => {
+            tracing::debug!("Skipping a preamble of {} bytes", archive_start);
+            archive_start
+        }
+        None => 0,
+    }
+}
+
+/// Parses the whole input into an MPQ.
+/// A preamble before the archive is skipped, see [`find_archive_start`], the
+/// offsets of the [`MPQ`] remain relative to the start of the input.
+pub fn parse(orig_input: &[u8]) -> MPQResult<&[u8], MPQ> {
+    let builder = MPQBuilder::new();
+    let archive_start = find_archive_start(orig_input);
+    let (tail, (mut archive_header, user_data)) = read_headers(&orig_input[archive_start..])
+        .map_err(|err| match err {
+            MPQParserError::ByteAlignedAt { offset, message } => MPQParserError::ByteAlignedAt {
+                offset: archive_start + offset,
+                message,
+            },
+            err => err,
+        })?;
+    archive_header.offset += archive_start;
+    validate_header_size(&archive_header)?;
+    validate_table_entries(&archive_header)?;
+    validate_table_bounds(&archive_header, orig_input.len() as u64)?;
+    // "seek" to the hash table offset.
+    let hash_table_offset = archive_header.hash_table_pos() as usize + archive_header.offset;
+    let (_, encrypted_hash_table_data) = dbg_dmp(
+        take(16usize * archive_header.hash_table_entries as usize),
+        "encrypted_hash_table_data",
+    )(input_at(orig_input, hash_table_offset)?)
+    .map_err(|err| MPQParserError::from_nom_at(orig_input, err))?;
+    let hash_table_entries = parse_hash_table(
+        &builder,
+        encrypted_hash_table_data,
+        archive_header.hash_table_entries,
+    )?;
     // "seek" to the block table offset.
-    let block_table_offset = archive_header.block_table_offset as usize + archive_header.offset;
+    let block_table_offset = archive_header.block_table_pos() as usize + archive_header.offset;
     let (_, encrypted_block_table_data) = dbg_dmp(
         take(16usize * archive_header.block_table_entries as usize),
         "encrypted_block_table_data",
-    )(&orig_input[block_table_offset..])?;
-    let (_, decrypted_block_table_data) =
-        builder.mpq_data_decrypt(encrypted_block_table_data, block_table_key)?;
-    let (_, block_table_entries) = match count(
-        MPQBlockTableEntry::parse,
-        archive_header.block_table_entries as usize,
-    )(&decrypted_block_table_data)
-    {
-        Ok((tail, value)) => (tail, value),
-        Err(err) => {
-            tracing::error!("Unable to use decrypted data: {:?}", err);
-            return Err(MPQParserError::IncompleteData);
+    )(input_at(orig_input, block_table_offset)?)
+    .map_err(|err| MPQParserError::from_nom_at(orig_input, err))?;
+    let block_table_entries = parse_block_table(
+        &builder,
+        encrypted_block_table_data,
+        archive_header.block_table_entries,
+    )?;
+    let hi_block_table = match hi_block_table_offset(&archive_header) {
+        Some(hi_block_table_offset) => {
+            let hi_block_table_data = orig_input
+                .get(hi_block_table_offset as usize..)
+                .ok_or(MPQParserError::OffsetOutOfBounds(hi_block_table_offset))?;
+            let (_, hi_block_table) = MPQHiBlockTable::parse(
+                hi_block_table_data,
+                archive_header.block_table_entries as usize,
+            )?;
+            Some(hi_block_table)
+        }
+        None => None,
+    };
+    let (het_table, bet_table) = parse_het_bet_tables(&builder, orig_input, &archive_header)?;
+    let (block_table_entries, hi_block_table) = match &bet_table {
+        Some(bet_table) if block_table_entries.is_empty() => bet_block_table(bet_table),
+        _ => (block_table_entries, hi_block_table),
+    };
+    let mpq = builder
+        .with_archive_header(archive_header)
+        .with_user_data(user_data)
+        .with_hash_table(hash_table_entries)
+        .with_block_table(block_table_entries)
+        .with_hi_block_table(hi_block_table)
+        .with_het_table(het_table)
+        .with_bet_table(bet_table)
+        .build(orig_input)?;
+    Ok((tail, mpq))
+}
+
+/// Parses the archive on the blocking pool of tokio, the parsing is CPU-bound so it
+/// must not run on the async workers.
+/// The contents are moved into the returned [`OwnedMpq`], along with the [`MPQ`].
+#[cfg(feature = "tokio")]
+pub async fn parse_async(bytes: Vec<u8>) -> Result<OwnedMpq, MPQParserError> {
+    let task = tokio::task::spawn_blocking(move || {
+        let (_tail, mpq) = parse(&bytes)?;
+        Ok(OwnedMpq {
+            archive: bytes,
+            mpq,
+        })
+    });
+    match task.await {
+        Ok(res) => res,
+        Err(err) => match err.try_into_panic() {
+            Ok(panic) => std::panic::resume_unwind(panic),
+            Err(err) => Err(MPQParserError::IoError(std::io::Error::other(err))),
+        },
+    }
+}
+
+/// Reads `len` bytes at `offset` of the reader.
+fn read_region<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    len: usize,
+) -> Result<Vec<u8>, MPQParserError> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut buffer = vec![0u8; len];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+/// Parses the headers and the hash and block tables from a reader.
+///
+/// Unlike [`parse`], only the regions of the headers and the tables are read, so
+/// opening a big archive to inspect its metadata doesn't require loading it whole.
+/// The files can then be read from the same reader with [`MPQ::read_file_from_reader`].
+pub fn parse_reader<R: Read + Seek>(reader: &mut R) -> Result<MPQ, MPQParserError> {
+    let builder = MPQBuilder::new();
+    // The magic and the user data fields, up to the archive header offset.
+    let mut headers_input = vec![];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.by_ref().take(16).read_to_end(&mut headers_input)?;
+    let (input, section_type) = get_header_type(&headers_input)?;
+    let archive_header_offset = match section_type {
+        MPQSectionType::UserData => {
+            let (_, (_user_data_size, archive_header_offset)) = nom::sequence::pair(
+                MPQUserData::parse_user_data_size,
+                MPQUserData::parse_archive_header_offset,
+            )(input)?;
+            archive_header_offset as u64
+        }
+        _ => 0,
+    };
+    // The archive header, up to the MD5 digests of the format version 4.
+    let headers_len =
+        archive_header_offset + MPQ_HEADER_SCAN_WINDOW as u64 + MPQVersion::V4.header_size() as u64;
+    reader.seek(SeekFrom::Start(0))?;
+    headers_input.clear();
+    reader
+        .by_ref()
+        .take(headers_len)
+        .read_to_end(&mut headers_input)?;
+    let (_tail, (archive_header, user_data)) = read_headers(&headers_input)?;
+    let input_len = reader.seek(SeekFrom::End(0))?;
+    validate_header_size(&archive_header)?;
+    validate_table_entries(&archive_header)?;
+    validate_table_bounds(&archive_header, input_len)?;
+    let hash_table_offset = archive_header.hash_table_pos() + archive_header.offset as u64;
+    let encrypted_hash_table_data = read_region(
+        reader,
+        hash_table_offset,
+        16usize * archive_header.hash_table_entries as usize,
+    )?;
+    let hash_table_entries = parse_hash_table(
+        &builder,
+        &encrypted_hash_table_data,
+        archive_header.hash_table_entries,
+    )?;
+    let block_table_offset = archive_header.block_table_pos() + archive_header.offset as u64;
+    let encrypted_block_table_data = read_region(
+        reader,
+        block_table_offset,
+        16usize * archive_header.block_table_entries as usize,
+    )?;
+    let block_table_entries = parse_block_table(
+        &builder,
+        &encrypted_block_table_data,
+        archive_header.block_table_entries,
+    )?;
+    let hi_block_table = match hi_block_table_offset(&archive_header) {
+        Some(hi_block_table_offset) => {
+            let hi_block_table_data = read_region(
+                reader,
+                hi_block_table_offset,
+                2usize * archive_header.block_table_entries as usize,
+            )?;
+            let (_, hi_block_table) = MPQHiBlockTable::parse(
+                &hi_block_table_data,
+                archive_header.block_table_entries as usize,
+            )?;
+            Some(hi_block_table)
         }
+        None => None,
     };
+    builder
+        .with_archive_header(archive_header)
+        .with_user_data(user_data)
+        .with_hash_table(hash_table_entries)
+        .with_block_table(block_table_entries)
+        .with_hi_block_table(hi_block_table)
+        .build(&headers_input)
+}
+
+/// Whether a decrypted hash table entry is plausible for an archive with
+/// `block_table_entries` blocks.
+fn is_plausible_hash_table_entry(entry: &MPQHashTableEntry, block_table_entries: u32) -> bool {
+    entry.is_empty() || entry.is_deleted() || entry.block_table_index < block_table_entries
+}
+
+/// Whether a decrypted block table entry is plausible for an archive of
+/// `archive_size` bytes after the archive header offset.
+fn is_plausible_block_table_entry(entry: &MPQBlockTableEntry, archive_size: u64) -> bool {
+    let known_flags = crate::MPQ_FILE_IMPLODE
+        | crate::MPQ_FILE_COMPRESS
+        | crate::MPQ_FILE_ENCRYPTED
+        | crate::MPQ_FILE_FIX_KEY
+        | crate::MPQ_FILE_SINGLE_UNIT
+        | crate::MPQ_FILE_DELETE_MARKER
+        | crate::MPQ_FILE_SECTOR_CRC
+        | crate::MPQ_FILE_EXISTS;
+    entry.flags & !known_flags == 0
+        && (entry.flags == 0 || entry.flags & crate::MPQ_FILE_EXISTS != 0)
+        && entry.offset as u64 + entry.archived_size as u64 <= archive_size
+}
+
+/// Finds a table of `table_entries` entries that decrypts to plausible entries.
+///
+/// The declared offset is tried first, then every offset after the archive header.
+/// Since the decryption of an entry only depends on the previous ones, the first
+/// entry is decrypted on its own to discard most of the candidates cheaply.
+fn find_protected_table<T>(
+    archive_input: &[u8],
+    declared_offset: usize,
+    table_entries: u32,
+    parse_table: impl Fn(&[u8]) -> Result<Vec<T>, MPQParserError>,
+    is_plausible: impl Fn(&T) -> bool,
+) -> Result<(usize, Vec<T>), MPQParserError> {
+    let table_size = 16usize * table_entries as usize;
+    if table_entries == 0 || table_size > archive_input.len() {
+        return Err(MPQParserError::IncompleteData);
+    }
+    let candidates = std::iter::once(declared_offset)
+        .chain(MPQ_HEADER_MIN_SIZE as usize..=archive_input.len() - table_size);
+    for offset in candidates {
+        let Some(table_data) = archive_input.get(offset..offset + table_size) else {
+            continue;
+        };
+        match parse_table(&table_data[..16]) {
+            Ok(first_entry) if first_entry.iter().all(&is_plausible) => {}
+            _ => continue,
+        }
+        if let Ok(table) = parse_table(table_data) {
+            if table.iter().all(&is_plausible) {
+                return Ok((offset, table));
+            }
+        }
+    }
+    Err(MPQParserError::IncompleteData)
+}
+
+/// Parses an archive whose header was altered by map-protection tools.
+///
+/// Protectors set a bogus [`MPQFileHeader::header_size`] and table offsets to break
+/// naive parsers, while the game still opens the archive. The declared header size
+/// is ignored and the hash and block tables are searched for heuristically, a table
+/// is accepted when all of its decrypted entries are plausible. The entry counts of
+/// the header are trusted, and the hi-block table is not read.
+/// The found table offsets are stored in the returned [`MPQ::archive_header`].
+pub fn parse_protected(orig_input: &[u8]) -> MPQResult<&[u8], MPQ> {
+    let builder = MPQBuilder::new();
+    let (tail, (mut archive_header, user_data)) = read_headers(orig_input)?;
+    let archive_input = &orig_input[archive_header.offset..];
+    let archive_size = archive_input.len() as u64;
+    let block_table_entries_count = archive_header.block_table_entries;
+    let (hash_table_offset, hash_table_entries) = find_protected_table(
+        archive_input,
+        archive_header.hash_table_offset as usize,
+        archive_header.hash_table_entries,
+        |data| parse_hash_table(&builder, data, (data.len() / 16) as u32),
+        |entry| is_plausible_hash_table_entry(entry, block_table_entries_count),
+    )?;
+    let (block_table_offset, block_table_entries) = find_protected_table(
+        archive_input,
+        archive_header.block_table_offset as usize,
+        archive_header.block_table_entries,
+        |data| parse_block_table(&builder, data, (data.len() / 16) as u32),
+        |entry| is_plausible_block_table_entry(entry, archive_size),
+    )?;
+    if hash_table_offset != archive_header.hash_table_offset as usize
+        || block_table_offset != archive_header.block_table_offset as usize
+    {
+        tracing::warn!(
+            "Protected archive tables found at hash table offset {} and block table offset {}",
+            hash_table_offset,
+            block_table_offset
+        );
+    }
+    archive_header.hash_table_offset = hash_table_offset as u32;
+    archive_header.block_table_offset = block_table_offset as u32;
     let mpq = builder
         .with_archive_header(archive_header)
         .with_user_data(user_data)
         .with_hash_table(hash_table_entries)
         .with_block_table(block_table_entries)
-        .build(orig_input)
-        .unwrap();
+        .build(orig_input)?;
     Ok((tail, mpq))
 }
 
+/// Parses every archive found in a file that concatenates several MPQs.
+///
+/// The input is scanned for the `MPQ` magic at [`MPQ_ARCHIVE_ALIGNMENT`] boundaries,
+/// each archive found is parsed and returned along with the offset at which it starts.
+/// Offsets inside each [`MPQ`] are relative to its starting offset, so further reads
+/// should be done on `&input[offset..]`.
+/// Failing to parse an archive doesn't stop the scan, the error is returned in its
+/// position and the scan continues at the next boundary.
+pub fn parse_all(input: &[u8]) -> Vec<Result<(usize, MPQ), MPQParserError>> {
+    let mut res = vec![];
+    let mut offset = 0usize;
+    while offset < input.len() {
+        if validate_magic(&input[offset..]).is_err() {
+            offset += MPQ_ARCHIVE_ALIGNMENT;
+            continue;
+        }
+        match parse(&input[offset..]) {
+            Ok((_tail, mpq)) => {
+                let archive_end = usize::try_from(mpq.archive_header.effective_archive_size())
+                    .ok()
+                    .and_then(|archive_size| archive_size.checked_add(mpq.archive_header.offset))
+                    .and_then(|archive_end| archive_end.checked_add(offset))
+                    .map_or(input.len(), |archive_end| archive_end.min(input.len()));
+                tracing::debug!("Found archive at offset {offset}, ending at {archive_end}");
+                res.push(Ok((offset, mpq)));
+                // Continue the scan at the next boundary after the archive.
+                offset = archive_end
+                    .max(offset + 1)
+                    .checked_next_multiple_of(MPQ_ARCHIVE_ALIGNMENT)
+                    .unwrap_or(input.len());
+            }
+            Err(err) => {
+                tracing::warn!("Unable to parse archive at offset {offset}: {:?}", err);
+                res.push(Err(err));
+                offset += MPQ_ARCHIVE_ALIGNMENT;
+            }
+        }
+    }
+    res
+}
+
+/// Convenience function to open an archive and extract a single file from it.
+pub fn extract_one<P: AsRef<Path>>(path: P, name: &str) -> Result<Vec<u8>, MPQParserError> {
+    let file_contents = std::fs::read(path)?;
+    let (_tail, mpq) = parse(&file_contents)?;
+    let (_tail, file_data) = mpq.read_mpq_file_sector(name, false, &file_contents)?;
+    Ok(file_data)
+}
+
 /// Convenience function to read a file to parse, mostly for testing.
 pub fn read_file(path: &str) -> Vec<u8> {
     let mut f = File::open(path).unwrap();
@@ -265,7 +918,9 @@ pub fn read_file(path: &str) -> Vec<u8> {
 
 #[cfg(test)]
 mod tests {
+    use super::mpq_bet_table::tests::basic_bet_table;
     use super::mpq_file_header::tests::basic_file_header;
+    use super::mpq_het_table::tests::basic_het_table;
     use super::mpq_user_data::tests::basic_user_header;
     use super::*;
     use test_log::test;
@@ -280,6 +935,15 @@ mod tests {
             read_headers(&user_data_header_input).unwrap();
         assert!(user_data_header.is_some());
     }
+
+    #[test]
+    fn it_reads_bits() {
+        let data = [0xff; 9];
+        assert_eq!(read_bits(&data, 4, 8), Some(0xff));
+        assert_eq!(read_bits(&data, 8, 64), Some(u64::MAX));
+        assert_eq!(read_bits(&data, 16, 64), None);
+        assert_eq!(read_bits(&data, 0, 65), None);
+    }
     #[test]
     fn it_generates_hashes() {
         let builder = MPQBuilder::new();
@@ -337,6 +1001,10 @@ mod tests {
             .mpq_data_decrypt(&encrypted_hash_table_data, hash_table_key)
             .unwrap();
         assert_eq!(decrypted_entries, decrypted_hash_table_data);
+        let (_, encrypted_entries) = builder
+            .mpq_data_encrypt(&decrypted_hash_table_data, hash_table_key)
+            .unwrap();
+        assert_eq!(encrypted_entries, encrypted_hash_table_data);
         let encrypted_block_table_data = vec![
             0xa7, 0x67, 0x48, 0x3d, 0x7a, 0xd1, 0x08, 0xca, 0x4c, 0xbc, 0x35, 0xf8, 0x06, 0x04,
             0x34, 0xe9, 0xbe, 0xb3, 0xb5, 0xb3, 0x7d, 0xeb, 0x0e, 0x11, 0x05, 0xb9, 0xf4, 0x17,
@@ -369,5 +1037,369 @@ mod tests {
             .mpq_data_decrypt(&encrypted_block_table_data, block_table_key)
             .unwrap();
         assert_eq!(decrypted_entries, decrypted_block_table_data);
+        let (_, encrypted_entries) = builder
+            .mpq_data_encrypt(&decrypted_block_table_data, block_table_key)
+            .unwrap();
+        assert_eq!(encrypted_entries, encrypted_block_table_data);
+    }
+
+    #[test]
+    fn it_parses_concatenated_archives() {
+        let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
+        let archive = read_file(file_path);
+        let mut input = archive.clone();
+        // Pad the first archive up to the next boundary.
+        input.resize(archive.len().next_multiple_of(MPQ_ARCHIVE_ALIGNMENT), 0);
+        let second_offset = input.len();
+        input.extend_from_slice(&archive);
+        let archives = parse_all(&input);
+        assert_eq!(archives.len(), 2);
+        let (first_offset, first_mpq) = archives[0].as_ref().unwrap();
+        assert_eq!(*first_offset, 0);
+        let (offset, second_mpq) = archives[1].as_ref().unwrap();
+        assert_eq!(*offset, second_offset);
+        assert_eq!(
+            first_mpq.block_table_entries,
+            second_mpq.block_table_entries
+        );
+        assert_eq!(
+            first_mpq.get_files(&input).unwrap(),
+            second_mpq.get_files(&input[*offset..]).unwrap()
+        );
+    }
+
+    #[test]
+    fn it_parses_from_reader() {
+        for file_path in [
+            concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay"),
+            concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/assets/SC2-Patch_4.12-2v2AI.SC2Replay"
+            ),
+        ] {
+            let file_contents = read_file(file_path);
+            let (_tail, mpq) = parse(&file_contents).unwrap();
+            let mut reader = std::io::Cursor::new(&file_contents);
+            let mut streamed_mpq = parse_reader(&mut reader).unwrap();
+            assert_eq!(streamed_mpq.archive_header, mpq.archive_header);
+            assert_eq!(streamed_mpq.user_data, mpq.user_data);
+            assert_eq!(streamed_mpq.hash_table_entries, mpq.hash_table_entries);
+            assert_eq!(streamed_mpq.block_table_entries, mpq.block_table_entries);
+            for (filename, _size) in mpq.get_files(&file_contents).unwrap() {
+                let (_tail, file_data) = mpq
+                    .read_mpq_file_sector(&filename, false, &file_contents)
+                    .unwrap();
+                assert_eq!(
+                    streamed_mpq
+                        .read_file_from_reader(&filename, &mut reader)
+                        .unwrap(),
+                    file_data
+                );
+            }
+            // A block beyond the end of the reader.
+            let block_table_index = streamed_mpq
+                .get_hash_table_entry("(listfile)", None)
+                .unwrap()
+                .block_table_index;
+            streamed_mpq.block_table_entries[block_table_index as usize].archived_size = u32::MAX;
+            assert!(matches!(
+                streamed_mpq.read_file_from_reader("(listfile)", &mut reader),
+                Err(MPQParserError::IncompleteData)
+            ));
+        }
+    }
+
+    #[test]
+    fn it_identifies_header_kinds() {
+        for file_path in [
+            concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay"),
+            concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/assets/SC2-Patch_4.12-2v2AI.SC2Replay"
+            ),
+        ] {
+            let file_contents = read_file(file_path);
+            assert!(is_user_data_header(&file_contents));
+            assert!(!is_archive_header(&file_contents));
+            let (_tail, mpq) = parse(&file_contents).unwrap();
+            let archive_input = &file_contents[mpq.archive_header.offset..];
+            assert!(is_archive_header(archive_input));
+            assert!(!is_user_data_header(archive_input));
+        }
+        assert!(!is_user_data_header(b"MP"));
+        assert!(!is_archive_header(b"MPQ\x1c"));
+    }
+
+    #[test]
+    fn it_scans_for_misaligned_archive_header() {
+        let mut archive_input = basic_user_header();
+        archive_input.extend_from_slice(&[0x00, 0x00, 0x00]);
+        archive_input.append(&mut basic_file_header());
+        let (_input, (archive_header, user_data)) = read_headers(&archive_input).unwrap();
+        assert_eq!(user_data.unwrap().archive_header_offset, 0x18);
+        assert_eq!(archive_header.offset, 0x18 + 3);
+        assert_eq!(archive_header.hash_table_entries, 1);
+        // Beyond the scan window the archive header is missing.
+        let mut archive_input = basic_user_header();
+        archive_input.extend_from_slice(&[0x00; MPQ_HEADER_SCAN_WINDOW + 1]);
+        archive_input.append(&mut basic_file_header());
+        assert!(matches!(
+            read_headers(&archive_input),
+            Err(MPQParserError::MissingArchiveHeader)
+        ));
+    }
+
+    #[test]
+    fn it_validates_table_bounds() {
+        let mut archive = MPQBuilder::new()
+            .with_file("replay.details", b"replay.details contents")
+            .write()
+            .unwrap();
+        assert!(parse(&archive).is_ok());
+        // Move the hash table offset to the end of the archive.
+        let archive_size = archive.len() as u32;
+        archive[0x10..0x14].copy_from_slice(&archive_size.to_le_bytes());
+        assert!(matches!(
+            parse(&archive),
+            Err(MPQParserError::OffsetOutOfBounds(_))
+        ));
+        let mut reader = std::io::Cursor::new(&archive);
+        assert!(matches!(
+            parse_reader(&mut reader),
+            Err(MPQParserError::OffsetOutOfBounds(_))
+        ));
+    }
+
+    #[test]
+    fn it_validates_header_size_against_format_version() {
+        let parse_header = |format_version: u8, header_size: u8| {
+            let mut header = basic_file_header();
+            header[4] = header_size;
+            header[12] = format_version;
+            let (input, _header_type) = get_header_type(&header).unwrap();
+            let (_input, archive_header) = MPQFileHeader::parse(input, 0).unwrap();
+            validate_header_size(&archive_header)
+        };
+        assert!(parse_header(0, 0x20).is_ok());
+        assert!(parse_header(1, 0x2c).is_ok());
+        assert!(parse_header(3, 0xd0).is_ok());
+        assert!(matches!(
+            parse_header(1, 0x20),
+            Err(MPQParserError::InvalidHeaderSize(0x20))
+        ));
+        assert!(matches!(
+            parse_header(3, 0x2c),
+            Err(MPQParserError::InvalidHeaderSize(0x2c))
+        ));
+    }
+
+    #[test]
+    fn it_validates_table_entries() {
+        let archive = MPQBuilder::new()
+            .with_file("replay.details", b"replay.details contents")
+            .write()
+            .unwrap();
+        let mut oversized_hash_table = archive.clone();
+        oversized_hash_table[0x18..0x1c].copy_from_slice(&(1u32 << 21).to_le_bytes());
+        assert!(matches!(
+            parse(&oversized_hash_table),
+            Err(MPQParserError::InvalidHashTableSize(0x200000))
+        ));
+        let mut oversized_block_table = archive.clone();
+        oversized_block_table[0x1c..0x20].copy_from_slice(&(1u32 << 21).to_le_bytes());
+        assert!(matches!(
+            parse(&oversized_block_table),
+            Err(MPQParserError::InvalidBlockTableSize(0x200000))
+        ));
+        let mut reader = std::io::Cursor::new(&oversized_block_table);
+        assert!(matches!(
+            parse_reader(&mut reader),
+            Err(MPQParserError::InvalidBlockTableSize(0x200000))
+        ));
+    }
+
+    #[test]
+    fn it_parses_het_bet_tables() {
+        let builder = MPQBuilder::new();
+        let ext_table = |signature: u32, key_name: &str, data: &[u8]| {
+            let key = builder
+                .mpq_string_hash(key_name, MPQHashType::Table)
+                .unwrap();
+            let (unaligned_tail, mut encrypted_data) = builder.mpq_data_encrypt(data, key).unwrap();
+            encrypted_data.extend_from_slice(unaligned_tail);
+            let mut res = signature.to_le_bytes().to_vec();
+            res.extend(1u32.to_le_bytes()); // version
+            res.extend((data.len() as u32).to_le_bytes());
+            res.extend(encrypted_data);
+            res
+        };
+        let file_contents = b"hello";
+        let het_table = ext_table(MPQ_HET_TABLE_SIGNATURE, "(hash table)", &basic_het_table());
+        let bet_table = ext_table(MPQ_BET_TABLE_SIGNATURE, "(block table)", &basic_bet_table());
+        let het_table_pos = 0x44 + file_contents.len() as u64;
+        let bet_table_pos = het_table_pos + het_table.len() as u64;
+        let archive_size = bet_table_pos + bet_table.len() as u64;
+        // A Cataclysm archive without the classic hash and block tables.
+        let mut archive = vec![b'M', b'P', b'Q', MPQ_ARCHIVE_HEADER_TYPE];
+        archive.extend(0x44u32.to_le_bytes()); // header_size
+        archive.extend((archive_size as u32).to_le_bytes());
+        archive.extend(2u16.to_le_bytes()); // format_version
+        archive.extend(3u16.to_le_bytes()); // sector_size_shift
+        archive.extend([0u8; 16]); // hash and block table offsets and entries
+        archive.extend([0u8; 12]); // extended header
+        archive.extend(archive_size.to_le_bytes());
+        archive.extend(bet_table_pos.to_le_bytes());
+        archive.extend(het_table_pos.to_le_bytes());
+        archive.extend(file_contents);
+        archive.extend(het_table);
+        archive.extend(bet_table);
+        let (_tail, mpq) = parse(&archive).unwrap();
+        assert_eq!(mpq.archive_header.het_table_pos, Some(het_table_pos));
+        assert_eq!(mpq.archive_header.bet_table_pos, Some(bet_table_pos));
+        let het_table = mpq.het_table.as_ref().unwrap();
+        assert_eq!(het_table.bet_indexes, vec![0x0f, 0x00, 0x0f, 0x01]);
+        let bet_table = mpq.bet_table.as_ref().unwrap();
+        assert_eq!(bet_table.entries.len(), 2);
+        // The BET entries are used as the block table.
+        assert_eq!(mpq.block_table_entries.len(), 2);
+        assert_eq!(mpq.block_table_entries[1].archived_size, 0x0a);
+        assert_eq!(
+            mpq.read_file_by_block_index(0, &archive).unwrap(),
+            file_contents
+        );
+        assert!(mpq.required_capabilities(&archive).unwrap().het_bet_tables);
+        // The signature of the HET table is checked.
+        let mut invalid_signature = archive.clone();
+        invalid_signature[het_table_pos as usize] = b'X';
+        assert!(parse(&invalid_signature).is_err());
+    }
+
+    /// A format version 3 archive without tables whose 64-bit archive size is
+    /// `u64::MAX`, after a preamble of 512 bytes.
+    fn huge_archive_size_64() -> Vec<u8> {
+        let mut archive = vec![0u8; MPQ_ARCHIVE_ALIGNMENT];
+        archive.extend([b'M', b'P', b'Q', MPQ_ARCHIVE_HEADER_TYPE]);
+        archive.extend(0x44u32.to_le_bytes()); // header_size
+        archive.extend(0x44u32.to_le_bytes()); // archive_size
+        archive.extend(2u16.to_le_bytes()); // format_version
+        archive.extend(3u16.to_le_bytes()); // sector_size_shift
+        archive.extend(0x44u32.to_le_bytes()); // hash_table_offset
+        archive.extend(0x44u32.to_le_bytes()); // block_table_offset
+        archive.extend([0u8; 8]); // hash and block table entries
+        archive.extend([0u8; 12]); // extended header
+        archive.extend(u64::MAX.to_le_bytes()); // archive_size_64
+        archive.extend([0u8; 16]); // bet and het table positions
+        archive
+    }
+
+    #[test]
+    fn it_clamps_huge_archive_size_64() {
+        let archive = huge_archive_size_64();
+        let (_tail, mpq) = parse(&archive).unwrap();
+        assert_eq!(mpq.archive_header.offset, MPQ_ARCHIVE_ALIGNMENT);
+        assert_eq!(mpq.archive_header.archive_size_bytes(), u64::MAX);
+        let archives = parse_all(&archive);
+        assert_eq!(archives.len(), 1);
+        assert_eq!(archives[0].as_ref().unwrap().0, MPQ_ARCHIVE_ALIGNMENT);
+    }
+
+    #[test]
+    fn it_fails_to_parse_truncated_header() {
+        let archive = crate::builder::MPQBuilder::new().write().unwrap();
+        // The header ends before the fields of the hash and block tables.
+        for header_len in [4, 0x10, 0x1c] {
+            assert!(parse(&archive[..header_len]).is_err());
+        }
+    }
+
+    #[test]
+    fn it_parses_protected_archive() {
+        let file_contents = read_file("assets/mpyq-test.SC2Replay");
+        let (_tail, mpq) = parse(&file_contents).unwrap();
+        let header_offset = mpq.archive_header.offset;
+        let mut protected = file_contents.clone();
+        // Zero the header size and move the hash table offset past the archive.
+        protected[header_offset + 0x04..header_offset + 0x08].copy_from_slice(&[0; 4]);
+        protected[header_offset + 0x10..header_offset + 0x14]
+            .copy_from_slice(&0xdeadbeefu32.to_le_bytes());
+        assert!(matches!(
+            parse(&protected),
+            Err(MPQParserError::InvalidHeaderSize(0))
+        ));
+        let (_tail, protected_mpq) = parse_protected(&protected).unwrap();
+        assert_eq!(
+            protected_mpq.archive_header.hash_table_offset,
+            mpq.archive_header.hash_table_offset
+        );
+        assert_eq!(protected_mpq.hash_table_entries, mpq.hash_table_entries);
+        assert_eq!(protected_mpq.block_table_entries, mpq.block_table_entries);
+        assert_eq!(
+            protected_mpq
+                .read_mpq_file_sector("(listfile)", false, &protected)
+                .unwrap()
+                .1,
+            mpq.read_mpq_file_sector("(listfile)", false, &file_contents)
+                .unwrap()
+                .1
+        );
+    }
+
+    #[test]
+    fn it_skips_text_preamble() {
+        let file_contents = read_file("assets/mpyq-test.SC2Replay");
+        let (_tail, mpq) = parse(&file_contents).unwrap();
+        let mut wrapped = b"#!/usr/bin/env sc2replay\n# Wrapped replay\n".to_vec();
+        wrapped.resize(MPQ_ARCHIVE_ALIGNMENT, b' ');
+        wrapped.extend_from_slice(&file_contents);
+        let (_tail, wrapped_mpq) = parse(&wrapped).unwrap();
+        assert_eq!(
+            wrapped_mpq.archive_header.offset,
+            MPQ_ARCHIVE_ALIGNMENT + mpq.archive_header.offset
+        );
+        assert_eq!(wrapped_mpq.hash_table_entries, mpq.hash_table_entries);
+        assert_eq!(wrapped_mpq.block_table_entries, mpq.block_table_entries);
+        assert_eq!(
+            wrapped_mpq.get_files(&wrapped).unwrap(),
+            mpq.get_files(&file_contents).unwrap()
+        );
+        // Without an archive at a boundary the input must start with the magic.
+        assert!(parse(&wrapped[1..]).is_err());
+    }
+
+    #[test]
+    fn it_reports_offset_of_parse_failure() {
+        // Truncated in the middle of the hash table offset.
+        let archive_input = basic_file_header();
+        assert!(matches!(
+            parse(&archive_input[..0x12]),
+            Err(MPQParserError::ByteAlignedAt { offset: 0x10, .. })
+        ));
+        let mut user_data_input = basic_user_header();
+        let archive_header_offset = user_data_input.len();
+        user_data_input.extend_from_slice(&archive_input[..0x12]);
+        match read_headers(&user_data_input) {
+            Err(MPQParserError::ByteAlignedAt { offset, .. }) => {
+                assert_eq!(offset, archive_header_offset + 0x10)
+            }
+            res => panic!("Unexpected result {:?}", res),
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn it_parses_async() {
+        let file_contents = read_file("assets/mpyq-test.SC2Replay");
+        let (_tail, mpq) = parse(&file_contents).unwrap();
+        let owned_mpq = parse_async(file_contents.clone()).await.unwrap();
+        assert_eq!(owned_mpq.archive, file_contents);
+        assert_eq!(owned_mpq.mpq.archive_header, mpq.archive_header);
+        assert_eq!(owned_mpq.mpq.block_table_entries, mpq.block_table_entries);
+        assert_eq!(
+            owned_mpq.read_file("replay.details").unwrap(),
+            mpq.read_mpq_file_sector("replay.details", false, &file_contents)
+                .unwrap()
+                .1
+        );
+        assert!(parse_async(vec![0u8; 16]).await.is_err());
     }
 }
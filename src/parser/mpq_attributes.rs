@@ -0,0 +1,329 @@
+//! The Attributes File Parsing
+//!
+//! The `(attributes)` file is an internal file of the archive that contains
+//! extended metadata for each of the entries of the block table.
+//! The file starts with a header of two int32, the version and the bit mask of
+//! the attributes present in the file, followed by an array per attribute
+//! present, each array containing one entry per block table entry, in this
+//! order:
+//! - `MPQ_ATTRIBUTE_CRC32`: int32(BlockTableEntries) CRC32 of the file data.
+//! - `MPQ_ATTRIBUTE_FILETIME`: int64(BlockTableEntries) Windows FILETIME.
+//! - `MPQ_ATTRIBUTE_MD5`: byte(16 * BlockTableEntries) MD5 of the file data.
+//! - `MPQ_ATTRIBUTE_PATCH_BIT`: bit(BlockTableEntries) whether the file is a
+//!   patch file, present in WoW patch archives.
+//!
+//! NOTES:
+//! - The entry that corresponds to the `(attributes)` file itself is zeroed.
+//! - Some archives have one entry less than the block table in each array, and
+//!   corrupt files may have even less, the missing entries are `None`.
+
+use super::LITTLE_ENDIAN;
+use nom::bytes::complete::take;
+use nom::error::dbg_dmp;
+use nom::multi::count;
+use nom::number::complete::{u32, u64};
+use nom::*;
+
+/// The only known version of the `(attributes)` file.
+pub const MPQ_ATTRIBUTES_VERSION: u32 = 100;
+/// The attributes file contains the CRC32 of each file.
+pub const MPQ_ATTRIBUTE_CRC32: u32 = 0x00000001;
+/// The attributes file contains the FILETIME of each file.
+pub const MPQ_ATTRIBUTE_FILETIME: u32 = 0x00000002;
+/// The attributes file contains the MD5 of each file.
+pub const MPQ_ATTRIBUTE_MD5: u32 = 0x00000004;
+/// The attributes file contains the patch bit of each file.
+pub const MPQ_ATTRIBUTE_PATCH_BIT: u32 = 0x00000008;
+
+/// The arrays present in an `(attributes)` file.
+#[derive(Debug, PartialEq, Default, Clone, Copy)]
+pub struct AttributeFlags {
+    /// The CRC32 array is present, see [`MPQ_ATTRIBUTE_CRC32`]
+    pub crc32: bool,
+    /// The FILETIME array is present, see [`MPQ_ATTRIBUTE_FILETIME`]
+    pub filetime: bool,
+    /// The MD5 array is present, see [`MPQ_ATTRIBUTE_MD5`]
+    pub md5: bool,
+    /// The patch bit array is present, see [`MPQ_ATTRIBUTE_PATCH_BIT`]
+    pub patch_bit: bool,
+}
+
+impl From<u32> for AttributeFlags {
+    fn from(flags: u32) -> Self {
+        Self {
+            crc32: flags & MPQ_ATTRIBUTE_CRC32 != 0,
+            filetime: flags & MPQ_ATTRIBUTE_FILETIME != 0,
+            md5: flags & MPQ_ATTRIBUTE_MD5 != 0,
+            patch_bit: flags & MPQ_ATTRIBUTE_PATCH_BIT != 0,
+        }
+    }
+}
+
+impl From<AttributeFlags> for u32 {
+    fn from(flags: AttributeFlags) -> Self {
+        let mut res = 0;
+        if flags.crc32 {
+            res |= MPQ_ATTRIBUTE_CRC32;
+        }
+        if flags.filetime {
+            res |= MPQ_ATTRIBUTE_FILETIME;
+        }
+        if flags.md5 {
+            res |= MPQ_ATTRIBUTE_MD5;
+        }
+        if flags.patch_bit {
+            res |= MPQ_ATTRIBUTE_PATCH_BIT;
+        }
+        res
+    }
+}
+
+/// The parsed contents of the `(attributes)` file, each array is indexed by
+/// block table index.
+#[derive(Debug, PartialEq, Default, Clone)]
+pub struct MPQAttributes {
+    /// The version of the attributes file.
+    pub version: u32,
+    /// The arrays present in the attributes file.
+    pub flags: AttributeFlags,
+    /// The CRC32 of each block, `None` for the blocks missing from a short array.
+    pub crc32s: Option<Vec<Option<u32>>>,
+    /// The FILETIME of each block, `None` for the blocks missing from a short array.
+    pub filetimes: Option<Vec<Option<u64>>>,
+    /// The MD5 of each block, `None` for the blocks missing from a short array.
+    pub md5s: Option<Vec<Option<[u8; 16]>>>,
+    /// Whether each block is a patch file, `None` for the blocks missing from a
+    /// short array.
+    pub patch_bits: Option<Vec<Option<bool>>>,
+    /// Problems found while parsing, i.e. arrays shorter than the block table.
+    pub diagnostics: Vec<String>,
+}
+
+impl MPQAttributes {
+    /// Parses all the fields in the expected order, the arrays contain
+    /// `block_count` entries.
+    pub fn parse(input: &[u8], block_count: usize) -> IResult<&[u8], Self> {
+        let (input, version) = Self::parse_version(input)?;
+        let (input, flags) = Self::parse_flags(input)?;
+        let flags = AttributeFlags::from(flags);
+        let mut diagnostics = vec![];
+        let entry_count = Self::entry_count(input.len(), block_count, flags);
+        if entry_count != block_count {
+            tracing::warn!(
+                "Attributes arrays contain {} entries for {} blocks",
+                entry_count,
+                block_count
+            );
+            diagnostics.push(format!(
+                "Arrays contain {entry_count} entries for {block_count} blocks"
+            ));
+        }
+        let (input, crc32s) = if flags.crc32 {
+            let (input, crc32s) = Self::parse_crc32s(input, entry_count)?;
+            (input, Some(Self::pad(crc32s, block_count)))
+        } else {
+            (input, None)
+        };
+        let (input, filetimes) = if flags.filetime {
+            let (input, filetimes) = Self::parse_filetimes(input, entry_count)?;
+            (input, Some(Self::pad(filetimes, block_count)))
+        } else {
+            (input, None)
+        };
+        let (input, md5s) = if flags.md5 {
+            let (input, md5s) = Self::parse_md5s(input, entry_count)?;
+            (input, Some(Self::pad(md5s, block_count)))
+        } else {
+            (input, None)
+        };
+        let (input, patch_bits) = if flags.patch_bit {
+            let (input, patch_bits) = Self::parse_patch_bits(input, entry_count)?;
+            (input, Some(Self::pad(patch_bits, block_count)))
+        } else {
+            (input, None)
+        };
+        Ok((
+            input,
+            MPQAttributes {
+                version,
+                flags,
+                crc32s,
+                filetimes,
+                md5s,
+                patch_bits,
+                diagnostics,
+            },
+        ))
+    }
+
+    /// Pads a short array with `None` up to `block_count` entries, the values of
+    /// the missing entries are unknown, they can't be told apart from zeroes.
+    fn pad<T>(entries: Vec<T>, block_count: usize) -> Vec<Option<T>> {
+        let mut res: Vec<Option<T>> = entries.into_iter().map(Some).collect();
+        res.resize_with(block_count, || None);
+        res
+    }
+
+    /// The number of entries of each array, the `block_count` unless the
+    /// arrays are shorter than expected for the size of the input.
+    fn entry_count(input_len: usize, block_count: usize, flags: AttributeFlags) -> usize {
+        let arrays_size = |entry_count: usize| {
+            let mut res = 0;
+            if flags.crc32 {
+                res += 4 * entry_count;
+            }
+            if flags.filetime {
+                res += 8 * entry_count;
+            }
+            if flags.md5 {
+                res += 16 * entry_count;
+            }
+            if flags.patch_bit {
+                res += entry_count.div_ceil(8);
+            }
+            res
+        };
+        if arrays_size(block_count) <= input_len {
+            return block_count;
+        }
+        (0..block_count)
+            .rev()
+            .find(|entry_count| arrays_size(*entry_count) <= input_len)
+            .unwrap_or_default()
+    }
+
+    /// `Offset 0x00`: int32 Version
+    ///
+    /// The version of the attributes file, [`MPQ_ATTRIBUTES_VERSION`].
+    pub fn parse_version(input: &[u8]) -> IResult<&[u8], u32> {
+        dbg_dmp(u32(LITTLE_ENDIAN), "version")(input)
+    }
+
+    /// `Offset 0x04`: int32 Flags
+    ///
+    /// Bit mask of the arrays present in the file.
+    pub fn parse_flags(input: &[u8]) -> IResult<&[u8], u32> {
+        dbg_dmp(u32(LITTLE_ENDIAN), "flags")(input)
+    }
+
+    /// `Offset 0x08`: int32(BlockTableEntries) CRC32
+    ///
+    /// The CRC32 of the uncompressed file data of each block.
+    pub fn parse_crc32s(input: &[u8], block_count: usize) -> IResult<&[u8], Vec<u32>> {
+        count(dbg_dmp(u32(LITTLE_ENDIAN), "crc32"), block_count)(input)
+    }
+
+    /// `Offset Varies`: int64(BlockTableEntries) FileTime
+    ///
+    /// The Windows FILETIME, 100-nanosecond intervals since January 1, 1601,
+    /// of each block.
+    pub fn parse_filetimes(input: &[u8], block_count: usize) -> IResult<&[u8], Vec<u64>> {
+        count(dbg_dmp(u64(LITTLE_ENDIAN), "filetime"), block_count)(input)
+    }
+
+    /// `Offset Varies`: byte(16 * BlockTableEntries) MD5
+    ///
+    /// The MD5 of the uncompressed file data of each block.
+    pub fn parse_md5s(input: &[u8], block_count: usize) -> IResult<&[u8], Vec<[u8; 16]>> {
+        let (input, md5s) = count(dbg_dmp(take(16usize), "md5"), block_count)(input)?;
+        let md5s = md5s
+            .into_iter()
+            .map(|md5| {
+                let mut res = [0u8; 16];
+                res.copy_from_slice(md5);
+                res
+            })
+            .collect();
+        Ok((input, md5s))
+    }
+
+    /// `Offset Varies`: bit(BlockTableEntries) PatchBit
+    ///
+    /// Whether each block is a patch file, the bits are stored starting from
+    /// the most significant bit of each byte.
+    pub fn parse_patch_bits(input: &[u8], block_count: usize) -> IResult<&[u8], Vec<bool>> {
+        let (input, bit_array) = dbg_dmp(take(block_count.div_ceil(8)), "patch_bits")(input)?;
+        let patch_bits = (0..block_count)
+            .map(|idx| bit_array[idx / 8] & (0x80 >> (idx % 8)) != 0)
+            .collect();
+        Ok((input, patch_bits))
+    }
+}
+
+#[cfg(test)]
+/// Attributes File Tests
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_patch_bits() {
+        let mut attributes_input = vec![];
+        attributes_input.extend_from_slice(&MPQ_ATTRIBUTES_VERSION.to_le_bytes());
+        attributes_input
+            .extend_from_slice(&(MPQ_ATTRIBUTE_CRC32 | MPQ_ATTRIBUTE_PATCH_BIT).to_le_bytes());
+        for crc32 in 0u32..10 {
+            attributes_input.extend_from_slice(&crc32.to_le_bytes());
+        }
+        // Blocks 0, 2 and 9 are patch files.
+        attributes_input.extend_from_slice(&[0b1010_0000, 0b0100_0000]);
+        let (input, attributes) = MPQAttributes::parse(&attributes_input, 10).unwrap();
+        assert_eq!(input, &b""[..]);
+        assert!(attributes.flags.patch_bit);
+        assert_eq!(attributes.crc32s, Some((0u32..10).map(Some).collect()));
+        let patch_bits = attributes.patch_bits.unwrap();
+        assert_eq!(patch_bits.len(), 10);
+        let patch_files: Vec<usize> = patch_bits
+            .iter()
+            .enumerate()
+            .filter(|(_, patch_bit)| **patch_bit == Some(true))
+            .map(|(idx, _)| idx)
+            .collect();
+        assert_eq!(patch_files, vec![0, 2, 9]);
+    }
+
+    #[test]
+    fn it_pads_short_arrays() {
+        let mut attributes_input = vec![];
+        attributes_input.extend_from_slice(&MPQ_ATTRIBUTES_VERSION.to_le_bytes());
+        attributes_input
+            .extend_from_slice(&(MPQ_ATTRIBUTE_CRC32 | MPQ_ATTRIBUTE_MD5).to_le_bytes());
+        // The arrays are one entry short of the 4 blocks.
+        for crc32 in 1u32..4 {
+            attributes_input.extend_from_slice(&crc32.to_le_bytes());
+        }
+        for md5 in 1u8..4 {
+            attributes_input.extend_from_slice(&[md5; 16]);
+        }
+        let (input, attributes) = MPQAttributes::parse(&attributes_input, 4).unwrap();
+        assert_eq!(input, &b""[..]);
+        // The missing entry is unknown rather than a zero CRC32 or MD5.
+        assert_eq!(
+            attributes.crc32s,
+            Some(vec![Some(1), Some(2), Some(3), None])
+        );
+        assert_eq!(
+            attributes.md5s,
+            Some(vec![
+                Some([1u8; 16]),
+                Some([2u8; 16]),
+                Some([3u8; 16]),
+                None
+            ])
+        );
+        assert_eq!(attributes.diagnostics.len(), 1);
+        // A truncated file fits no entry of both arrays, all of them are unknown.
+        let (_input, attributes) = MPQAttributes::parse(&attributes_input[..8 + 4 * 2], 4).unwrap();
+        assert_eq!(attributes.crc32s, Some(vec![None; 4]));
+        assert_eq!(attributes.md5s, Some(vec![None; 4]));
+        // A truncated CRC32 array, the 2 last CRC32s are missing.
+        let mut crc32_input = vec![];
+        crc32_input.extend_from_slice(&MPQ_ATTRIBUTES_VERSION.to_le_bytes());
+        crc32_input.extend_from_slice(&MPQ_ATTRIBUTE_CRC32.to_le_bytes());
+        crc32_input.extend_from_slice(&attributes_input[8..8 + 4 * 2]);
+        let (_input, attributes) = MPQAttributes::parse(&crc32_input, 4).unwrap();
+        assert_eq!(attributes.crc32s, Some(vec![Some(1), Some(2), None, None]));
+        assert_eq!(attributes.md5s, None);
+        let (_input, attributes) = MPQAttributes::parse(&attributes_input, 3).unwrap();
+        assert!(attributes.diagnostics.is_empty());
+    }
+}
@@ -0,0 +1,289 @@
+//! The BET Table Parsing
+//!
+//! The Cataclysm formats may replace the block table by the BET (Block Extended
+//! Table), its offset is in [`super::MPQFileHeader::bet_table_pos`].
+//! It starts with the common header of the extended tables, which is not encrypted,
+//! the rest of the table is encrypted with the key of the block table.
+//! The file entries are packed in a bit array, the position and width of each of
+//! their fields is in the header of the table.
+//! NOTES:
+//! - MPyQ does not support the BET table.
+//! - Compressed BET tables are not supported.
+
+use super::{read_bits, LITTLE_ENDIAN, MPQ_MAX_BIT_FIELD_SIZE, MPQ_MAX_TABLE_ENTRIES};
+use nom::bytes::complete::take;
+use nom::error::dbg_dmp;
+use nom::multi::count;
+use nom::number::complete::u32;
+use nom::*;
+
+/// The signature of the BET table, `BET\x1A`.
+pub const MPQ_BET_TABLE_SIGNATURE: u32 = 0x1A544542;
+
+/// A file entry of the BET table.
+#[derive(Debug, PartialEq, Default, Clone, Copy)]
+pub struct MPQBetTableEntry {
+    /// Offset of the beginning of the file, relative to the beginning of the archive.
+    pub file_pos: u64,
+    /// Size of the file data.
+    pub file_size: u64,
+    /// Size of the file in the archive.
+    pub cmp_size: u64,
+    /// The flags of the file, taken from [`MPQBetTable::flags`].
+    pub flags: u32,
+}
+
+/// The BET table of the MPQ archive.
+#[derive(Debug, PartialEq, Default, Clone)]
+pub struct MPQBetTable {
+    /// Size of the whole BET table, including the common header.
+    pub table_size: u32,
+    /// Number of file entries.
+    pub entry_count: u32,
+    /// Unknown, set to 0x10.
+    pub unknown08: u32,
+    /// Size of a file entry, in bits.
+    pub table_entry_size: u32,
+    /// Bit index of the file position in an entry.
+    pub bit_index_file_pos: u32,
+    /// Bit index of the file size in an entry.
+    pub bit_index_file_size: u32,
+    /// Bit index of the compressed size in an entry.
+    pub bit_index_cmp_size: u32,
+    /// Bit index of the flag index in an entry.
+    pub bit_index_flag_index: u32,
+    /// Bit index of the unknown field in an entry.
+    pub bit_index_unknown: u32,
+    /// Bit size of the file position.
+    pub bit_count_file_pos: u32,
+    /// Bit size of the file size.
+    pub bit_count_file_size: u32,
+    /// Bit size of the compressed size.
+    pub bit_count_cmp_size: u32,
+    /// Bit size of the flag index.
+    pub bit_count_flag_index: u32,
+    /// Bit size of the unknown field.
+    pub bit_count_unknown: u32,
+    /// Total size of a name hash, in bits.
+    pub total_bet_hash_size: u32,
+    /// Extra bits of the name hashes.
+    pub bet_hash_size_extra: u32,
+    /// Effective size of a name hash, in bits.
+    pub bet_hash_size: u32,
+    /// Size of the name hash array, in bytes.
+    pub bet_hash_array_size: u32,
+    /// Number of flags.
+    pub flag_count: u32,
+    /// The distinct flags of the files.
+    pub flags: Vec<u32>,
+    /// The file entries.
+    pub entries: Vec<MPQBetTableEntry>,
+    /// The name hashes of the file entries.
+    pub name_hashes: Vec<u64>,
+}
+
+impl MPQBetTable {
+    /// Parses the BET table, the input starts after the common header of the
+    /// extended tables and must be decrypted already.
+    pub fn parse(input: &[u8]) -> IResult<&[u8], Self> {
+        let (input, table_size) = dbg_dmp(u32(LITTLE_ENDIAN), "table_size")(input)?;
+        let (input, entry_count) = dbg_dmp(u32(LITTLE_ENDIAN), "entry_count")(input)?;
+        let (input, unknown08) = dbg_dmp(u32(LITTLE_ENDIAN), "unknown08")(input)?;
+        let (input, table_entry_size) = dbg_dmp(u32(LITTLE_ENDIAN), "table_entry_size")(input)?;
+        let (input, bit_indexes) = count(u32(LITTLE_ENDIAN), 5)(input)?;
+        let (input, bit_counts) = count(u32(LITTLE_ENDIAN), 5)(input)?;
+        let (input, total_bet_hash_size) =
+            dbg_dmp(u32(LITTLE_ENDIAN), "total_bet_hash_size")(input)?;
+        let (input, bet_hash_size_extra) =
+            dbg_dmp(u32(LITTLE_ENDIAN), "bet_hash_size_extra")(input)?;
+        let (input, bet_hash_size) = dbg_dmp(u32(LITTLE_ENDIAN), "bet_hash_size")(input)?;
+        let (input, bet_hash_array_size) =
+            dbg_dmp(u32(LITTLE_ENDIAN), "bet_hash_array_size")(input)?;
+        let (input, flag_count) = dbg_dmp(u32(LITTLE_ENDIAN), "flag_count")(input)?;
+        let (input, flags) = count(u32(LITTLE_ENDIAN), flag_count as usize)(input)?;
+        let out_of_bounds =
+            |input| nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Eof));
+        let invalid =
+            |input| nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Verify));
+        if entry_count > MPQ_MAX_TABLE_ENTRIES {
+            tracing::error!("BET table of {entry_count} entries exceeds the maximum");
+            return Err(invalid(input));
+        }
+        // Each field must be readable and lie within its entry.
+        let fields_fit = bit_indexes.iter().zip(&bit_counts).all(|(index, count)| {
+            *count <= MPQ_MAX_BIT_FIELD_SIZE
+                && *index as u64 + *count as u64 <= table_entry_size as u64
+        });
+        if !fields_fit || bet_hash_size > MPQ_MAX_BIT_FIELD_SIZE {
+            tracing::error!("BET table fields don't fit entries of {table_entry_size} bits");
+            return Err(invalid(input));
+        }
+        let file_table_bits = table_entry_size as u64 * entry_count as u64;
+        if file_table_bits > input.len() as u64 * 8 {
+            tracing::error!("BET table of {entry_count} entries is beyond the end of the table");
+            return Err(out_of_bounds(input));
+        }
+        let file_table_size = (file_table_bits as usize).div_ceil(8);
+        let (input, file_table) = dbg_dmp(take(file_table_size), "file_table")(input)?;
+        let (input, name_hash_array) =
+            dbg_dmp(take(bet_hash_array_size), "name_hash_array")(input)?;
+        let mut entries = Vec::with_capacity(entry_count as usize);
+        for i in 0..entry_count as usize {
+            let entry_offset = i * table_entry_size as usize;
+            let field = |field: usize| {
+                read_bits(
+                    file_table,
+                    entry_offset + bit_indexes[field] as usize,
+                    bit_counts[field],
+                )
+            };
+            let (Some(file_pos), Some(file_size), Some(cmp_size), Some(flag_index)) =
+                (field(0), field(1), field(2), field(3))
+            else {
+                return Err(out_of_bounds(file_table));
+            };
+            let flags = if flag_count == 0 {
+                0
+            } else {
+                *flags
+                    .get(flag_index as usize)
+                    .ok_or(out_of_bounds(file_table))?
+            };
+            entries.push(MPQBetTableEntry {
+                file_pos,
+                file_size,
+                cmp_size,
+                flags,
+            });
+        }
+        let name_hashes = (0..entry_count as usize)
+            .map(|i| {
+                read_bits(
+                    name_hash_array,
+                    i * total_bet_hash_size as usize,
+                    bet_hash_size,
+                )
+            })
+            .collect::<Option<Vec<u64>>>()
+            .ok_or(out_of_bounds(name_hash_array))?;
+        Ok((
+            input,
+            Self {
+                table_size,
+                entry_count,
+                unknown08,
+                table_entry_size,
+                bit_index_file_pos: bit_indexes[0],
+                bit_index_file_size: bit_indexes[1],
+                bit_index_cmp_size: bit_indexes[2],
+                bit_index_flag_index: bit_indexes[3],
+                bit_index_unknown: bit_indexes[4],
+                bit_count_file_pos: bit_counts[0],
+                bit_count_file_size: bit_counts[1],
+                bit_count_cmp_size: bit_counts[2],
+                bit_count_flag_index: bit_counts[3],
+                bit_count_unknown: bit_counts[4],
+                total_bet_hash_size,
+                bet_hash_size_extra,
+                bet_hash_size,
+                bet_hash_array_size,
+                flag_count,
+                flags,
+                entries,
+                name_hashes,
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+/// BET Table Tests
+pub mod tests {
+    use super::*;
+
+    /// A BET table of two files, each field of the entries is byte aligned.
+    pub fn basic_bet_table() -> Vec<u8> {
+        let mut res = vec![];
+        for value in [
+            0x00u32, // table_size
+            2,       // entry_count
+            0x10,    // unknown08
+            56,      // table_entry_size
+            0,       // bit_index_file_pos
+            16,      // bit_index_file_size
+            32,      // bit_index_cmp_size
+            48,      // bit_index_flag_index
+            56,      // bit_index_unknown
+            16,      // bit_count_file_pos
+            16,      // bit_count_file_size
+            16,      // bit_count_cmp_size
+            8,       // bit_count_flag_index
+            0,       // bit_count_unknown
+            16,      // total_bet_hash_size
+            0,       // bet_hash_size_extra
+            16,      // bet_hash_size
+            4,       // bet_hash_array_size
+            2,       // flag_count
+            0x81000000, 0x81000200,
+        ] {
+            res.extend(value.to_le_bytes());
+        }
+        // file_pos, file_size, cmp_size, flag_index
+        res.extend([0x44, 0x00, 0x05, 0x00, 0x05, 0x00, 0x00]);
+        res.extend([0x49, 0x00, 0x20, 0x00, 0x0a, 0x00, 0x01]);
+        // name_hashes
+        res.extend([0x34, 0x12, 0x78, 0x56]);
+        res
+    }
+
+    #[test]
+    fn it_parses_bet_table() {
+        let bet_table_input = basic_bet_table();
+        let (input, bet_table) = MPQBetTable::parse(&bet_table_input).unwrap();
+        assert_eq!(input, &b""[..]);
+        assert_eq!(bet_table.entry_count, 2);
+        assert_eq!(bet_table.flags, vec![0x81000000, 0x81000200]);
+        assert_eq!(
+            bet_table.entries,
+            vec![
+                MPQBetTableEntry {
+                    file_pos: 0x44,
+                    file_size: 5,
+                    cmp_size: 5,
+                    flags: 0x81000000,
+                },
+                MPQBetTableEntry {
+                    file_pos: 0x49,
+                    file_size: 0x20,
+                    cmp_size: 0x0a,
+                    flags: 0x81000200,
+                },
+            ]
+        );
+        assert_eq!(bet_table.name_hashes, vec![0x1234, 0x5678]);
+        // A flag index beyond the flags.
+        let mut invalid_flag_index = bet_table_input.clone();
+        let flag_index_offset = invalid_flag_index.len() - 5;
+        invalid_flag_index[flag_index_offset] = 0x02;
+        assert!(MPQBetTable::parse(&invalid_flag_index).is_err());
+    }
+
+    #[test]
+    fn it_rejects_invalid_bet_table_sizes() {
+        // Empty entries of no fields, so many of them read nothing.
+        let mut huge_count = basic_bet_table();
+        huge_count[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        huge_count[12..16].copy_from_slice(&0u32.to_le_bytes());
+        huge_count[16..56].fill(0);
+        assert!(MPQBetTable::parse(&huge_count).is_err());
+        // A count the file table can't hold.
+        let mut long_count = basic_bet_table();
+        long_count[4..8].copy_from_slice(&3u32.to_le_bytes());
+        assert!(MPQBetTable::parse(&long_count).is_err());
+        // A field wider than 64 bits, within a wide enough entry.
+        let mut wide_field = basic_bet_table();
+        wide_field[12..16].copy_from_slice(&200u32.to_le_bytes());
+        wide_field[36..40].copy_from_slice(&65u32.to_le_bytes());
+        assert!(MPQBetTable::parse(&wide_field).is_err());
+    }
+}
@@ -14,6 +14,10 @@
 //!     - MPyQ uses struct_format: `'4I'`
 
 use super::LITTLE_ENDIAN;
+use crate::{
+    MPQ_FILE_COMPRESS, MPQ_FILE_DELETE_MARKER, MPQ_FILE_ENCRYPTED, MPQ_FILE_EXISTS,
+    MPQ_FILE_FIX_KEY, MPQ_FILE_IMPLODE, MPQ_FILE_SECTOR_CRC, MPQ_FILE_SINGLE_UNIT,
+};
 use nom::error::dbg_dmp;
 use nom::number::complete::u32;
 use nom::*;
@@ -39,6 +43,132 @@ pub struct MPQBlockTableEntry {
     pub flags: u32,
 }
 
+/// The [`MPQBlockTableEntry::flags`] bit mask, with a predicate for each flag.
+/// Its debug format lists the names of the flags set, i.e. `EXISTS | COMPRESS`.
+#[derive(PartialEq, Eq, Default, Clone, Copy)]
+pub struct MPQBlockFlags(pub u32);
+
+impl std::fmt::Debug for MPQBlockFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = [
+            (MPQ_FILE_EXISTS, "EXISTS"),
+            (MPQ_FILE_COMPRESS, "COMPRESS"),
+            (MPQ_FILE_IMPLODE, "IMPLODE"),
+            (MPQ_FILE_ENCRYPTED, "ENCRYPTED"),
+            (MPQ_FILE_FIX_KEY, "FIX_KEY"),
+            (MPQ_FILE_SINGLE_UNIT, "SINGLE_UNIT"),
+            (MPQ_FILE_SECTOR_CRC, "SECTOR_CRC"),
+            (MPQ_FILE_DELETE_MARKER, "DELETE_MARKER"),
+        ]
+        .into_iter()
+        .filter(|(flag, _)| self.contains(*flag))
+        .map(|(_, name)| name)
+        .collect();
+        write!(f, "MPQBlockFlags({})", names.join(" | "))
+    }
+}
+
+impl From<u32> for MPQBlockFlags {
+    fn from(flags: u32) -> Self {
+        Self(flags)
+    }
+}
+
+impl MPQBlockFlags {
+    /// The raw bit mask.
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// Whether all the bits of `flags` are set.
+    pub fn contains(&self, flags: u32) -> bool {
+        self.0 & flags == flags
+    }
+
+    /// The block is a file, see [`MPQ_FILE_EXISTS`]
+    pub fn exists(&self) -> bool {
+        self.contains(MPQ_FILE_EXISTS)
+    }
+
+    /// The file is compressed, see [`MPQ_FILE_COMPRESS`]
+    pub fn is_compressed(&self) -> bool {
+        self.contains(MPQ_FILE_COMPRESS)
+    }
+
+    /// The file is imploded, see [`MPQ_FILE_IMPLODE`]
+    pub fn is_imploded(&self) -> bool {
+        self.contains(MPQ_FILE_IMPLODE)
+    }
+
+    /// The file is encrypted, see [`MPQ_FILE_ENCRYPTED`]
+    pub fn is_encrypted(&self) -> bool {
+        self.contains(MPQ_FILE_ENCRYPTED)
+    }
+
+    /// The encryption key is adjusted, see [`MPQ_FILE_FIX_KEY`]
+    pub fn fix_key(&self) -> bool {
+        self.contains(MPQ_FILE_FIX_KEY)
+    }
+
+    /// The file is stored as a single unit, see [`MPQ_FILE_SINGLE_UNIT`]
+    pub fn is_single_unit(&self) -> bool {
+        self.contains(MPQ_FILE_SINGLE_UNIT)
+    }
+
+    /// The file has checksums for each sector, see [`MPQ_FILE_SECTOR_CRC`]
+    pub fn has_sector_crc(&self) -> bool {
+        self.contains(MPQ_FILE_SECTOR_CRC)
+    }
+
+    /// The file is a deletion marker, see [`MPQ_FILE_DELETE_MARKER`]
+    pub fn is_deleted(&self) -> bool {
+        self.contains(MPQ_FILE_DELETE_MARKER)
+    }
+}
+
+/// The [`MPQBlockTableEntry::flags`] bit mask decoded into its individual flags,
+/// as given by the predicates of [`MPQBlockFlags`].
+#[derive(Debug, PartialEq, Default, Clone, Copy)]
+pub struct BlockFlags {
+    /// The block is a file, see [`MPQ_FILE_EXISTS`]
+    pub exists: bool,
+    /// The file is compressed, see [`MPQ_FILE_COMPRESS`]
+    pub compressed: bool,
+    /// The file is imploded, see [`MPQ_FILE_IMPLODE`]
+    pub imploded: bool,
+    /// The file is encrypted, see [`MPQ_FILE_ENCRYPTED`]
+    pub encrypted: bool,
+    /// The encryption key is adjusted, see [`MPQ_FILE_FIX_KEY`]
+    pub fix_key: bool,
+    /// The file is stored as a single unit, see [`MPQ_FILE_SINGLE_UNIT`]
+    pub single_unit: bool,
+    /// The file has checksums for each sector, see [`MPQ_FILE_SECTOR_CRC`]
+    pub sector_crc: bool,
+    /// The file is a deletion marker, see [`MPQ_FILE_DELETE_MARKER`]
+    pub delete_marker: bool,
+}
+
+impl From<MPQBlockFlags> for BlockFlags {
+    fn from(flags: MPQBlockFlags) -> Self {
+        Self {
+            exists: flags.exists(),
+            compressed: flags.is_compressed(),
+            imploded: flags.is_imploded(),
+            encrypted: flags.is_encrypted(),
+            fix_key: flags.fix_key(),
+            single_unit: flags.is_single_unit(),
+            sector_crc: flags.has_sector_crc(),
+            delete_marker: flags.is_deleted(),
+        }
+    }
+}
+
+impl From<u32> for BlockFlags {
+    fn from(flags: u32) -> Self {
+        Self::from(MPQBlockFlags(flags))
+    }
+}
+
 impl MPQBlockTableEntry {
     /// This method is not related to parsing but for testing, maybe we should consider further
     /// splitting this into a MPQBlockTableEntryParser, maybe overkill.
@@ -51,6 +181,26 @@ impl MPQBlockTableEntry {
         }
     }
 
+    /// The [`MPQBlockTableEntry::flags`] bit mask as [`MPQBlockFlags`].
+    pub fn flags(&self) -> MPQBlockFlags {
+        MPQBlockFlags(self.flags)
+    }
+
+    /// Decodes the [`MPQBlockTableEntry::flags`] bit mask into readable fields.
+    pub fn decoded_flags(&self) -> BlockFlags {
+        BlockFlags::from(self.flags())
+    }
+
+    /// Serializes the fields in the expected order, before encryption.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res = vec![];
+        res.extend_from_slice(&self.offset.to_le_bytes());
+        res.extend_from_slice(&self.archived_size.to_le_bytes());
+        res.extend_from_slice(&self.size.to_le_bytes());
+        res.extend_from_slice(&self.flags.to_le_bytes());
+        res
+    }
+
     /// Parses all the fields in the expected order
     pub fn parse(input: &[u8]) -> IResult<&[u8], Self> {
         let (tail, offset) = Self::parse_offset(input)?;
@@ -116,7 +266,77 @@ impl MPQBlockTableEntry {
     /// - `0x00010000` File is encrypted.
     /// - `0x00000200` File is compressed. File cannot be imploded.
     /// - `0x00000100` File is imploded. File cannot be compressed.
+    #[allow(clippy::doc_overindented_list_items)]
     pub fn parse_flags(input: &[u8]) -> IResult<&[u8], u32> {
         dbg_dmp(u32(LITTLE_ENDIAN), "flags")(input)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_decodes_flags() {
+        let block_entry = MPQBlockTableEntry::new(0x0000002C, 727, 890, 0x81000200u32);
+        assert_eq!(
+            block_entry.decoded_flags(),
+            BlockFlags {
+                exists: true,
+                compressed: true,
+                single_unit: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            MPQBlockTableEntry::new(0, 0, 0, 0).decoded_flags(),
+            BlockFlags::default()
+        );
+    }
+
+    #[test]
+    fn it_formats_flags() {
+        let block_entry = MPQBlockTableEntry::new(0x0000002C, 727, 890, 0x81000200u32);
+        assert_eq!(
+            format!("{:?}", block_entry.flags()),
+            "MPQBlockFlags(EXISTS | COMPRESS | SINGLE_UNIT)"
+        );
+        assert_eq!(format!("{:?}", MPQBlockFlags(0)), "MPQBlockFlags()");
+    }
+
+    #[test]
+    fn it_checks_flag_predicates() {
+        let block_entry = MPQBlockTableEntry::new(0x0000002C, 727, 890, 0x81000200u32);
+        let flags = block_entry.flags();
+        assert_eq!(flags.bits(), 0x81000200);
+        assert!(flags.exists());
+        assert!(flags.is_compressed());
+        assert!(flags.is_single_unit());
+        assert!(!flags.is_imploded());
+        assert!(!flags.is_encrypted());
+        assert!(!flags.fix_key());
+        assert!(!flags.has_sector_crc());
+        assert!(!flags.is_deleted());
+        let flags = MPQBlockTableEntry::new(
+            0,
+            0,
+            0,
+            MPQ_FILE_IMPLODE
+                | MPQ_FILE_ENCRYPTED
+                | MPQ_FILE_FIX_KEY
+                | MPQ_FILE_SECTOR_CRC
+                | MPQ_FILE_DELETE_MARKER,
+        )
+        .flags();
+        assert!(!flags.exists());
+        assert!(!flags.is_compressed());
+        assert!(!flags.is_single_unit());
+        assert!(flags.is_imploded());
+        assert!(flags.is_encrypted());
+        assert!(flags.fix_key());
+        assert!(flags.has_sector_crc());
+        assert!(flags.is_deleted());
+        assert!(flags.contains(MPQ_FILE_ENCRYPTED | MPQ_FILE_FIX_KEY));
+        assert!(!flags.contains(MPQ_FILE_ENCRYPTED | MPQ_FILE_EXISTS));
+    }
+}
@@ -3,11 +3,52 @@
 //! - MPyQ uses struct_format: '<4s2I2H4I'
 
 use super::MPQFileHeaderExt;
+use super::MPQFileHeaderV4;
 use super::LITTLE_ENDIAN;
 use nom::error::dbg_dmp;
-use nom::number::complete::{u16, u32};
+use nom::number::complete::{u16, u32, u64};
 use nom::*;
 
+/// The MoPaQ format versions.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MPQVersion {
+    /// `0x0000` Original format, up to The Burning Crusade.
+    V1Original,
+    /// `0x0001` Burning Crusade format, large archives are supported.
+    V2BurningCrusade,
+    /// `0x0002` Cataclysm beta format.
+    V3Cataclysm,
+    /// `0x0003` Cataclysm format, including the MD5 of the tables.
+    V4,
+    /// A format version that is not known.
+    Unknown(u16),
+}
+
+impl From<u16> for MPQVersion {
+    fn from(format_version: u16) -> Self {
+        match format_version {
+            0 => Self::V1Original,
+            1 => Self::V2BurningCrusade,
+            2 => Self::V3Cataclysm,
+            3 => Self::V4,
+            unknown => Self::Unknown(unknown),
+        }
+    }
+}
+
+impl MPQVersion {
+    /// The size of the archive header of the format version, the smallest size
+    /// of the original format for unknown versions.
+    pub fn header_size(&self) -> u32 {
+        match self {
+            Self::V1Original | Self::Unknown(_) => 0x20,
+            Self::V2BurningCrusade => 0x2c,
+            Self::V3Cataclysm => 0x44,
+            Self::V4 => 0xd0,
+        }
+    }
+}
+
 /// The MPQ File Header
 #[derive(Debug, Default, PartialEq, Clone, Copy)]
 pub struct MPQFileHeader {
@@ -34,6 +75,17 @@ pub struct MPQFileHeader {
     pub block_table_entries: u32,
     /// Extended Block Table only present in Burning Crusade format and later:
     pub extended_file_header: Option<MPQFileHeaderExt>,
+    /// Size of the whole archive, including the header, only present in the
+    /// Cataclysm formats, it supersedes [`MPQFileHeader::archive_size`].
+    pub archive_size_64: Option<u64>,
+    /// Offset to the beginning of the BET table, relative to the beginning of the
+    /// archive, only present in the Cataclysm formats.
+    pub bet_table_pos: Option<u64>,
+    /// Offset to the beginning of the HET table, relative to the beginning of the
+    /// archive, only present in the Cataclysm formats.
+    pub het_table_pos: Option<u64>,
+    /// The sizes and MD5 digests of the tables, only present in the format version 4.
+    pub v4_file_header: Option<MPQFileHeaderV4>,
     /// Store the offset at which the FileHeader was found.
     /// this is done because other offsets are relative to this one.
     pub offset: usize,
@@ -52,6 +104,11 @@ impl MPQFileHeader {
         let (input, block_table_entries) = Self::parse_block_table_entries(input)?;
         let (input, extended_file_header) =
             Self::parse_extended_header_if_needed(input, format_version)?;
+        let (input, archive_size_64) =
+            Self::parse_archive_size_64_if_needed(input, format_version)?;
+        let (input, bet_table_pos) = Self::parse_bet_table_pos_if_needed(input, format_version)?;
+        let (input, het_table_pos) = Self::parse_het_table_pos_if_needed(input, format_version)?;
+        let (input, v4_file_header) = Self::parse_v4_header_if_needed(input, format_version)?;
         Ok((
             input,
             MPQFileHeader {
@@ -64,11 +121,57 @@ impl MPQFileHeader {
                 hash_table_entries,
                 block_table_entries,
                 extended_file_header,
+                archive_size_64,
+                bet_table_pos,
+                het_table_pos,
+                v4_file_header,
                 offset,
             },
         ))
     }
 
+    /// The MoPaQ format version of the archive.
+    pub fn version(&self) -> MPQVersion {
+        MPQVersion::from(self.format_version)
+    }
+
+    /// The size of the whole archive, the 64-bit size of the Cataclysm formats
+    /// when present, otherwise the [`MPQFileHeader::archive_size`].
+    pub fn archive_size_bytes(&self) -> u64 {
+        self.archive_size_64.unwrap_or(self.archive_size as u64)
+    }
+
+    /// The offset of the hash table relative to the archive header, including the
+    /// high 16 bits of the extended header of large archives.
+    pub fn hash_table_pos(&self) -> u64 {
+        let high = self
+            .extended_file_header
+            .map_or(0, |ext| ext.hash_table_offset_high as u16 as u64);
+        (high << 32) | self.hash_table_offset as u64
+    }
+
+    /// The offset of the block table relative to the archive header, including the
+    /// high 16 bits of the extended header of large archives.
+    pub fn block_table_pos(&self) -> u64 {
+        let high = self
+            .extended_file_header
+            .map_or(0, |ext| ext.block_table_offset_high as u16 as u64);
+        (high << 32) | self.block_table_offset as u64
+    }
+
+    /// The size of the archive, relative to the archive header.
+    ///
+    /// Since the [`MPQFileHeader::archive_size`] field is deprecated in the Burning Crusade
+    /// format, this is the largest of the declared archive size and the end of the hash
+    /// and block tables.
+    pub fn effective_archive_size(&self) -> u64 {
+        let hash_table_end = self.hash_table_pos() + 16u64 * self.hash_table_entries as u64;
+        let block_table_end = self.block_table_pos() + 16u64 * self.block_table_entries as u64;
+        self.archive_size_bytes()
+            .max(hash_table_end)
+            .max(block_table_end)
+    }
+
     /// `Offset 0x04`: int32 HeaderSize
     ///
     /// Size of the archive header.
@@ -99,6 +202,7 @@ impl MPQFileHeader {
     ///          archives are not supported.
     /// - `0x0001` Burning Crusade format. Header size should be `0x2c`,
     ///          and large archives are supported.
+    #[allow(clippy::doc_overindented_list_items)]
     pub fn parse_format_version(input: &[u8]) -> IResult<&[u8], u16> {
         dbg_dmp(u16(LITTLE_ENDIAN), "format_version")(input)
     }
@@ -155,12 +259,83 @@ impl MPQFileHeader {
         input: &[u8],
         format_version: u16,
     ) -> IResult<&[u8], Option<MPQFileHeaderExt>> {
-        if format_version != 1u16 {
+        if !matches!(
+            MPQVersion::from(format_version),
+            MPQVersion::V2BurningCrusade | MPQVersion::V3Cataclysm | MPQVersion::V4
+        ) {
             return Ok((input, None));
         }
         let (input, extended_file_header) = MPQFileHeaderExt::parse(input)?;
         Ok((input, Some(extended_file_header)))
     }
+
+    /// `Offset 0x2c`: int64 ArchiveSize64
+    ///
+    /// Size of the whole archive, including the header, only present in the
+    /// Cataclysm formats. Supersedes the 32-bit ArchiveSize for archives over 4GB.
+    pub fn parse_archive_size_64_if_needed(
+        input: &[u8],
+        format_version: u16,
+    ) -> IResult<&[u8], Option<u64>> {
+        if !matches!(
+            MPQVersion::from(format_version),
+            MPQVersion::V3Cataclysm | MPQVersion::V4
+        ) {
+            return Ok((input, None));
+        }
+        let (input, archive_size_64) = dbg_dmp(u64(LITTLE_ENDIAN), "archive_size_64")(input)?;
+        Ok((input, Some(archive_size_64)))
+    }
+
+    /// `Offset 0x34`: int64 BetTablePos64
+    ///
+    /// Offset to the beginning of the BET table, relative to the beginning of the
+    /// archive, only present in the Cataclysm formats. Zero when there is no BET table.
+    pub fn parse_bet_table_pos_if_needed(
+        input: &[u8],
+        format_version: u16,
+    ) -> IResult<&[u8], Option<u64>> {
+        if !matches!(
+            MPQVersion::from(format_version),
+            MPQVersion::V3Cataclysm | MPQVersion::V4
+        ) {
+            return Ok((input, None));
+        }
+        let (input, bet_table_pos) = dbg_dmp(u64(LITTLE_ENDIAN), "bet_table_pos")(input)?;
+        Ok((input, Some(bet_table_pos)))
+    }
+
+    /// `Offset 0x3c`: int64 HetTablePos64
+    ///
+    /// Offset to the beginning of the HET table, relative to the beginning of the
+    /// archive, only present in the Cataclysm formats. Zero when there is no HET table.
+    pub fn parse_het_table_pos_if_needed(
+        input: &[u8],
+        format_version: u16,
+    ) -> IResult<&[u8], Option<u64>> {
+        if !matches!(
+            MPQVersion::from(format_version),
+            MPQVersion::V3Cataclysm | MPQVersion::V4
+        ) {
+            return Ok((input, None));
+        }
+        let (input, het_table_pos) = dbg_dmp(u64(LITTLE_ENDIAN), "het_table_pos")(input)?;
+        Ok((input, Some(het_table_pos)))
+    }
+
+    /// `Offset 0x44`: The sizes and MD5 digests of the tables
+    ///
+    /// Only present in the format version 4, see [`MPQFileHeaderV4`].
+    pub fn parse_v4_header_if_needed(
+        input: &[u8],
+        format_version: u16,
+    ) -> IResult<&[u8], Option<MPQFileHeaderV4>> {
+        if MPQVersion::from(format_version) != MPQVersion::V4 {
+            return Ok((input, None));
+        }
+        let (input, v4_file_header) = MPQFileHeaderV4::parse(input)?;
+        Ok((input, Some(v4_file_header)))
+    }
 }
 
 #[cfg(test)]
@@ -171,7 +346,7 @@ pub mod tests {
     /// Generates a valid basic file header
     pub fn basic_file_header() -> Vec<u8> {
         // struct_format: '<4s2I2H4I'
-        vec![
+        let mut res = vec![
             b'M',
             b'P',
             b'Q', // Magic
@@ -204,7 +379,46 @@ pub mod tests {
             0x00,
             0x00,
             0x00, // block_table_entries
-        ]
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00, // extended_block_table_offset
+            0x00,
+            0x00, // hash_table_offset_high
+            0x00,
+            0x00, // block_table_offset_high
+            0xcf,
+            0xa3,
+            0x03,
+            0x00,
+            0x01,
+            0x00,
+            0x00,
+            0x00, // archive_size_64
+            0x00,
+            0x10,
+            0x04,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00, // bet_table_pos
+            0x00,
+            0x00,
+            0x04,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00, // het_table_pos
+        ];
+        // The table sizes and MD5 digests of the format version 4.
+        res.resize(0xd0, 0x00);
+        res
     }
 
     #[test]
@@ -216,5 +430,68 @@ pub mod tests {
         let (_input, header_data) = MPQFileHeader::parse(input, 0).unwrap();
         assert_eq!(header_data.hash_table_entries, 1);
         assert_eq!(header_data.block_table_entries, 2);
+        assert_eq!(header_data.version(), MPQVersion::V4);
+    }
+
+    #[test]
+    fn it_parses_archive_size_64() {
+        let basic_file_header_input = basic_file_header();
+        let (input, _header_type) = get_header_type(&basic_file_header_input).unwrap();
+        let (input, header_data) = MPQFileHeader::parse(input, 0).unwrap();
+        assert_eq!(input, &b""[..]);
+        assert_eq!(header_data.archive_size, 0x0003a3cf);
+        assert_eq!(header_data.archive_size_64, Some(0x1_0003_a3cf));
+        assert_eq!(header_data.archive_size_bytes(), 0x1_0003_a3cf);
+        assert_eq!(header_data.bet_table_pos, Some(0x0004_1000));
+        assert_eq!(header_data.het_table_pos, Some(0x0004_0000));
+        // The Burning Crusade format only has the 32-bit size.
+        let mut burning_crusade_header = basic_file_header();
+        burning_crusade_header[12] = 0x01;
+        let (input, _header_type) = get_header_type(&burning_crusade_header).unwrap();
+        let (_input, header_data) = MPQFileHeader::parse(input, 0).unwrap();
+        assert_eq!(header_data.archive_size_64, None);
+        assert_eq!(header_data.archive_size_bytes(), 0x0003a3cf);
+        assert_eq!(header_data.bet_table_pos, None);
+        assert_eq!(header_data.het_table_pos, None);
+    }
+
+    #[test]
+    fn it_parses_header_through_both_entry_points() {
+        // The Burning Crusade format carries the extended header.
+        let mut burning_crusade_header = basic_file_header();
+        burning_crusade_header[12] = 0x01;
+        // Without the 64-bit archive size of the Cataclysm formats.
+        burning_crusade_header.truncate(0x2c);
+        let (tail, read_headers_header) = read_headers(&burning_crusade_header).unwrap();
+        let (input, _header_type) = get_header_type(&burning_crusade_header).unwrap();
+        let (input, parsed_header) = MPQFileHeader::parse(input, 0).unwrap();
+        assert_eq!(read_headers_header, (parsed_header, None));
+        assert_eq!(tail, input);
+        assert_eq!(input, &b""[..]);
+        assert_eq!(
+            parsed_header.extended_file_header,
+            Some(MPQFileHeaderExt::default())
+        );
+    }
+
+    #[test]
+    fn it_combines_high_table_offsets() {
+        let mut burning_crusade_header = basic_file_header();
+        burning_crusade_header[12] = 0x01;
+        burning_crusade_header.truncate(0x2c);
+        // hash_table_offset_high and block_table_offset_high
+        burning_crusade_header[0x28..0x2a].copy_from_slice(&[0x01, 0x00]);
+        burning_crusade_header[0x2a..0x2c].copy_from_slice(&[0xff, 0xff]);
+        let (input, _header_type) = get_header_type(&burning_crusade_header).unwrap();
+        let (_input, header_data) = MPQFileHeader::parse(input, 0).unwrap();
+        assert_eq!(header_data.hash_table_pos(), 0x1_0003_a0bf);
+        assert_eq!(header_data.block_table_pos(), 0xffff_0003_a2bf);
+        // The original format has no high bits.
+        let mut original_header = burning_crusade_header.clone();
+        original_header[12] = 0x00;
+        let (input, _header_type) = get_header_type(&original_header).unwrap();
+        let (_input, header_data) = MPQFileHeader::parse(input, 0).unwrap();
+        assert_eq!(header_data.hash_table_pos(), 0x0003_a0bf);
+        assert_eq!(header_data.block_table_pos(), 0x0003_a2bf);
     }
 }
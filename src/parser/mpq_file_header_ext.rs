@@ -11,11 +11,11 @@ use nom::*;
 #[derive(Debug, PartialEq, Default, Clone, Copy)]
 pub struct MPQFileHeaderExt {
     /// Offset to the beginning of the extended block table, relative to the beginning of the archive.
-    extended_block_table_offset: i64,
+    pub extended_block_table_offset: i64,
     /// High 16 bits of the hash table offset for large archives.
-    hash_table_offset_high: i16,
+    pub hash_table_offset_high: i16,
     /// High 16 bits of the block table offset for large archives.
-    block_table_offset_high: i16,
+    pub block_table_offset_high: i16,
 }
 
 impl MPQFileHeaderExt {
@@ -55,3 +55,23 @@ impl MPQFileHeaderExt {
         dbg_dmp(i16(LITTLE_ENDIAN), "block_table_offset_high")(input)
     }
 }
+
+#[cfg(test)]
+/// Extended File Header Tests
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_file_header_ext() {
+        let file_header_ext_input = vec![
+            0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // extended_block_table_offset
+            0x01, 0x00, // hash_table_offset_high
+            0x02, 0x00, // block_table_offset_high
+        ];
+        let (input, file_header_ext) = MPQFileHeaderExt::parse(&file_header_ext_input).unwrap();
+        assert!(input.is_empty());
+        assert_eq!(file_header_ext.extended_block_table_offset, 0x1000);
+        assert_eq!(file_header_ext.hash_table_offset_high, 1);
+        assert_eq!(file_header_ext.block_table_offset_high, 2);
+    }
+}
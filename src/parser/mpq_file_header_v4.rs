@@ -0,0 +1,134 @@
+//! Nom Parsing The MPQ File Header of the format version 4
+//! NOTES:
+//! - MPyQ does not support the format version 4 fields.
+
+use super::LITTLE_ENDIAN;
+use nom::bytes::complete::take;
+use nom::error::dbg_dmp;
+use nom::number::complete::{u32, u64};
+use nom::*;
+
+/// The size of the MD5 digests of the tables and the header.
+pub const MPQ_MD5_DIGEST_SIZE: usize = 16;
+
+/// The size of the part of the header covered by [`MPQFileHeaderV4::md5_mpq_header`].
+pub const MPQ_HEADER_MD5_COVERED_SIZE: usize = 0xc0;
+
+/// Fields only present in the format version 4, they contain the compressed sizes
+/// of the tables and their MD5 digests.
+#[derive(Debug, PartialEq, Default, Clone, Copy)]
+pub struct MPQFileHeaderV4 {
+    /// Compressed size of the hash table.
+    pub hash_table_size_64: u64,
+    /// Compressed size of the block table.
+    pub block_table_size_64: u64,
+    /// Compressed size of the hi-block table.
+    pub hi_block_table_size_64: u64,
+    /// Compressed size of the HET table.
+    pub het_table_size_64: u64,
+    /// Compressed size of the BET table.
+    pub bet_table_size_64: u64,
+    /// Size of the raw data chunks, used to compute the MD5 of the file data.
+    pub raw_chunk_size: u32,
+    /// MD5 of the block table, before decryption.
+    pub md5_block_table: [u8; MPQ_MD5_DIGEST_SIZE],
+    /// MD5 of the hash table, before decryption.
+    pub md5_hash_table: [u8; MPQ_MD5_DIGEST_SIZE],
+    /// MD5 of the hi-block table.
+    pub md5_hi_block_table: [u8; MPQ_MD5_DIGEST_SIZE],
+    /// MD5 of the BET table, before decryption.
+    pub md5_bet_table: [u8; MPQ_MD5_DIGEST_SIZE],
+    /// MD5 of the HET table, before decryption.
+    pub md5_het_table: [u8; MPQ_MD5_DIGEST_SIZE],
+    /// MD5 of the header, from the signature up to this field.
+    pub md5_mpq_header: [u8; MPQ_MD5_DIGEST_SIZE],
+}
+
+impl MPQFileHeaderV4 {
+    /// Parses all the fields in the expected order
+    pub fn parse(input: &[u8]) -> IResult<&[u8], MPQFileHeaderV4> {
+        let (input, hash_table_size_64) = Self::parse_table_size_64(input, "hash_table_size_64")?;
+        let (input, block_table_size_64) = Self::parse_table_size_64(input, "block_table_size_64")?;
+        let (input, hi_block_table_size_64) =
+            Self::parse_table_size_64(input, "hi_block_table_size_64")?;
+        let (input, het_table_size_64) = Self::parse_table_size_64(input, "het_table_size_64")?;
+        let (input, bet_table_size_64) = Self::parse_table_size_64(input, "bet_table_size_64")?;
+        let (input, raw_chunk_size) = Self::parse_raw_chunk_size(input)?;
+        let (input, md5_block_table) = Self::parse_md5(input, "md5_block_table")?;
+        let (input, md5_hash_table) = Self::parse_md5(input, "md5_hash_table")?;
+        let (input, md5_hi_block_table) = Self::parse_md5(input, "md5_hi_block_table")?;
+        let (input, md5_bet_table) = Self::parse_md5(input, "md5_bet_table")?;
+        let (input, md5_het_table) = Self::parse_md5(input, "md5_het_table")?;
+        let (input, md5_mpq_header) = Self::parse_md5(input, "md5_mpq_header")?;
+        Ok((
+            input,
+            MPQFileHeaderV4 {
+                hash_table_size_64,
+                block_table_size_64,
+                hi_block_table_size_64,
+                het_table_size_64,
+                bet_table_size_64,
+                raw_chunk_size,
+                md5_block_table,
+                md5_hash_table,
+                md5_hi_block_table,
+                md5_bet_table,
+                md5_het_table,
+                md5_mpq_header,
+            },
+        ))
+    }
+
+    /// `Offset 0x44..0x6C`: int64 HashTableSize64, BlockTableSize64,
+    /// HiBlockTableSize64, HetTableSize64, BetTableSize64
+    ///
+    /// Compressed size of each of the tables.
+    pub fn parse_table_size_64<'a>(input: &'a [u8], name: &'static str) -> IResult<&'a [u8], u64> {
+        dbg_dmp(u64(LITTLE_ENDIAN), name)(input)
+    }
+
+    /// `Offset 0x6C`: int32 RawChunkSize
+    ///
+    /// Size of the raw data chunks, used to compute the MD5 of the file data.
+    pub fn parse_raw_chunk_size(input: &[u8]) -> IResult<&[u8], u32> {
+        dbg_dmp(u32(LITTLE_ENDIAN), "raw_chunk_size")(input)
+    }
+
+    /// `Offset 0x70..0xD0`: char(16) MD5_BlockTable, MD5_HashTable,
+    /// MD5_HiBlockTable, MD5_BetTable, MD5_HetTable, MD5_MpqHeader
+    pub fn parse_md5<'a>(
+        input: &'a [u8],
+        name: &'static str,
+    ) -> IResult<&'a [u8], [u8; MPQ_MD5_DIGEST_SIZE]> {
+        let (input, md5) = dbg_dmp(take(MPQ_MD5_DIGEST_SIZE), name)(input)?;
+        let mut res = [0u8; MPQ_MD5_DIGEST_SIZE];
+        res.copy_from_slice(md5);
+        Ok((input, res))
+    }
+}
+
+#[cfg(test)]
+/// Format Version 4 File Header Tests
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_file_header_v4() {
+        let mut file_header_v4_input = vec![];
+        for table_size in 1u64..=5 {
+            file_header_v4_input.extend(table_size.to_le_bytes());
+        }
+        file_header_v4_input.extend(0x4000u32.to_le_bytes()); // raw_chunk_size
+        for md5 in 1u8..=6 {
+            file_header_v4_input.extend([md5; MPQ_MD5_DIGEST_SIZE]);
+        }
+        let (input, file_header_v4) = MPQFileHeaderV4::parse(&file_header_v4_input).unwrap();
+        assert!(input.is_empty());
+        assert_eq!(file_header_v4.hash_table_size_64, 1);
+        assert_eq!(file_header_v4.bet_table_size_64, 5);
+        assert_eq!(file_header_v4.raw_chunk_size, 0x4000);
+        assert_eq!(file_header_v4.md5_block_table, [1; MPQ_MD5_DIGEST_SIZE]);
+        assert_eq!(file_header_v4.md5_mpq_header, [6; MPQ_MD5_DIGEST_SIZE]);
+        assert!(MPQFileHeaderV4::parse(&file_header_v4_input[..0x80]).is_err());
+    }
+}
@@ -30,6 +30,11 @@ use nom::error::dbg_dmp;
 use nom::number::complete::{u16, u32};
 use nom::*;
 
+/// The [`MPQHashTableEntry::block_table_index`] of an entry that has always been empty.
+pub const MPQ_HASH_ENTRY_EMPTY: u32 = 0xFFFFFFFF;
+/// The [`MPQHashTableEntry::block_table_index`] of an entry whose file was deleted.
+pub const MPQ_HASH_ENTRY_DELETED: u32 = 0xFFFFFFFE;
+
 /// The hash table entry definition
 #[derive(Debug, PartialEq, Default, Clone)]
 pub struct MPQHashTableEntry {
@@ -67,6 +72,27 @@ impl MPQHashTableEntry {
         }
     }
 
+    /// The entry has always been empty, it terminates searches.
+    pub fn is_empty(&self) -> bool {
+        self.block_table_index == MPQ_HASH_ENTRY_EMPTY
+    }
+
+    /// The entry was valid at some point but its file was deleted.
+    pub fn is_deleted(&self) -> bool {
+        self.block_table_index == MPQ_HASH_ENTRY_DELETED
+    }
+
+    /// Serializes the fields in the expected order, before encryption.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res = vec![];
+        res.extend_from_slice(&self.hash_a.to_le_bytes());
+        res.extend_from_slice(&self.hash_b.to_le_bytes());
+        res.extend_from_slice(&self.locale.to_le_bytes());
+        res.extend_from_slice(&self.platform.to_le_bytes());
+        res.extend_from_slice(&self.block_table_index.to_le_bytes());
+        res
+    }
+
     /// Parses all the fields in the expected order
     pub fn parse(input: &[u8]) -> IResult<&[u8], Self> {
         let (tail, hash_a) = Self::parse_hash_a(input)?;
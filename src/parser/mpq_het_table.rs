@@ -0,0 +1,175 @@
+//! The HET Table Parsing
+//!
+//! The Cataclysm formats may replace the hash table by the HET (Hash Extended
+//! Table), its offset is in [`super::MPQFileHeader::het_table_pos`].
+//! It starts with the common header of the extended tables, which is not encrypted,
+//! the rest of the table is encrypted with the key of the hash table.
+//! For each file the HET table contains an 8-bit name hash and the index of its
+//! entry in the BET table, see [`super::MPQBetTable`].
+//! NOTES:
+//! - MPyQ does not support the HET table.
+//! - The lookup of a filename requires the Jenkins hash of the name, which is not
+//!   implemented, the table is only parsed.
+//! - Compressed HET tables are not supported.
+
+use super::{read_bits, LITTLE_ENDIAN, MPQ_MAX_BIT_FIELD_SIZE};
+use nom::bytes::complete::take;
+use nom::error::dbg_dmp;
+use nom::number::complete::u32;
+use nom::*;
+
+/// The signature of the HET table, `HET\x1A`.
+pub const MPQ_HET_TABLE_SIGNATURE: u32 = 0x1A544548;
+
+/// The HET table of the MPQ archive.
+#[derive(Debug, PartialEq, Default, Clone)]
+pub struct MPQHetTable {
+    /// Size of the whole HET table, including the common header.
+    pub table_size: u32,
+    /// Number of occupied entries.
+    pub entry_count: u32,
+    /// Total number of entries in the table.
+    pub total_count: u32,
+    /// Size of the name hashes, in bits.
+    pub name_hash_bit_size: u32,
+    /// Total size of an entry of the BET index array, in bits.
+    pub index_size_total: u32,
+    /// Extra bits of the entries of the BET index array.
+    pub index_size_extra: u32,
+    /// Effective size of an entry of the BET index array, in bits.
+    pub index_size: u32,
+    /// Size of the BET index array, in bytes.
+    pub index_table_size: u32,
+    /// The 8-bit name hashes, zero for empty entries.
+    pub name_hashes: Vec<u8>,
+    /// The index in the BET table of each of the entries.
+    pub bet_indexes: Vec<u64>,
+}
+
+impl MPQHetTable {
+    /// Parses the HET table, the input starts after the common header of the
+    /// extended tables and must be decrypted already.
+    pub fn parse(input: &[u8]) -> IResult<&[u8], Self> {
+        let (input, table_size) = Self::parse_table_size(input)?;
+        let (input, entry_count) = Self::parse_entry_count(input)?;
+        let (input, total_count) = Self::parse_total_count(input)?;
+        let (input, name_hash_bit_size) = Self::parse_name_hash_bit_size(input)?;
+        let (input, index_size_total) = Self::parse_index_size_total(input)?;
+        let (input, index_size_extra) = Self::parse_index_size_extra(input)?;
+        let (input, index_size) = Self::parse_index_size(input)?;
+        let (input, index_table_size) = Self::parse_index_table_size(input)?;
+        if index_size > MPQ_MAX_BIT_FIELD_SIZE {
+            tracing::error!("HET table index of {index_size} bits doesn't fit a u64");
+            return Err(nom::Err::Error(nom::error::Error::new(
+                input,
+                nom::error::ErrorKind::Verify,
+            )));
+        }
+        let (input, name_hashes) = dbg_dmp(take(total_count), "name_hashes")(input)?;
+        let (input, index_table) = dbg_dmp(take(index_table_size), "index_table")(input)?;
+        let bet_indexes = (0..total_count as usize)
+            .map(|i| read_bits(index_table, i * index_size_total as usize, index_size))
+            .collect::<Option<Vec<u64>>>()
+            .ok_or(nom::Err::Error(nom::error::Error::new(
+                index_table,
+                nom::error::ErrorKind::Eof,
+            )))?;
+        Ok((
+            input,
+            Self {
+                table_size,
+                entry_count,
+                total_count,
+                name_hash_bit_size,
+                index_size_total,
+                index_size_extra,
+                index_size,
+                index_table_size,
+                name_hashes: name_hashes.to_vec(),
+                bet_indexes,
+            },
+        ))
+    }
+
+    /// `Offset 0x00`: int32 TableSize
+    pub fn parse_table_size(input: &[u8]) -> IResult<&[u8], u32> {
+        dbg_dmp(u32(LITTLE_ENDIAN), "table_size")(input)
+    }
+
+    /// `Offset 0x04`: int32 EntryCount
+    pub fn parse_entry_count(input: &[u8]) -> IResult<&[u8], u32> {
+        dbg_dmp(u32(LITTLE_ENDIAN), "entry_count")(input)
+    }
+
+    /// `Offset 0x08`: int32 TotalCount
+    pub fn parse_total_count(input: &[u8]) -> IResult<&[u8], u32> {
+        dbg_dmp(u32(LITTLE_ENDIAN), "total_count")(input)
+    }
+
+    /// `Offset 0x0c`: int32 NameHashBitSize
+    pub fn parse_name_hash_bit_size(input: &[u8]) -> IResult<&[u8], u32> {
+        dbg_dmp(u32(LITTLE_ENDIAN), "name_hash_bit_size")(input)
+    }
+
+    /// `Offset 0x10`: int32 IndexSizeTotal
+    pub fn parse_index_size_total(input: &[u8]) -> IResult<&[u8], u32> {
+        dbg_dmp(u32(LITTLE_ENDIAN), "index_size_total")(input)
+    }
+
+    /// `Offset 0x14`: int32 IndexSizeExtra
+    pub fn parse_index_size_extra(input: &[u8]) -> IResult<&[u8], u32> {
+        dbg_dmp(u32(LITTLE_ENDIAN), "index_size_extra")(input)
+    }
+
+    /// `Offset 0x18`: int32 IndexSize
+    pub fn parse_index_size(input: &[u8]) -> IResult<&[u8], u32> {
+        dbg_dmp(u32(LITTLE_ENDIAN), "index_size")(input)
+    }
+
+    /// `Offset 0x1c`: int32 IndexTableSize
+    pub fn parse_index_table_size(input: &[u8]) -> IResult<&[u8], u32> {
+        dbg_dmp(u32(LITTLE_ENDIAN), "index_table_size")(input)
+    }
+}
+
+#[cfg(test)]
+/// HET Table Tests
+pub mod tests {
+    use super::*;
+
+    /// A HET table of four entries, two of them occupied.
+    pub fn basic_het_table() -> Vec<u8> {
+        vec![
+            0x3d, 0x00, 0x00, 0x00, // table_size
+            0x02, 0x00, 0x00, 0x00, // entry_count
+            0x04, 0x00, 0x00, 0x00, // total_count
+            0x08, 0x00, 0x00, 0x00, // name_hash_bit_size
+            0x04, 0x00, 0x00, 0x00, // index_size_total
+            0x00, 0x00, 0x00, 0x00, // index_size_extra
+            0x04, 0x00, 0x00, 0x00, // index_size
+            0x02, 0x00, 0x00, 0x00, // index_table_size
+            0x00, 0xa1, 0x00, 0xb2, // name_hashes
+            0x0f, 0x1f, // bet_indexes
+        ]
+    }
+
+    #[test]
+    fn it_parses_het_table() {
+        let mut het_table_input = basic_het_table();
+        het_table_input.push(0xff); // trailing data
+        let (input, het_table) = MPQHetTable::parse(&het_table_input).unwrap();
+        assert_eq!(input, &[0xff][..]);
+        assert_eq!(het_table.entry_count, 2);
+        assert_eq!(het_table.total_count, 4);
+        assert_eq!(het_table.name_hashes, vec![0x00, 0xa1, 0x00, 0xb2]);
+        assert_eq!(het_table.bet_indexes, vec![0x0f, 0x00, 0x0f, 0x01]);
+        // The BET index array is too small for the entries.
+        let mut short_index_table = het_table_input.clone();
+        short_index_table[28] = 0x01;
+        assert!(MPQHetTable::parse(&short_index_table).is_err());
+        // An index wider than 64 bits.
+        let mut wide_index = het_table_input.clone();
+        wide_index[24] = 65;
+        assert!(MPQHetTable::parse(&wide_index).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+//! The Hi-Block Table Parsing
+//!
+//! Archives bigger than 4GB, supported since the Burning Crusade format, store
+//! the high 16 bits of each of the block offsets in the hi-block table.
+//! It contains one int16 per block table entry and it is not encrypted.
+//! Its offset is in [`super::MPQFileHeaderExt::extended_block_table_offset`].
+//! NOTES:
+//! - MPyQ does not support the hi-block table.
+
+use super::LITTLE_ENDIAN;
+use nom::error::dbg_dmp;
+use nom::multi::count;
+use nom::number::complete::u16;
+use nom::*;
+
+/// The hi-block table of the MPQ archive.
+#[derive(Debug, PartialEq, Default, Clone)]
+pub struct MPQHiBlockTable;
+
+impl MPQHiBlockTable {
+    /// Parses the `block_count` high offset words.
+    pub fn parse(input: &[u8], block_count: usize) -> IResult<&[u8], Vec<u16>> {
+        count(Self::parse_offset_high, block_count)(input)
+    }
+
+    /// `Offset Varies`: int16 BlockOffsetHigh
+    ///
+    /// The high 16 bits of the offset of a block, the block offset is then
+    /// `(BlockOffsetHigh << 32) | BlockOffset`.
+    pub fn parse_offset_high(input: &[u8]) -> IResult<&[u8], u16> {
+        dbg_dmp(u16(LITTLE_ENDIAN), "offset_high")(input)
+    }
+}
+
+#[cfg(test)]
+/// Hi-Block Table Tests
+pub mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_hi_block_table() {
+        let hi_block_table_input = vec![
+            0x00, 0x00, // block 0
+            0x01, 0x00, // block 1
+            0x34, 0x12, // block 2
+            0xff, 0xff, // trailing data
+        ];
+        let (input, hi_block_table) = MPQHiBlockTable::parse(&hi_block_table_input, 3).unwrap();
+        assert_eq!(hi_block_table, vec![0x0000, 0x0001, 0x1234]);
+        assert_eq!(input, &[0xff, 0xff][..]);
+        assert!(MPQHiBlockTable::parse(&hi_block_table_input, 5).is_err());
+    }
+}
@@ -5,14 +5,247 @@
 //!     and claims the userdata starts at offset 0x0c.
 //!     In this implementation the MPyQ version is honored.
 
-use super::LITTLE_ENDIAN;
+use super::{LITTLE_ENDIAN, MPQ_USER_DATA_HEADER_TYPE};
+use crate::MPQParserError;
 use nom::bytes::complete::take;
 use nom::error::dbg_dmp;
-use nom::number::complete::u32;
+use nom::number::complete::{u32, u64, u8};
 use nom::*;
 
+/// The version of the game that created a Starcraft 2 replay.
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub struct ReplayVersion {
+    /// The version flags.
+    pub flags: i64,
+    /// The major version number.
+    pub major: i64,
+    /// The minor version number.
+    pub minor: i64,
+    /// The revision version number.
+    pub revision: i64,
+    /// The build number.
+    pub build: i64,
+    /// The base build number, used to select the protocol to decode the replay.
+    pub base_build: i64,
+}
+
+/// The header of a Starcraft 2 replay, stored in the user data content.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct ReplayHeader {
+    /// The signature, i.e. `StarCraft II replay\x1b11`
+    pub signature: Vec<u8>,
+    /// The version of the game that created the replay.
+    pub version: ReplayVersion,
+}
+
+/// A value of the versioned serialization used by Starcraft 2 replays.
+/// Only the types needed to walk the replay header are kept.
+#[derive(Debug, PartialEq, Clone)]
+enum VersionedValue {
+    /// An array, bit array, choice or optional, its contents are skipped.
+    Skipped,
+    /// A blob of bytes.
+    Blob(Vec<u8>),
+    /// A struct, a list of field tags and values.
+    Struct(Vec<(i64, VersionedValue)>),
+    /// Any of the integer types.
+    Int(i64),
+}
+
+/// The maximum nesting of the versioned values, the replay header only nests a
+/// few levels, deeper values would overflow the stack.
+pub const MAX_VERSIONED_VALUE_DEPTH: usize = 32;
+
+impl VersionedValue {
+    /// Parses a variable length integer, the first bit of the first byte is the sign.
+    /// Values that don't fit an `i64` are rejected.
+    fn parse_vint(input: &[u8]) -> IResult<&[u8], i64> {
+        let too_large = |input| {
+            nom::Err::Error(nom::error::Error::new(
+                input,
+                nom::error::ErrorKind::TooLarge,
+            ))
+        };
+        let (mut input, mut byte) = u8(input)?;
+        let negative = byte & 1 != 0;
+        let mut res = ((byte >> 1) & 0x3f) as u64;
+        let mut bits = 6;
+        while byte & 0x80 != 0 {
+            (input, byte) = u8(input)?;
+            let value = (byte & 0x7f) as u64;
+            if bits >= 64 || value.leading_zeros() < bits {
+                return Err(too_large(input));
+            }
+            res |= value << bits;
+            bits += 7;
+        }
+        let res = i64::try_from(res).map_err(|_| too_large(input))?;
+        Ok((input, if negative { -res } else { res }))
+    }
+
+    /// Parses a value preceded by its type tag.
+    fn parse(input: &[u8]) -> IResult<&[u8], Self> {
+        Self::parse_nested(input, 0)
+    }
+
+    /// Parses a value nested in `depth` arrays, choices, optionals or structs,
+    /// failing beyond [`MAX_VERSIONED_VALUE_DEPTH`].
+    fn parse_nested(input: &[u8], depth: usize) -> IResult<&[u8], Self> {
+        if depth > MAX_VERSIONED_VALUE_DEPTH {
+            return Err(nom::Err::Error(nom::error::Error::new(
+                input,
+                nom::error::ErrorKind::TooLarge,
+            )));
+        }
+        let (input, tag) = u8(input)?;
+        match tag {
+            // array
+            0x00 => {
+                let (mut input, length) = Self::parse_vint(input)?;
+                for _ in 0..length {
+                    (input, _) = Self::parse_nested(input, depth + 1)?;
+                }
+                Ok((input, Self::Skipped))
+            }
+            // bit array
+            0x01 => {
+                let (input, length) = Self::parse_vint(input)?;
+                let (input, _) = take((length as usize).div_ceil(8))(input)?;
+                Ok((input, Self::Skipped))
+            }
+            // blob
+            0x02 => {
+                let (input, length) = Self::parse_vint(input)?;
+                let (input, blob) = take(length as usize)(input)?;
+                Ok((input, Self::Blob(blob.to_vec())))
+            }
+            // choice
+            0x03 => {
+                let (input, _choice) = Self::parse_vint(input)?;
+                let (input, _) = Self::parse_nested(input, depth + 1)?;
+                Ok((input, Self::Skipped))
+            }
+            // optional
+            0x04 => {
+                let (input, exists) = u8(input)?;
+                if exists == 0 {
+                    return Ok((input, Self::Skipped));
+                }
+                let (input, _) = Self::parse_nested(input, depth + 1)?;
+                Ok((input, Self::Skipped))
+            }
+            // struct
+            0x05 => {
+                let (mut input, length) = Self::parse_vint(input)?;
+                let mut fields = vec![];
+                for _ in 0..length {
+                    let (tail, field_tag) = Self::parse_vint(input)?;
+                    let (tail, value) = Self::parse_nested(tail, depth + 1)?;
+                    fields.push((field_tag, value));
+                    input = tail;
+                }
+                Ok((input, Self::Struct(fields)))
+            }
+            0x06 => {
+                let (input, value) = u8(input)?;
+                Ok((input, Self::Int(value as i64)))
+            }
+            0x07 => {
+                let (input, value) = u32(LITTLE_ENDIAN)(input)?;
+                Ok((input, Self::Int(value as i64)))
+            }
+            0x08 => {
+                let (input, value) = u64(LITTLE_ENDIAN)(input)?;
+                Ok((input, Self::Int(value as i64)))
+            }
+            0x09 => {
+                let (input, value) = Self::parse_vint(input)?;
+                Ok((input, Self::Int(value)))
+            }
+            _ => Err(nom::Err::Error(nom::error::Error::new(
+                input,
+                nom::error::ErrorKind::Tag,
+            ))),
+        }
+    }
+
+    /// Returns the value of a struct field.
+    fn field(&self, field_tag: i64) -> Option<&Self> {
+        match self {
+            Self::Struct(fields) => fields
+                .iter()
+                .find(|(tag, _)| *tag == field_tag)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    /// Returns the value of an integer struct field.
+    fn int_field(&self, field_tag: i64) -> Option<i64> {
+        match self.field(field_tag) {
+            Some(Self::Int(value)) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+/// The contents of the user data.
+/// In Starcraft 2 replay files it contains the [`ReplayHeader`]
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct UserDataContent(Vec<u8>);
+
+impl UserDataContent {
+    /// Wraps the user data bytes.
+    pub fn new(content: Vec<u8>) -> Self {
+        Self(content)
+    }
+
+    /// The raw bytes of the user data.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The number of bytes of the user data.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether the user data is empty.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Decodes the content as the header of a Starcraft 2 replay.
+    pub fn try_as_replay_header(&self) -> Result<ReplayHeader, MPQParserError> {
+        let (_tail, value) =
+            VersionedValue::parse(&self.0).map_err(|_| MPQParserError::InvalidReplayHeader)?;
+        let signature = match value.field(0) {
+            Some(VersionedValue::Blob(signature)) => signature.clone(),
+            _ => return Err(MPQParserError::InvalidReplayHeader),
+        };
+        let version = match value.field(1) {
+            Some(version) => ReplayVersion {
+                flags: version.int_field(0).unwrap_or_default(),
+                major: version.int_field(1).unwrap_or_default(),
+                minor: version.int_field(2).unwrap_or_default(),
+                revision: version.int_field(3).unwrap_or_default(),
+                build: version.int_field(4).unwrap_or_default(),
+                base_build: version.int_field(5).unwrap_or_default(),
+            },
+            None => return Err(MPQParserError::InvalidReplayHeader),
+        };
+        Ok(ReplayHeader { signature, version })
+    }
+}
+
+impl From<Vec<u8>> for UserDataContent {
+    fn from(content: Vec<u8>) -> Self {
+        Self(content)
+    }
+}
+
 /// The MPQ User Data
-#[derive(Debug, Default)]
+#[derive(Debug, Default, PartialEq, Clone)]
 pub struct MPQUserData {
     /// The number of bytes that have been allocated for user data.
     pub user_data_size: u32, // This variable is unused
@@ -22,7 +255,11 @@ pub struct MPQUserData {
     pub user_data_header_size: u32,
     /// The contents of the user data, in Starcraft 2 replay files contains
     /// the build information of the game version that created the replay.
-    pub content: Vec<u8>,
+    /// It is left empty by [`MPQUserData::parse_lazy`] until materialized.
+    pub content: UserDataContent,
+    /// The range of the content in the input, relative to the start of the user
+    /// data section, only set by [`MPQUserData::parse_lazy`].
+    pub content_range: Option<std::ops::Range<usize>>,
 }
 
 impl MPQUserData {
@@ -41,10 +278,55 @@ impl MPQUserData {
                 archive_header_offset,
                 user_data_header_size,
                 content,
+                content_range: None,
             },
         ))
     }
 
+    /// Parses all the fields in the expected order, like [`MPQUserData::parse`],
+    /// but the content is not copied, only its range in the input is kept.
+    ///
+    /// The content can then be read with [`MPQUserData::content_bytes`] or copied
+    /// with [`MPQUserData::materialize`], providing the input the section starts at.
+    pub fn parse_lazy(input: &[u8]) -> IResult<&[u8], Self> {
+        let (input, user_data_size) = Self::parse_user_data_size(input)?;
+        let (input, archive_header_offset) = Self::parse_archive_header_offset(input)?;
+        let (input, user_data_header_size) = Self::parse_user_data_header_size(input)?;
+        let (input, _content) = dbg_dmp(take(user_data_header_size as usize), "content")(input)?;
+        let (input, _) =
+            Self::consume_until_header_offset(input, user_data_header_size, archive_header_offset)?;
+        // The magic and the three fields precede the content.
+        let content_start = 16usize;
+        Ok((
+            input,
+            MPQUserData {
+                user_data_size,
+                archive_header_offset,
+                user_data_header_size,
+                content: UserDataContent::default(),
+                content_range: Some(content_start..content_start + user_data_header_size as usize),
+            },
+        ))
+    }
+
+    /// Returns the bytes of the content, for lazily parsed user data they are
+    /// borrowed from the `input` the user data section starts at.
+    pub fn content_bytes<'a>(&'a self, input: &'a [u8]) -> &'a [u8] {
+        match &self.content_range {
+            Some(content_range) => input.get(content_range.clone()).unwrap_or_default(),
+            None => self.content.as_bytes(),
+        }
+    }
+
+    /// Copies the content of lazily parsed user data from the `input` the user data
+    /// section starts at, afterwards it is the same as if parsed by [`MPQUserData::parse`].
+    pub fn materialize(&mut self, input: &[u8]) {
+        if let Some(content_range) = self.content_range.take() {
+            let content = input.get(content_range).unwrap_or_default();
+            self.content = UserDataContent::new(content.to_vec());
+        }
+    }
+
     /// `Offset 0x04`: int32 UserDataSize
     ///
     /// The number of bytes that have been allocated in this archive for user
@@ -71,9 +353,12 @@ impl MPQUserData {
     /// `Offset 0x10`: byte(UserDataSize) UserData
     ///
     /// The block to store user data in.
-    pub fn parse_content(input: &[u8], user_data_header_size: u32) -> IResult<&[u8], Vec<u8>> {
+    pub fn parse_content(
+        input: &[u8],
+        user_data_header_size: u32,
+    ) -> IResult<&[u8], UserDataContent> {
         let (input, content) = dbg_dmp(take(user_data_header_size as usize), "content")(input)?;
-        Ok((input, content.to_vec()))
+        Ok((input, UserDataContent::new(content.to_vec())))
     }
 
     /// Offset Varies: padded data
@@ -91,14 +376,17 @@ impl MPQUserData {
         // - 4 bytes for the user_data_header_size
         // - user_data_header_size bytes
         let curr_read_byte_count = 16;
+        // The archive header may follow the content immediately, without padding.
         if archive_header_offset < user_data_header_size + curr_read_byte_count {
             tracing::error!(
-                "Invalid archive_header_offset: {}, should be bigger than {}",
+                "Invalid archive_header_offset: {}, should be at least {}",
                 archive_header_offset,
                 user_data_header_size + curr_read_byte_count
             );
-            return Err(nom::Err::Incomplete(nom::Needed::new(
-                (user_data_header_size + curr_read_byte_count) as usize,
+            // Only complete parsers are used, an Incomplete error can't be converted.
+            return Err(nom::Err::Error(nom::error::Error::new(
+                input,
+                nom::error::ErrorKind::Verify,
             )));
         }
         let (input, _) = dbg_dmp(
@@ -107,6 +395,21 @@ impl MPQUserData {
         )(input)?;
         Ok((input, ()))
     }
+
+    /// Serializes the user data section, including the magic and the padding
+    /// up to the `archive_header_offset`, as consumed by [`MPQUserData::parse`]
+    /// Lazily parsed user data must be materialized first.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut res = vec![b'M', b'P', b'Q', MPQ_USER_DATA_HEADER_TYPE];
+        res.extend_from_slice(&self.user_data_size.to_le_bytes());
+        res.extend_from_slice(&self.archive_header_offset.to_le_bytes());
+        res.extend_from_slice(&self.user_data_header_size.to_le_bytes());
+        res.extend_from_slice(self.content.as_bytes());
+        if res.len() < self.archive_header_offset as usize {
+            res.resize(self.archive_header_offset as usize, 0);
+        }
+        res
+    }
 }
 
 #[cfg(test)]
@@ -155,7 +458,135 @@ pub mod tests {
         let (input, user_data) = MPQUserData::parse(input).unwrap();
         assert_eq!(user_data.archive_header_offset, 0x18);
         assert_eq!(user_data.user_data_header_size, 0x04);
-        assert_eq!(user_data.content, vec![0xbe, 0xef, 0xca, 0x4e]);
+        assert_eq!(user_data.content.as_bytes(), vec![0xbe, 0xef, 0xca, 0x4e]);
         assert_eq!(input, &b""[..]);
     }
+
+    #[test]
+    fn it_wraps_content() {
+        let user_data_header_input = basic_user_header();
+        let (input, _header_type) = get_header_type(&user_data_header_input).unwrap();
+        let (_input, user_data) = MPQUserData::parse(input).unwrap();
+        assert_eq!(user_data.content.len(), 4);
+        assert!(!user_data.content.is_empty());
+        assert!(user_data.content.try_as_replay_header().is_err());
+        let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
+        let file_contents = read_file(file_path);
+        let (_input, (_archive_header, user_data)) = read_headers(&file_contents).unwrap();
+        let user_data = user_data.unwrap();
+        assert_eq!(user_data.content.len(), 0x3c);
+        let replay_header = user_data.content.try_as_replay_header().unwrap();
+        assert_eq!(
+            replay_header.signature,
+            b"StarCraft II replay\x1b11".to_vec()
+        );
+        assert_eq!(
+            replay_header.version,
+            ReplayVersion {
+                flags: 1,
+                major: 1,
+                minor: 0,
+                revision: 2,
+                build: 16223,
+                base_build: 15405,
+            }
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_replay_headers() {
+        // Deeply nested arrays.
+        let nested_arrays = UserDataContent::new([0x00, 0x02].repeat(500_000));
+        assert!(matches!(
+            nested_arrays.try_as_replay_header(),
+            Err(MPQParserError::InvalidReplayHeader)
+        ));
+        // The magnitude of i64::MIN, which can't be negated.
+        let min_vint = UserDataContent::new(vec![
+            0x09, 0x81, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x02,
+        ]);
+        assert!(matches!(
+            min_vint.try_as_replay_header(),
+            Err(MPQParserError::InvalidReplayHeader)
+        ));
+        // Continuation bytes beyond 64 bits.
+        assert!(VersionedValue::parse_vint(&[0xff; 12]).is_err());
+        assert_eq!(
+            VersionedValue::parse_vint(&[
+                0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01
+            ]),
+            Ok((&b""[..], i64::MAX))
+        );
+        assert_eq!(VersionedValue::parse_vint(&[0x03]), Ok((&b""[..], -1)));
+    }
+
+    #[test]
+    fn it_round_trips_user_data() {
+        let user_data_header_input = basic_user_header();
+        let (input, _header_type) = get_header_type(&user_data_header_input).unwrap();
+        let (_input, user_data) = MPQUserData::parse(input).unwrap();
+        assert_eq!(user_data.to_bytes(), user_data_header_input);
+        let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
+        let file_contents = read_file(file_path);
+        let (_input, (_archive_header, user_data)) = read_headers(&file_contents).unwrap();
+        let user_data = user_data.unwrap();
+        let user_data_bytes = user_data.to_bytes();
+        assert_eq!(
+            user_data_bytes,
+            file_contents[..user_data.archive_header_offset as usize]
+        );
+        let (input, _header_type) = get_header_type(&user_data_bytes).unwrap();
+        let (input, reparsed_user_data) = MPQUserData::parse(input).unwrap();
+        assert_eq!(reparsed_user_data, user_data);
+        assert_eq!(input, &b""[..]);
+    }
+
+    #[test]
+    fn it_parses_user_data_lazily() {
+        let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
+        let file_contents = read_file(file_path);
+        let (input, _header_type) = get_header_type(&file_contents).unwrap();
+        let (eager_input, user_data) = MPQUserData::parse(input).unwrap();
+        let (lazy_input, mut lazy_user_data) = MPQUserData::parse_lazy(input).unwrap();
+        assert_eq!(lazy_input, eager_input);
+        assert!(lazy_user_data.content.is_empty());
+        assert_eq!(lazy_user_data.content_range, Some(16..16 + 0x3c));
+        assert_eq!(
+            lazy_user_data.content_bytes(&file_contents),
+            user_data.content.as_bytes()
+        );
+        assert_eq!(
+            user_data.content_bytes(&file_contents),
+            user_data.content.as_bytes()
+        );
+        lazy_user_data.materialize(&file_contents);
+        assert_eq!(lazy_user_data, user_data);
+    }
+
+    #[test]
+    fn it_parses_user_data_without_padding() {
+        let mut user_data_header_input = basic_user_header();
+        // Drop the padding, the archive header follows the content immediately.
+        user_data_header_input.truncate(0x14);
+        user_data_header_input[0x08] = 0x14;
+        let (input, _header_type) = get_header_type(&user_data_header_input).unwrap();
+        let (eager_input, user_data) = MPQUserData::parse(input).unwrap();
+        let (lazy_input, mut lazy_user_data) = MPQUserData::parse_lazy(input).unwrap();
+        assert_eq!(eager_input, &b""[..]);
+        assert_eq!(lazy_input, eager_input);
+        assert_eq!(user_data.archive_header_offset, 0x14);
+        assert_eq!(user_data.content.as_bytes(), vec![0xbe, 0xef, 0xca, 0x4e]);
+        lazy_user_data.materialize(&user_data_header_input);
+        assert_eq!(lazy_user_data, user_data);
+        assert_eq!(user_data.to_bytes(), user_data_header_input);
+        let mut archive_input = user_data_header_input.clone();
+        archive_input.append(&mut crate::parser::mpq_file_header::tests::basic_file_header());
+        let (_input, (archive_header, _user_data)) = read_headers(&archive_input).unwrap();
+        assert_eq!(archive_header.offset, 0x14);
+        // The archive header offset can't be within the content.
+        user_data_header_input[0x08] = 0x13;
+        let (input, _header_type) = get_header_type(&user_data_header_input).unwrap();
+        assert!(MPQUserData::parse(input).is_err());
+        assert!(MPQUserData::parse_lazy(input).is_err());
+    }
 }
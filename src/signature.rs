@@ -0,0 +1,216 @@
+//! Verification of the RSA signatures of the archives
+//!
+//! The weak signature is stored in the `(signature)` file, it is the RSA signature
+//! of the MD5 of the archive, with the block of the `(signature)` file zeroed.
+//! The file contains 8 bytes of zeroes followed by the 512-bit signature, stored
+//! in little endian.
+//! The signature is padded with PKCS#1 v1.5, only the public key operation is
+//! needed to verify it, so the modular exponentiation is implemented here over
+//! big endian byte strings instead of depending on a big number crate.
+//! NOTES:
+//! - MPyQ does not support signatures.
+//! - The strong signature, appended after the archive, is not supported.
+
+use std::cmp::Ordering;
+
+/// The size of the weak signature, in bytes.
+pub const MPQ_WEAK_SIGNATURE_SIZE: usize = 64;
+
+/// The size of the `(signature)` file, the signature is preceded by 8 bytes of zeroes.
+pub const MPQ_WEAK_SIGNATURE_FILE_SIZE: usize = 8 + MPQ_WEAK_SIGNATURE_SIZE;
+
+/// The public exponent of the RSA keys.
+pub const RSA_PUBLIC_EXPONENT: u32 = 0x10001;
+
+/// The modulus of the public key Blizzard uses for the weak signatures, big endian.
+pub const BLIZZARD_WEAK_PUBLIC_KEY: [u8; MPQ_WEAK_SIGNATURE_SIZE] = [
+    0x92, 0x62, 0x77, 0x04, 0xbf, 0xb8, 0x82, 0xcc, 0x05, 0x23, 0xb9, 0x0c, 0xb1, 0xac, 0x04, 0x59,
+    0x27, 0x21, 0x75, 0x96, 0x8d, 0x02, 0x5e, 0xda, 0x47, 0xdd, 0x7c, 0x49, 0x37, 0x1b, 0xf8, 0xfa,
+    0xeb, 0x0e, 0x0a, 0x92, 0x16, 0x75, 0x57, 0xad, 0x51, 0xb7, 0x8c, 0xcb, 0x68, 0xc5, 0x42, 0x62,
+    0x90, 0xee, 0x9f, 0xb1, 0x4b, 0xc1, 0x18, 0xe4, 0x30, 0x34, 0x9e, 0xa4, 0xef, 0x3c, 0x2b, 0xb7,
+];
+
+/// The DER encoded DigestInfo of an MD5 digest, it precedes the digest in the
+/// PKCS#1 v1.5 padding.
+const MD5_DIGEST_INFO_PREFIX: [u8; 18] = [
+    0x30, 0x20, 0x30, 0x0c, 0x06, 0x08, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x05, 0x05, 0x00,
+    0x04, 0x10,
+];
+
+/// Pads an MD5 digest with PKCS#1 v1.5 to `len` bytes, the size of the modulus.
+pub fn pkcs1_md5_encode(digest: &[u8; 16], len: usize) -> Vec<u8> {
+    let padding_len = len.saturating_sub(3 + MD5_DIGEST_INFO_PREFIX.len() + digest.len());
+    let mut res = vec![0x00, 0x01];
+    res.resize(2 + padding_len, 0xff);
+    res.push(0x00);
+    res.extend_from_slice(&MD5_DIGEST_INFO_PREFIX);
+    res.extend_from_slice(digest);
+    res
+}
+
+/// Verifies a big endian RSA signature of an MD5 digest, padded with PKCS#1 v1.5,
+/// with the big endian `modulus` and [`RSA_PUBLIC_EXPONENT`].
+pub fn verify_md5_signature(signature: &[u8], digest: &[u8; 16], modulus: &[u8]) -> bool {
+    let limbs = modulus.len().div_ceil(4) + 1;
+    if signature.len() != modulus.len()
+        || compare(&to_limbs(signature, limbs), &to_limbs(modulus, limbs)) != Ordering::Less
+    {
+        return false;
+    }
+    let decrypted = mod_pow(signature, &RSA_PUBLIC_EXPONENT.to_be_bytes(), modulus);
+    decrypted == pkcs1_md5_encode(digest, modulus.len())
+}
+
+/// Computes `base ^ exponent % modulus`, all of them big endian, the result has
+/// the size of the modulus. The `base` must be smaller than the `modulus`.
+pub fn mod_pow(base: &[u8], exponent: &[u8], modulus: &[u8]) -> Vec<u8> {
+    // One more limb so doubling a value below the modulus can't overflow.
+    let limbs = modulus.len().div_ceil(4) + 1;
+    let modulus_limbs = to_limbs(modulus, limbs);
+    let base = to_limbs(base, limbs);
+    let mut res = to_limbs(&[1], limbs);
+    for byte in exponent {
+        for bit in (0..8).rev() {
+            res = mul_mod(&res, &res, &modulus_limbs);
+            if byte >> bit & 1 != 0 {
+                res = mul_mod(&res, &base, &modulus_limbs);
+            }
+        }
+    }
+    let mut res_bytes: Vec<u8> = res
+        .iter()
+        .rev()
+        .flat_map(|limb| limb.to_be_bytes())
+        .collect();
+    res_bytes.split_off(res_bytes.len() - modulus.len())
+}
+
+/// Converts big endian bytes into little endian 32-bit limbs.
+fn to_limbs(bytes: &[u8], limbs: usize) -> Vec<u32> {
+    let mut res = vec![0u32; limbs];
+    for (i, byte) in bytes.iter().rev().enumerate() {
+        res[i / 4] |= (*byte as u32) << (8 * (i % 4));
+    }
+    res
+}
+
+/// Compares two numbers of the same number of limbs.
+fn compare(lhs: &[u32], rhs: &[u32]) -> Ordering {
+    lhs.iter().rev().cmp(rhs.iter().rev())
+}
+
+/// Subtracts `rhs` from `lhs`, which must be greater or equal.
+fn sub_assign(lhs: &mut [u32], rhs: &[u32]) {
+    let mut borrow = 0u64;
+    for (lhs, rhs) in lhs.iter_mut().zip(rhs) {
+        let value = (*lhs as u64).wrapping_sub(*rhs as u64 + borrow);
+        *lhs = value as u32;
+        borrow = (value >> 63) & 1;
+    }
+}
+
+/// Adds `rhs` to `lhs`, the carry of the last limb is dropped.
+fn add_assign(lhs: &mut [u32], rhs: &[u32]) {
+    let mut carry = 0u64;
+    for (lhs, rhs) in lhs.iter_mut().zip(rhs) {
+        let value = *lhs as u64 + *rhs as u64 + carry;
+        *lhs = value as u32;
+        carry = value >> 32;
+    }
+}
+
+/// Computes `lhs * rhs % modulus` by shifting and adding, both values must be
+/// smaller than the modulus.
+fn mul_mod(lhs: &[u32], rhs: &[u32], modulus: &[u32]) -> Vec<u32> {
+    let mut res = vec![0u32; modulus.len()];
+    for limb in rhs.iter().rev() {
+        for bit in (0..32).rev() {
+            // Doubles the result.
+            let mut carry = 0;
+            for res_limb in res.iter_mut() {
+                let value = (*res_limb as u64) << 1 | carry;
+                *res_limb = value as u32;
+                carry = value >> 32;
+            }
+            if compare(&res, modulus) != Ordering::Less {
+                sub_assign(&mut res, modulus);
+            }
+            if limb >> bit & 1 != 0 {
+                add_assign(&mut res, lhs);
+                if compare(&res, modulus) != Ordering::Less {
+                    sub_assign(&mut res, modulus);
+                }
+            }
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+/// Signature Tests
+pub mod tests {
+    use super::*;
+
+    /// The modulus of a 512-bit key generated for the tests, big endian.
+    pub const TEST_PUBLIC_KEY: [u8; MPQ_WEAK_SIGNATURE_SIZE] = [
+        0xac, 0x42, 0xe0, 0xbe, 0xd0, 0xed, 0xa9, 0x72, 0x7c, 0xdc, 0x54, 0xf0, 0xdf, 0x07, 0x67,
+        0x95, 0x71, 0xa9, 0x39, 0x28, 0x59, 0x96, 0x78, 0xe7, 0x92, 0xc9, 0x18, 0x00, 0x25, 0x27,
+        0xad, 0x70, 0x58, 0xd0, 0xe7, 0x92, 0xdc, 0x48, 0xda, 0xa8, 0x34, 0x4c, 0x1d, 0x1d, 0x99,
+        0x1d, 0xaf, 0x8d, 0x69, 0x69, 0xe8, 0x18, 0x0e, 0x24, 0x16, 0xa8, 0x39, 0xb8, 0x24, 0x3a,
+        0x26, 0xf7, 0x4c, 0xb5,
+    ];
+
+    /// The private exponent of [`TEST_PUBLIC_KEY`], big endian.
+    pub const TEST_PRIVATE_EXPONENT: [u8; MPQ_WEAK_SIGNATURE_SIZE] = [
+        0x2c, 0x41, 0x5e, 0x46, 0x07, 0x9d, 0x17, 0x4c, 0x5f, 0x81, 0xa7, 0x9c, 0xde, 0x5f, 0xa3,
+        0xa0, 0x04, 0x3c, 0x6a, 0x7f, 0xd0, 0x4c, 0x55, 0x31, 0x81, 0x47, 0x82, 0x8f, 0xfe, 0xfb,
+        0xab, 0x95, 0xe6, 0x34, 0xdb, 0xe8, 0xe7, 0xd8, 0x86, 0x79, 0xb9, 0xfd, 0x97, 0xfb, 0xcc,
+        0x0e, 0x60, 0x4e, 0x80, 0x10, 0x79, 0x16, 0x95, 0x13, 0x9c, 0xf8, 0xcb, 0x25, 0x2e, 0x59,
+        0xb1, 0x14, 0xbd, 0x81,
+    ];
+
+    /// Signs an MD5 digest with the test key, big endian.
+    pub fn test_sign(digest: &[u8; 16]) -> Vec<u8> {
+        mod_pow(
+            &pkcs1_md5_encode(digest, MPQ_WEAK_SIGNATURE_SIZE),
+            &TEST_PRIVATE_EXPONENT,
+            &TEST_PUBLIC_KEY,
+        )
+    }
+
+    #[test]
+    fn it_computes_mod_pow() {
+        assert_eq!(mod_pow(&[4], &[13], &[0x01, 0xf1]), vec![0x01, 0xbd]);
+        assert_eq!(
+            mod_pow(&[0x12, 0x34], &[0], &[0xff, 0xfb]),
+            vec![0x00, 0x01]
+        );
+        assert_eq!(
+            mod_pow(&[0xff, 0xff, 0xff, 0xfe], &[2], &[0xff, 0xff, 0xff, 0xff]),
+            vec![0x00, 0x00, 0x00, 0x01]
+        );
+    }
+
+    #[test]
+    fn it_verifies_md5_signatures() {
+        let digest = [0x5a; 16];
+        let signature = test_sign(&digest);
+        assert!(verify_md5_signature(&signature, &digest, &TEST_PUBLIC_KEY));
+        assert!(!verify_md5_signature(
+            &signature,
+            &[0xa5; 16],
+            &TEST_PUBLIC_KEY
+        ));
+        assert!(!verify_md5_signature(
+            &signature,
+            &digest,
+            &BLIZZARD_WEAK_PUBLIC_KEY
+        ));
+        // A signature beyond the modulus.
+        assert!(!verify_md5_signature(
+            &[0xff; MPQ_WEAK_SIGNATURE_SIZE],
+            &digest,
+            &TEST_PUBLIC_KEY
+        ));
+    }
+}
@@ -20,14 +20,17 @@ fn mpyq_test_file_header() {
     assert_eq!(mpq.archive_header.header_size, 44);
     assert_eq!(mpq.archive_header.archive_size, 205044);
     assert_eq!(mpq.archive_header.format_version, 1);
+    assert_eq!(mpq.archive_header.version(), MPQVersion::V2BurningCrusade);
     assert_eq!(mpq.archive_header.sector_size_shift, 3);
     assert_eq!(mpq.archive_header.hash_table_offset, 204628);
     assert_eq!(mpq.archive_header.block_table_offset, 204884);
     assert_eq!(mpq.archive_header.hash_table_entries, 16);
     assert_eq!(mpq.archive_header.block_table_entries, 10);
-    //assert_eq!(mpq.archive_header.extended_block_table_offset, 0);
-    //assert_eq!(mpq.archive_header.hash_table_offset_high, 0);
-    //assert_eq!(mpq.archive_header.block_table_offset_high, 0);
+    assert_eq!(mpq.hi_block_table, None);
+    let extended_file_header = mpq.archive_header.extended_file_header.unwrap();
+    assert_eq!(extended_file_header.extended_block_table_offset, 0);
+    assert_eq!(extended_file_header.hash_table_offset_high, 0);
+    assert_eq!(extended_file_header.block_table_offset_high, 0);
 }
 
 #[test]
@@ -66,6 +69,7 @@ fn mpyq_test_file_list() {
 }
 
 #[test]
+#[allow(clippy::vec_init_then_push)]
 fn mpyq_test_hash_table() {
     let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
     let file_contents = parser::read_file(file_path);
@@ -123,6 +127,7 @@ fn mpyq_test_hash_table() {
 }
 
 #[test_log::test]
+#[allow(clippy::vec_init_then_push)]
 fn mpyq_test_block_table() {
     let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
     let file_contents = parser::read_file(file_path);
@@ -165,3 +170,345 @@ fn mpyq_test_block_table() {
     expected_entries.push(MPQBlockTableEntry::new(0x00031E56, 254, 288, 0x81000200u32));
     assert_eq!(mpq.block_table_entries, expected_entries);
 }
+
+#[test]
+fn mpyq_test_tables_export() {
+    let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
+    let file_contents = parser::read_file(file_path);
+    let (_input, mpq) = parser::parse(&file_contents).unwrap();
+    let tables: serde_json::Value = serde_json::from_str(&mpq.tables_to_json()).unwrap();
+    assert_eq!(tables["hash_table"].as_array().unwrap().len(), 16);
+    assert_eq!(tables["block_table"].as_array().unwrap().len(), 10);
+    assert_eq!(tables["hash_table"][0]["hash_a"], "0xD38437CB");
+    assert_eq!(tables["block_table"][0]["flags"], "0x81000200");
+    let csv = mpq.tables_to_csv();
+    assert_eq!(csv.lines().count(), 1 + 16 + 10);
+    assert!(csv.contains("hash,0,0xD38437CB,0x07DFEAEC,0x0000,0x0000,0x00000009,,,,"));
+}
+
+#[test]
+fn mpyq_test_debug_dump() {
+    let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
+    let file_contents = parser::read_file(file_path);
+    let (_input, mpq) = parser::parse(&file_contents).unwrap();
+    let dump = mpq.debug_dump(&file_contents);
+    assert!(dump.contains("header_size: 44\n"));
+    assert!(dump.contains(
+        "0: hash_a 0xD38437CB, hash_b 0x07DFEAEC, locale 0x0000, platform 0x0000, block_table_index 0x00000009"
+    ));
+    for filename in [
+        "replay.attributes.events",
+        "replay.details",
+        "replay.game.events",
+        "replay.initData",
+        "replay.load.info",
+        "replay.message.events",
+        "replay.smartcam.events",
+        "replay.sync.events",
+    ] {
+        assert!(dump.contains(&format!("{filename}: size ")));
+    }
+}
+
+#[test]
+fn mpyq_test_extract_one() {
+    let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
+    let replay_details = parser::extract_one(file_path, "replay.details").unwrap();
+    assert_eq!(replay_details.len(), 890);
+    let file_contents = parser::read_file(file_path);
+    let (_input, mpq) = parser::parse(&file_contents).unwrap();
+    let (_tail, expected) = mpq
+        .read_mpq_file_sector("replay.details", false, &file_contents)
+        .unwrap();
+    assert_eq!(replay_details, expected);
+    assert!(matches!(
+        parser::extract_one(file_path, "replay.missing"),
+        Err(MPQParserError::HashTableEntryNotFound(_))
+    ));
+}
+
+#[test]
+fn mpyq_test_hash_table_utilization() {
+    let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
+    let file_contents = parser::read_file(file_path);
+    let (_input, mpq) = parser::parse(&file_contents).unwrap();
+    // 10 live entries out of 16 slots.
+    assert!((mpq.hash_table_utilization() - 0.625).abs() < f64::EPSILON);
+}
+
+#[test]
+fn mpyq_test_raw_sector_bytes() {
+    let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
+    let file_contents = parser::read_file(file_path);
+    let (_input, mpq) = parser::parse(&file_contents).unwrap();
+    // The file is stored as a single unit, the only sector is the whole block.
+    let raw_sector = mpq
+        .raw_sector_bytes("replay.details", 0, &file_contents)
+        .unwrap();
+    let block_table_index = mpq
+        .get_hash_table_entry("replay.details", None)
+        .unwrap()
+        .block_table_index;
+    let block_entry = mpq.get_block_table_entry(block_table_index).unwrap();
+    assert_eq!(raw_sector.len(), block_entry.archived_size as usize);
+    // The compression type prefix followed by the bzip2 stream magic.
+    assert_eq!(raw_sector[..4], [COMPRESSION_BZ2, b'B', b'Z', b'h']);
+    assert!(matches!(
+        mpq.raw_sector_bytes("replay.details", 1, &file_contents),
+        Err(MPQParserError::SectorIndexOutOfRange(1))
+    ));
+}
+
+#[test]
+fn mpyq_test_raw_file_data() {
+    let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
+    let file_contents = parser::read_file(file_path);
+    let (_input, mut mpq) = parser::parse(&file_contents).unwrap();
+    for filename in ["replay.details", "replay.game.events", "(listfile)"] {
+        let raw_data = mpq.raw_file_data(filename, &file_contents).unwrap();
+        let block_table_index = mpq
+            .get_hash_table_entry(filename, None)
+            .unwrap()
+            .block_table_index;
+        let block_entry = mpq.get_block_table_entry(block_table_index).unwrap();
+        assert_eq!(raw_data.len(), block_entry.archived_size as usize);
+    }
+    assert!(mpq.raw_file_data("missing.file", &file_contents).is_none());
+    // The block lies beyond a truncated input.
+    assert!(mpq
+        .raw_file_data("replay.details", &file_contents[..0x100])
+        .is_none());
+    // The end of the block overflows.
+    let block_table_index = mpq
+        .get_hash_table_entry("replay.details", None)
+        .unwrap()
+        .block_table_index;
+    let block_offset = mpq.get_block_table_entry(block_table_index).unwrap().offset;
+    mpq.archive_header.offset = usize::MAX - block_offset as usize;
+    assert!(mpq
+        .raw_file_data("replay.details", &file_contents)
+        .is_none());
+}
+
+#[test]
+fn mpyq_test_truncated_input() {
+    let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
+    let file_contents = parser::read_file(file_path);
+    let (_input, mpq) = parser::parse(&file_contents).unwrap();
+    for input_len in (0..file_contents.len()).step_by(4099) {
+        let truncated = &file_contents[..input_len];
+        // The tables are at the end of the archive, they are always cut off.
+        assert!(parser::parse(truncated).is_err());
+        // The blocks of the files are beyond the end of the input.
+        assert!(matches!(
+            mpq.read_mpq_file_sector("replay.details", false, &truncated[..input_len.min(0x100)]),
+            Err(MPQParserError::IncompleteData)
+        ));
+        let _ = mpq.read_mpq_file_sector("replay.game.events", false, truncated);
+    }
+}
+
+#[test]
+fn mpyq_test_read_file_string() {
+    let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
+    let file_contents = parser::read_file(file_path);
+    let (_input, mpq) = parser::parse(&file_contents).unwrap();
+    let listfile = mpq.read_file_string("(listfile)", &file_contents).unwrap();
+    let filenames: Vec<&str> = listfile.lines().collect();
+    assert_eq!(filenames.len(), 8);
+    assert!(filenames.contains(&"replay.details"));
+    assert!(matches!(
+        mpq.read_file_string("replay.game.events", &file_contents),
+        Err(MPQParserError::InvalidUTF8Sequence(_))
+    ));
+}
+
+#[test]
+fn mpyq_test_is_complete() {
+    let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
+    let file_contents = parser::read_file(file_path);
+    let (_input, mpq) = parser::parse(&file_contents).unwrap();
+    assert!(mpq.is_complete(&file_contents));
+    assert!(!mpq.is_complete(&file_contents[..file_contents.len() - 1]));
+}
+
+#[test]
+fn mpyq_test_internal_files() {
+    let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
+    let file_contents = parser::read_file(file_path);
+    let (_input, mpq) = parser::parse(&file_contents).unwrap();
+    assert_eq!(mpq.internal_files(), vec!["(listfile)", "(attributes)"]);
+}
+
+#[test]
+fn mpyq_test_files_by_offset() {
+    let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
+    let file_contents = parser::read_file(file_path);
+    let (_input, mpq) = parser::parse(&file_contents).unwrap();
+    let files: Vec<String> = mpq
+        .get_files(&file_contents)
+        .unwrap()
+        .into_iter()
+        .map(|(filename, _size)| filename)
+        .collect();
+    let files_by_offset = mpq.files_by_offset(&file_contents).unwrap();
+    assert_eq!(files_by_offset.len(), files.len());
+    assert!(files_by_offset.windows(2).all(|pair| pair[0].1 < pair[1].1));
+    let filenames_by_offset: Vec<String> = files_by_offset
+        .into_iter()
+        .map(|(filename, _offset)| filename)
+        .collect();
+    assert_ne!(filenames_by_offset, files);
+}
+
+#[test]
+fn mpyq_test_required_capabilities() {
+    let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
+    let file_contents = parser::read_file(file_path);
+    let (_input, mpq) = parser::parse(&file_contents).unwrap();
+    // Every compressed file uses bzip2.
+    assert_eq!(
+        mpq.required_capabilities(&file_contents).unwrap(),
+        Capabilities {
+            bzip2: true,
+            ..Default::default()
+        }
+    );
+    let file_path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/assets/SC2-Patch_4.12-2v2AI.SC2Replay"
+    );
+    let file_contents = parser::read_file(file_path);
+    let (_input, mpq) = parser::parse(&file_contents).unwrap();
+    assert_eq!(
+        mpq.required_capabilities(&file_contents).unwrap(),
+        Capabilities {
+            bzip2: true,
+            het_bet_tables: true,
+            ..Default::default()
+        }
+    );
+}
+
+#[test]
+fn mpyq_test_assert_roundtrips() {
+    let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
+    let file_contents = parser::read_file(file_path);
+    let (_input, mpq) = parser::parse(&file_contents).unwrap();
+    assert!(mpq.assert_roundtrips(&file_contents).is_ok());
+}
+
+#[test]
+fn mpyq_test_files_in_block_order() {
+    let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
+    let file_contents = parser::read_file(file_path);
+    let (_input, mpq) = parser::parse(&file_contents).unwrap();
+    let files_in_block_order = mpq.files_in_block_order(&file_contents);
+    let file_count = mpq
+        .block_table_entries
+        .iter()
+        .filter(|block_entry| block_entry.flags & MPQ_FILE_EXISTS != 0)
+        .count();
+    assert_eq!(files_in_block_order.len(), file_count);
+    assert!(files_in_block_order
+        .windows(2)
+        .all(|pair| pair[0].1 < pair[1].1));
+    // The 8 files in the (listfile), the (listfile) and the (attributes).
+    assert!(files_in_block_order
+        .iter()
+        .all(|(filename, _block_index)| filename.is_some()));
+    assert_eq!(files_in_block_order.len(), 10);
+}
+
+#[test]
+fn mpyq_test_for_each_file() {
+    let file_path = concat!(env!("CARGO_MANIFEST_DIR"), "/assets/mpyq-test.SC2Replay");
+    let file_contents = parser::read_file(file_path);
+    let (_input, mpq) = parser::parse(&file_contents).unwrap();
+    let mut visited_files = vec![];
+    mpq.for_each_file(&file_contents, |filename, size| {
+        visited_files.push((filename.to_string(), size))
+    })
+    .unwrap();
+    assert_eq!(visited_files, mpq.get_files(&file_contents).unwrap());
+}
+
+#[test]
+fn it_verifies_header_md5() {
+    let data = parser::read_file("assets/SC2-Patch_4.12-2v2AI.SC2Replay");
+    let (_, mpq) = parser::parse(&data).unwrap();
+    let v4_file_header = mpq.archive_header.v4_file_header.unwrap();
+    assert_eq!(v4_file_header.hash_table_size_64, 512);
+    assert_eq!(v4_file_header.block_table_size_64, 272);
+    assert!(mpq.verify_header_md5(&data).is_ok());
+    // A byte of the encrypted hash table.
+    let mut mutated_hash_table = data.clone();
+    mutated_hash_table[mpq.archive_header.offset + mpq.archive_header.hash_table_pos() as usize] ^=
+        0xff;
+    assert!(matches!(
+        mpq.verify_header_md5(&mutated_hash_table),
+        Err(MPQParserError::Md5Mismatch(table)) if table == "(hash table)"
+    ));
+    // The sector size shift of the header.
+    let mut mutated_header = data.clone();
+    mutated_header[mpq.archive_header.offset + 0x0e] ^= 0xff;
+    assert!(matches!(
+        mpq.verify_header_md5(&mutated_header),
+        Err(MPQParserError::Md5Mismatch(table)) if table == "(header)"
+    ));
+    // A table size beyond the input, with a digest to check.
+    let mut mpq = mpq;
+    if let Some(v4_file_header) = mpq.archive_header.v4_file_header.as_mut() {
+        v4_file_header.hash_table_size_64 = u64::MAX;
+    }
+    assert!(matches!(
+        mpq.verify_header_md5(&data),
+        Err(MPQParserError::OffsetOutOfBounds(u64::MAX))
+    ));
+    // The original format has no digests.
+    let data = parser::read_file("assets/mpyq-test.SC2Replay");
+    let (_, mpq) = parser::parse(&data).unwrap();
+    assert!(mpq.verify_header_md5(&data).is_ok());
+}
+
+#[test]
+fn mpyq_test_extract_all_to() {
+    let data = parser::read_file("assets/mpyq-test.SC2Replay");
+    let (_, mpq) = parser::parse(&data).unwrap();
+    let out_dir = std::env::temp_dir().join(format!("nom-mpq-extract-{}", std::process::id()));
+    let paths = mpq.extract_all_to(&data, &out_dir).unwrap();
+    assert_eq!(paths.len(), 8);
+    for path in &paths {
+        assert!(path.starts_with(&out_dir));
+        assert!(path.is_file());
+    }
+    assert_eq!(
+        std::fs::read(out_dir.join("replay.details")).unwrap(),
+        mpq.read_mpq_file_sector("replay.details", false, &data)
+            .unwrap()
+            .1
+    );
+    std::fs::remove_dir_all(&out_dir).unwrap();
+}
+
+#[test]
+fn mpyq_test_read_attributes() {
+    let data = parser::read_file("assets/mpyq-test.SC2Replay");
+    let (_, mpq) = parser::parse(&data).unwrap();
+    let attributes = mpq.read_attributes(&data).unwrap();
+    assert_eq!(attributes.version, 100);
+    assert_eq!(
+        attributes.crc32s.unwrap().len(),
+        mpq.block_table_entries.len()
+    );
+    // The MD5s are those of the file contents.
+    use md5::Digest;
+    let hash_entry = mpq.get_hash_table_entry("replay.details", None).unwrap();
+    let (_tail, details) = mpq
+        .read_mpq_file_sector("replay.details", false, &data)
+        .unwrap();
+    assert_eq!(
+        attributes.md5s.unwrap()[hash_entry.block_table_index as usize],
+        Some(<[u8; 16]>::from(md5::Md5::digest(&details)))
+    );
+}